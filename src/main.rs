@@ -30,20 +30,8 @@ use rds::Renderer;
 use path_tracer::{cpu, *};
 
 
-fn main() {
-    let rdr = Renderer::get();
-
-    // Setup variables
-    let size = Renderer::get_size();
-    let mut canvas = Image::new(size);
-    
-    // Create camera
-    let camera = Camera::new(vec3!(0.0, 0.0, 0.0), 1.0);
-
-    let mut cpu_path_tracer = cpu::CpuRenderingDevice::new(3, 1000);
-
-
-    // Setup world
+/// Creates the demo scene, returns its objects and the materials to clean up
+fn setup_world(cpu_path_tracer: &mut cpu::CpuRenderingDevice) -> (Vec<rid::Rid>, Vec<rid::Rid>) {
     let red_ball = cpu_path_tracer.create_metal_material(Color::raw_rgb(0.8, 0.4, 0.4), 0.2);
     let default_ball = cpu_path_tracer.create_lambertial_material(Color::raw_rgb(0.4, 0.4, 0.4));
     let grass = cpu_path_tracer.create_lambertial_material(Color::raw_rgb(0.2, 0.8, 0.2));
@@ -59,6 +47,67 @@ fn main() {
 
     let plane = cpu_path_tracer.create_plane(vec3!(0.0, -0.5, 0.0), Vec3::UNIT_Y);
     cpu_path_tracer.object_set_material(plane, grass);
+
+    (vec![sphere1, sphere2, sphere3, plane], vec![red_ball, grass])
+}
+
+
+/// Renders the frames of an animated scene file to numbered image files:
+/// `batch <scene file> <first frame> <last frame> <path pattern> [width] [height]`
+fn batch(args: &[String]) -> Result<(), String> {
+    let usage = "usage: batch <scene file> <first frame> <last frame> <path pattern, e.g. out/frame_####.ppm> \
+        [width] [height]";
+    let parse = |index: usize| -> Result<i64, String> {
+        args.get(index).ok_or(usage)?.parse::<i64>().map_err(|err| format!("{}: {}", args[index], err))
+    };
+
+    let scene_path = args.first().ok_or(usage)?;
+    let (first, last) = (parse(1)?, parse(2)?);
+    let pattern = args.get(3).ok_or(usage)?;
+    let width = if args.len() > 4 { parse(4)? } else { 320 };
+    let height = if args.len() > 5 { parse(5)? } else { 180 };
+    if width <= 0 || height <= 0 {
+        return Err("the image size must be positive".to_string());
+    }
+
+    let mut cpu_path_tracer = cpu::CpuRenderingDevice::new(3, 100);
+    let mut scene = cpu_path_tracer.load_scene(std::path::Path::new(scene_path))
+        .map_err(|err| format!("could not load {}: {}", scene_path, err))?;
+
+    if cpu_path_tracer.spectral && !cpu_path_tracer.spectral_supported() {
+        eprintln!("warning: the integrator does not support spectral rendering, rendering in RGB");
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    cpu_path_tracer.render_sequence(&scene.animation, &mut scene.camera, first..=last, width, height, pattern)
+        .map_err(|err| format!("could not write the frames: {}", err))
+}
+
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("batch") {
+        if let Err(err) = batch(&args[2..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let rdr = Renderer::get();
+
+    // Setup variables
+    let size = Renderer::get_size();
+    let mut canvas = Image::new(size);
+    
+    // Create camera
+    let camera = Camera::new(vec3!(0.0, 0.0, 0.0), 1.0);
+
+    let mut cpu_path_tracer = cpu::CpuRenderingDevice::new(3, 1000);
+
+
+    // Setup world
+    let (objects, materials) = setup_world(&mut cpu_path_tracer);
     
     // Render image
     let path_tracer_start = time::Instant::now();
@@ -73,13 +122,12 @@ fn main() {
     rdr.end_draw();
 
     // Cleanup
-    cpu_path_tracer.remove_object(sphere1);
-    cpu_path_tracer.remove_object(sphere2);
-    cpu_path_tracer.remove_object(sphere3);
-    cpu_path_tracer.remove_object(plane);
-
-    cpu_path_tracer.remove_material(red_ball);
-    cpu_path_tracer.remove_material(grass);
+    for object in objects {
+        cpu_path_tracer.remove_object(object);
+    }
+    for material in materials {
+        cpu_path_tracer.remove_material(material);
+    }
 
     // Wait for input and exit
     Input::get().get_event_blocking();
@@ -0,0 +1,299 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::collections::HashMap;
+
+use simple_term_renderer::img::Color;
+use simple_term_renderer::math::Vec3;
+
+use crate::rid::Rid;
+use crate::transform::{Quat, Transform};
+
+use super::{lerp, lerp_vec};
+use super::mat::PrincipledParameters;
+
+
+/// Easing of the segment between two keyframes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /// Cubic Bezier timing curve going from (0, 0) to (1, 1) through the control points `(x1, y1)` and `(x2, y2)`,
+    /// like CSS timing functions
+    Bezier(f64, f64, f64, f64)
+}
+
+
+impl Interpolation {
+    pub const EASE_IN_OUT: Interpolation = Interpolation::Bezier(0.42, 0.0, 0.58, 1.0);
+
+
+    /// Maps the linear progress `t` of a segment to its eased progress
+    pub fn ease(&self, t: f64) -> f64 {
+        match *self {
+            Interpolation::Linear => t,
+            Interpolation::Bezier(x1, y1, x2, y2) => {
+                let bezier = |s: f64, p1: f64, p2: f64| {
+                    let r = 1.0 - s;
+                    3.0 * r * r * s * p1 + 3.0 * r * s * s * p2 + s * s * s
+                };
+
+                // Finds the curve parameter whose abscissa is `t` by bisection, the abscissa being monotonic
+                let (x1, x2) = (x1.clamp(0.0, 1.0), x2.clamp(0.0, 1.0));
+                let (mut low, mut high) = (0.0, 1.0);
+                for _ in 0..32 {
+                    let middle = 0.5 * (low + high);
+                    if bezier(middle, x1, x2) < t {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+
+                bezier(0.5 * (low + high), y1, y2)
+            }
+        }
+    }
+}
+
+
+/// Value that can be interpolated between keyframes
+pub trait Animatable: Clone {
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self;
+}
+
+
+impl Animatable for f64 {
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self {
+        lerp(t, *a, *b)
+    }
+}
+
+
+impl Animatable for Vec3 {
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self {
+        lerp_vec(t, *a, *b)
+    }
+}
+
+
+impl Animatable for Quat {
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self {
+        Quat::slerp(t, a, b)
+    }
+}
+
+
+impl Animatable for Transform {
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self {
+        Transform::lerp(t, a, b)
+    }
+}
+
+
+impl Animatable for PrincipledParameters {
+    /// Interpolates every factor and the base color, textures and dispersion switch halfway
+    fn interpolate(t: f64, a: &Self, b: &Self) -> Self {
+        let textures = if t < 0.5 { a } else { b };
+        Self {
+            base_color: Color::raw_vec3_rgb(lerp_vec(t, a.base_color.get_raw_vec3f(), b.base_color.get_raw_vec3f())),
+            base_color_texture: textures.base_color_texture,
+            metallic: lerp(t, a.metallic, b.metallic),
+            roughness: lerp(t, a.roughness, b.roughness),
+            roughness_texture: textures.roughness_texture,
+            specular: lerp(t, a.specular, b.specular),
+            transmission: lerp(t, a.transmission, b.transmission),
+            ior: lerp(t, a.ior, b.ior),
+            dispersion: textures.dispersion,
+            clearcoat: lerp(t, a.clearcoat, b.clearcoat),
+            clearcoat_roughness: lerp(t, a.clearcoat_roughness, b.clearcoat_roughness),
+            sheen: lerp(t, a.sheen, b.sheen),
+            sheen_tint: lerp(t, a.sheen_tint, b.sheen_tint)
+        }
+    }
+}
+
+
+struct Keyframe<T> {
+    frame: f64,
+    value: T,
+    /// Easing of the segment starting at this keyframe
+    interpolation: Interpolation
+}
+
+
+/// Keyframes of a single value, sorted by frame
+pub struct Track<T: Animatable> {
+    keyframes: Vec<Keyframe<T>>
+}
+
+
+impl<T: Animatable> Track<T> {
+
+    pub fn new() -> Self {
+        Self {
+            keyframes: Vec::new()
+        }
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+
+    /// Adds a keyframe, replacing the one at the same frame if any
+    pub fn add(&mut self, frame: f64, value: T, interpolation: Interpolation) -> &mut Self {
+        let keyframe = Keyframe { frame: frame, value: value, interpolation: interpolation };
+        match self.keyframes.binary_search_by(|key| key.frame.total_cmp(&frame)) {
+            Ok(index) => self.keyframes[index] = keyframe,
+            Err(index) => self.keyframes.insert(index, keyframe)
+        }
+        self
+    }
+
+
+    /// Value at `frame`, holding the first and last keyframes outside of the animated range
+    pub fn sample(&self, frame: f64) -> Option<T> {
+        let first = self.keyframes.first()?;
+        if frame <= first.frame {
+            return Some(first.value.clone());
+        }
+
+        let next = self.keyframes.partition_point(|key| key.frame <= frame);
+        if next == self.keyframes.len() {
+            return Some(self.keyframes[next - 1].value.clone());
+        }
+
+        let (start, end) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (frame - start.frame) / (end.frame - start.frame);
+        Some(T::interpolate(start.interpolation.ease(t), &start.value, &end.value))
+    }
+}
+
+
+/// Keyframed camera, object transforms and principled material parameters.
+/// Empty tracks leave the animated values untouched.
+pub struct Animation {
+    pub camera_position: Track<Vec3>,
+    pub camera_rotation: Track<Quat>,
+    pub camera_focal_length: Track<f64>,
+    object_transforms: HashMap<Rid, Track<Transform>>,
+    materials: HashMap<Rid, Track<PrincipledParameters>>
+}
+
+
+impl Animation {
+
+    pub fn new() -> Self {
+        Self {
+            camera_position: Track::new(),
+            camera_rotation: Track::new(),
+            camera_focal_length: Track::new(),
+            object_transforms: HashMap::new(),
+            materials: HashMap::new()
+        }
+    }
+
+
+    /// Track of the local transform of an object
+    pub fn object_transform(&mut self, rid: Rid) -> &mut Track<Transform> {
+        self.object_transforms.entry(rid).or_insert_with(Track::new)
+    }
+
+
+    /// Track of the parameters of a material, which is replaced by a principled material when the animation is applied
+    pub fn material(&mut self, rid: Rid) -> &mut Track<PrincipledParameters> {
+        self.materials.entry(rid).or_insert_with(Track::new)
+    }
+
+
+    pub fn object_transforms(&self) -> impl Iterator<Item = (&Rid, &Track<Transform>)> {
+        self.object_transforms.iter()
+    }
+
+
+    pub fn materials(&self) -> impl Iterator<Item = (&Rid, &Track<PrincipledParameters>)> {
+        self.materials.iter()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("the track has keyframes");
+        assert!((actual - expected).abs() < 1e-6, "value {}, expected {}", actual, expected);
+    }
+
+
+    #[test]
+    fn empty_track_has_no_value() {
+        assert!(Track::<f64>::new().sample(0.0).is_none());
+    }
+
+
+    #[test]
+    fn track_holds_its_first_and_last_keyframes() {
+        let mut track = Track::new();
+        track.add(10.0, 1.0, Interpolation::Linear).add(20.0, 3.0, Interpolation::Linear);
+
+        assert_close(track.sample(-5.0), 1.0);
+        assert_close(track.sample(10.0), 1.0);
+        assert_close(track.sample(20.0), 3.0);
+        assert_close(track.sample(100.0), 3.0);
+    }
+
+
+    #[test]
+    fn track_interpolates_between_keyframes() {
+        let mut track = Track::new();
+        track.add(0.0, 0.0, Interpolation::Linear)
+            .add(10.0, 10.0, Interpolation::EASE_IN_OUT)
+            .add(20.0, 20.0, Interpolation::Linear);
+
+        assert_close(track.sample(2.5), 2.5);
+        // The eased segment is symmetric, slow at both ends
+        assert_close(track.sample(15.0), 15.0);
+        assert!(track.sample(12.5).unwrap() < 12.5);
+        assert!(track.sample(17.5).unwrap() > 17.5);
+    }
+
+
+    #[test]
+    fn keyframes_are_sorted_and_replaced() {
+        let mut track = Track::new();
+        track.add(20.0, 5.0, Interpolation::Linear)
+            .add(0.0, 1.0, Interpolation::Linear)
+            .add(20.0, 3.0, Interpolation::Linear);
+
+        assert_close(track.sample(10.0), 2.0);
+        assert_close(track.sample(20.0), 3.0);
+    }
+
+
+    #[test]
+    fn bezier_easing_goes_through_its_ends() {
+        let ease = Interpolation::Bezier(0.1, 0.7, 0.3, 1.0);
+        assert!(ease.ease(0.0).abs() < 1e-6);
+        assert!((ease.ease(1.0) - 1.0).abs() < 1e-6);
+        assert!(ease.ease(0.5) > 0.5, "the curve starts fast");
+    }
+}
@@ -0,0 +1,455 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::f64::consts::PI;
+
+use simple_term_renderer::math::Vec3;
+use simple_term_renderer::vec3;
+
+use crate::{HitInfo, Ray};
+use crate::rid::Rid;
+
+use super::{mul_elem, sample_cosine_hemisphere, CpuRenderingDevice, Frame};
+use super::light::{Light, LightSource};
+use super::mat::Material;
+
+
+/// Vertex of a camera or light subpath
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    position: Vec3,
+    /// Throughput of the subpath up to the vertex
+    beta: Vec3,
+    /// Density of sampling the vertex from the previous vertex of its subpath, per unit area
+    pdf_fwd: f64,
+    /// Density of sampling the vertex from the next vertex, were the path traced the other way, per unit area
+    pdf_rev: f64,
+    /// Whether the subpath continued along a specular lobe of the vertex
+    delta: bool
+}
+
+
+/// Sampling densities of a vertex, copied to be updated for a connection
+#[derive(Copy, Clone)]
+struct VertexDensities {
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    delta: bool
+}
+
+
+enum VertexKind<'a> {
+    Camera,
+    Light(&'a Light),
+    /// Point of an emissive surface a light subpath starts from
+    Emitter { hit: HitInfo, material: &'a dyn Material },
+    /// `wo` points towards the previous vertex of the subpath
+    Surface { hit: HitInfo, material: &'a dyn Material, wo: Vec3, object: Rid }
+}
+
+
+impl<'a> Vertex<'a> {
+
+    fn new(kind: VertexKind<'a>, position: Vec3, beta: Vec3, pdf_fwd: f64) -> Self {
+        Self {
+            kind: kind,
+            position: position,
+            beta: beta,
+            pdf_fwd: pdf_fwd,
+            pdf_rev: 0.0,
+            delta: false
+        }
+    }
+
+
+    fn densities(&self) -> VertexDensities {
+        VertexDensities {
+            pdf_fwd: self.pdf_fwd,
+            pdf_rev: self.pdf_rev,
+            delta: self.delta
+        }
+    }
+
+
+    /// Density with which the subpath would go from the vertex towards `direction`, having arrived along `wo`.
+    /// Directions are given with respect to solid angles.
+    fn pdf(&self, direction: Vec3, wo: Vec3) -> f64 {
+        match &self.kind {
+            VertexKind::Surface { hit, material, .. } => material.pdf(direction, wo, hit),
+            _ => 0.0
+        }
+    }
+
+
+    /// Density with which a light subpath starting at the vertex goes towards `direction`, over solid angles
+    fn emission_pdf(&self, direction: Vec3) -> f64 {
+        match &self.kind {
+            VertexKind::Light(light) => light.emission_pdf(direction),
+            VertexKind::Emitter { hit, .. } => direction.normalized().dot(hit.normal).max(0.0) / PI,
+            _ => 0.0
+        }
+    }
+
+
+    /// Converts a density over the directions leaving `from` to a density over the area around the vertex
+    fn area_density(&self, pdf: f64, from: Vec3) -> f64 {
+        let offset = self.position - from;
+        let distance_sq = offset.length_sq();
+        if distance_sq == 0.0 {
+            return 0.0;
+        }
+
+        let cos = match &self.kind {
+            VertexKind::Surface { hit, .. } | VertexKind::Emitter { hit, .. } => {
+                hit.normal.dot(offset).abs() / distance_sq.sqrt()
+            },
+            _ => 1.0
+        };
+        pdf * cos / distance_sq
+    }
+}
+
+
+impl CpuRenderingDevice {
+
+    /// Radiance arriving along the camera `ray`, estimated with bidirectional path tracing.
+    ///
+    /// Camera and light subpaths are connected in every possible way, the contributions being weighted with multiple
+    /// importance sampling (balance heuristic). Light subpaths start from the positional lights and emissive surfaces,
+    /// while the sky and distant lights are only reached from the camera. Subpaths are not connected to the camera
+    /// directly, and participating media are ignored.
+    pub(super) fn bidirectional_radiance(&self, ray: &Ray) -> Vec3 {
+        // Surface vertices of a complete path, as in `ray_color`
+        let max_surfaces = self.max_light_bounce.max(0) as usize + 1;
+        let mut radiance = Vec3::ZERO;
+
+        let mut camera_path = vec![Vertex::new(VertexKind::Camera, ray.origin, vec3!(1.0, 1.0, 1.0), 1.0)];
+        let escaped = self.trace_subpath(*ray, vec3!(1.0, 1.0, 1.0), 1.0, max_surfaces + 1, &mut camera_path);
+
+        // Emitted light found by the camera subpath, weighted against the light subpaths starting on the same surfaces
+        for t in 2..=camera_path.len() {
+            let vertex = &camera_path[t - 1];
+            if let VertexKind::Surface { hit, material, wo, .. } = &vertex.kind {
+                let emitted = mul_elem(vertex.beta, material.emitted(*wo, hit));
+                if emitted.length_sq() > 0.0 {
+                    radiance += self.emission_mis_weight(&camera_path, t, ray.time) * emitted;
+                }
+            }
+        }
+        if let Some((escaped_ray, beta)) = escaped {
+            radiance += mul_elem(beta, self.sky_color(&escaped_ray));
+        }
+
+        let light_path = self.light_subpath(ray.time, max_surfaces);
+
+        for t in 2..=camera_path.len() {
+            radiance += self.distant_lighting(&camera_path[t - 1], ray.time);
+
+            for s in 1..=light_path.len() {
+                if s + t - 2 > max_surfaces {
+                    break;
+                }
+                radiance += self.connect(&light_path, &camera_path, s, t, ray.time);
+            }
+        }
+
+        radiance
+    }
+
+
+    /// Subpath starting from a light picked proportionally to its power, of at most `max_length` vertices
+    fn light_subpath(&self, time: f64, max_length: usize) -> Vec<Vertex> {
+        let Some((source, probability)) = self.light_tree.sample_power() else {
+            return Vec::new();
+        };
+
+        let (mut path, ray, beta, pdf_direction) = match source {
+            LightSource::Light(light) => {
+                let Some(((direction, pdf_direction), position)) = light.sample_emission().zip(light.position()) else {
+                    return Vec::new();
+                };
+                let beta = (1.0 / probability) * vec3!(1.0, 1.0, 1.0);
+                let vertex = Vertex::new(VertexKind::Light(light), position, beta, probability);
+
+                let beta = (1.0 / (probability * pdf_direction)) * light.intensity(direction);
+                (vec![vertex], Ray::new(position, direction), beta, pdf_direction)
+            },
+            LightSource::Area(area_light) => {
+                let Some((hit, material, pdf_area)) = self.sample_area_light_surface(area_light, time) else {
+                    return Vec::new();
+                };
+                let local = sample_cosine_hemisphere();
+                let (direction, pdf_direction) = (Frame::from_normal(hit.normal).from_local(local), local.z / PI);
+                if pdf_direction <= 0.0 {
+                    return Vec::new();
+                }
+
+                // The cosine of the emitted light cancels with the density of the direction
+                let pdf_position = probability * pdf_area;
+                let beta = (PI / pdf_position) * material.emitted(direction, &hit);
+                let (position, ray) = (hit.position, hit.spawn_ray(direction));
+
+                let kind = VertexKind::Emitter { hit: hit, material: material };
+                let vertex = Vertex::new(kind, position, (1.0 / pdf_position) * vec3!(1.0, 1.0, 1.0), pdf_position);
+                (vec![vertex], ray, beta, pdf_direction)
+            }
+        };
+
+        self.trace_subpath(ray.with_time(time), beta, pdf_direction, max_length, &mut path);
+
+        // Subpaths are traced as if light decreased with the square of the distance, while the first surface lit by a
+        // positional light receives it according to the falloff of the light
+        let scale = match (&path[0].kind, path.get(1)) {
+            (VertexKind::Light(light), Some(first)) => {
+                let distance = (first.position - path[0].position).length();
+                distance * distance * light.attenuation(distance)
+            },
+            _ => 1.0
+        };
+        for vertex in &mut path[1..] {
+            vertex.beta = scale * vertex.beta;
+        }
+        path
+    }
+
+
+    /// Extends `path` by following `ray`, sampled with the density `pdf` from the last vertex, and scattering at
+    /// every surface until the path has `max_length` vertices. Returns the ray which left the scene with its
+    /// throughput, if any.
+    fn trace_subpath<'a>(
+        &'a self, mut ray: Ray, mut beta: Vec3, mut pdf: f64, max_length: usize, path: &mut Vec<Vertex<'a>>
+    ) -> Option<(Ray, Vec3)> {
+        while path.len() < max_length {
+            let Some((mut hit, &obj_rid)) = self.closest_hit(&ray) else {
+                return Some((ray, beta));
+            };
+
+            let material = self.shade(&mut hit, &obj_rid);
+            let wo = -ray.direction.normalized();
+            let (position, shading_normal) = (hit.position, hit.shading_normal);
+            let sample = material.sample(wo, &hit);
+            let next_ray = sample.map(|sample| hit.spawn_ray(sample.wi).with_time(ray.time));
+
+            let previous = path.last().unwrap().position;
+            let kind = VertexKind::Surface { hit: hit, material: material, wo: wo, object: obj_rid };
+            let mut vertex = Vertex::new(kind, position, beta, 0.0);
+            vertex.pdf_fwd = vertex.area_density(pdf, previous);
+
+            let (Some(sample), Some(next_ray)) = (sample, next_ray) else {
+                path.push(vertex);
+                return None; // The path was absorbed
+            };
+
+            let mut pdf_rev = vertex.pdf(wo, sample.wi);
+            pdf = sample.pdf;
+            if sample.is_specular() {
+                vertex.delta = true;
+                pdf = 0.0;
+                pdf_rev = 0.0;
+            }
+            path.push(vertex);
+
+            let index = path.len() - 2;
+            path[index].pdf_rev = path[index].area_density(pdf_rev, position);
+
+            beta = mul_elem(beta, sample.weight(shading_normal));
+            if beta.length_sq() == 0.0 {
+                return None;
+            }
+            ray = next_ray;
+        }
+
+        None
+    }
+
+
+    /// Light arriving from the distant lights at a vertex of the camera subpath, weighted by its BSDF
+    fn distant_lighting(&self, vertex: &Vertex, time: f64) -> Vec3 {
+        let VertexKind::Surface { hit, material, wo, .. } = &vertex.kind else {
+            return Vec3::ZERO;
+        };
+        let mut radiance = Vec3::ZERO;
+
+        for light in self.light_tree.distant_lights() {
+            let Some(sample) = light.sample(hit.position) else {
+                continue;
+            };
+            let f = sample.wi.dot(hit.shading_normal).abs() * material.eval(sample.wi, *wo, hit);
+            if f.length_sq() == 0.0 {
+                continue;
+            }
+
+            let shadow_ray = hit.spawn_ray(sample.wi).with_time(time);
+            let transmittance = self.shadow_transmittance(&shadow_ray, sample.distance, None);
+            radiance += mul_elem(mul_elem(f, sample.radiance), transmittance);
+        }
+
+        mul_elem(vertex.beta, radiance)
+    }
+
+
+    /// Weighted contribution of the path made of the first `s` vertices of the light subpath and the first `t`
+    /// vertices of the camera subpath, with `s` and `t` at least 1 and 2
+    fn connect(&self, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize, time: f64) -> Vec3 {
+        let (light_vertex, camera_vertex) = (&light_path[s - 1], &camera_path[t - 1]);
+        let VertexKind::Surface { hit, material, wo, .. } = &camera_vertex.kind else {
+            return Vec3::ZERO;
+        };
+
+        let offset = light_vertex.position - camera_vertex.position;
+        let distance = offset.length();
+        if distance == 0.0 {
+            return Vec3::ZERO;
+        }
+        let to_light = offset / distance;
+
+        let light_f = match &light_vertex.kind {
+            VertexKind::Light(light) => light.intensity(-to_light),
+            VertexKind::Emitter { hit: light_hit, material: light_material } => {
+                // Only the outside of emissive surfaces emits light
+                let cos = -to_light.dot(light_hit.normal);
+                if cos <= 0.0 {
+                    return Vec3::ZERO;
+                }
+                cos * light_material.emitted(-to_light, light_hit)
+            },
+            VertexKind::Surface { hit: light_hit, material: light_material, wo: light_wo, .. } => {
+                let cos = to_light.dot(light_hit.shading_normal).abs();
+                cos * light_material.eval(*light_wo, -to_light, light_hit)
+            },
+            VertexKind::Camera => return Vec3::ZERO
+        };
+        let camera_f = to_light.dot(hit.shading_normal).abs() * material.eval(to_light, *wo, hit);
+
+        let throughput = mul_elem(mul_elem(light_vertex.beta, light_f), mul_elem(camera_f, camera_vertex.beta));
+        if throughput.length_sq() == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let shadow_ray = hit.spawn_ray(to_light).with_time(time);
+        let transmittance = self.shadow_transmittance(&shadow_ray, distance, None);
+        let attenuation = match &light_vertex.kind {
+            VertexKind::Light(light) => light.attenuation(distance),
+            _ => 1.0 / (distance * distance)
+        };
+        let contribution = attenuation * mul_elem(throughput, transmittance);
+        if contribution.length_sq() == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        Self::mis_weight(light_path, camera_path, s, t) * contribution
+    }
+
+
+    /// Balance heuristic weight of the connection strategy `(s, t)`, against the other strategies able to sample
+    /// the same path
+    fn mis_weight(light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize) -> f64 {
+        let (light_vertex, camera_vertex) = (&light_path[s - 1], &camera_path[t - 1]);
+        let VertexKind::Surface { wo: camera_wo, .. } = camera_vertex.kind else {
+            return 0.0;
+        };
+        let to_light = (light_vertex.position - camera_vertex.position).normalized();
+
+        // Densities of the vertices along the connected path
+        let mut camera: Vec<VertexDensities> = camera_path[..t].iter().map(Vertex::densities).collect();
+        let mut light: Vec<VertexDensities> = light_path[..s].iter().map(Vertex::densities).collect();
+        camera[t - 1].delta = false;
+        light[s - 1].delta = false;
+
+        // Densities of sampling the vertices around the connection the other way
+        let pdf = match &light_vertex.kind {
+            VertexKind::Surface { wo, .. } => light_vertex.pdf(-to_light, *wo),
+            _ => light_vertex.emission_pdf(-to_light)
+        };
+        camera[t - 1].pdf_rev = camera_vertex.area_density(pdf, light_vertex.position);
+
+        let pdf = camera_vertex.pdf(camera_wo, to_light);
+        camera[t - 2].pdf_rev = camera_path[t - 2].area_density(pdf, camera_vertex.position);
+
+        let pdf = camera_vertex.pdf(to_light, camera_wo);
+        light[s - 1].pdf_rev = light_vertex.area_density(pdf, camera_vertex.position);
+
+        // Only surface vertices follow another light subpath vertex
+        if let VertexKind::Surface { wo, .. } = &light_vertex.kind {
+            let pdf = light_vertex.pdf(*wo, -to_light);
+            light[s - 2].pdf_rev = light_path[s - 2].area_density(pdf, light_vertex.position);
+        }
+
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        // Strategies with fewer camera vertices, which must keep at least two of them
+        let mut ratio = 1.0;
+        for i in (2..t).rev() {
+            ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        // Strategies with fewer light vertices. Camera subpaths can end on emissive surfaces, but not on positional
+        // lights, which are delta lights.
+        let starts_on_emitter = matches!(light_path[0].kind, VertexKind::Emitter { .. });
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+            let delta_before = if i > 0 { light[i - 1].delta } else { !starts_on_emitter };
+            if !light[i].delta && !delta_before {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+
+    /// Balance heuristic weight of the light emitted by the vertex `t - 1` of the camera subpath towards the previous
+    /// vertex, against the strategies connecting to a light subpath starting on the same emissive surface
+    fn emission_mis_weight(&self, camera_path: &[Vertex], t: usize, time: f64) -> f64 {
+        let (vertex, previous) = (&camera_path[t - 1], &camera_path[t - 2]);
+        let VertexKind::Surface { hit, object, .. } = &vertex.kind else {
+            return 1.0;
+        };
+        let probability = self.light_tree.area_light_power_probability(*object);
+        if probability == 0.0 { // No light subpath starts on the surface
+            return 1.0;
+        }
+
+        // Densities of the light subpath starting at the vertex and going towards the previous one
+        let mut camera: Vec<VertexDensities> = camera_path[..t].iter().map(Vertex::densities).collect();
+        camera[t - 1].delta = false;
+        camera[t - 1].pdf_rev = probability * self.surface_density(hit, object, time);
+
+        let pdf = (previous.position - vertex.position).normalized().dot(hit.normal).max(0.0) / PI;
+        camera[t - 2].pdf_rev = previous.area_density(pdf, vertex.position);
+
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        // Strategies with fewer camera vertices, which must keep at least two of them
+        let mut ratio = 1.0;
+        for i in (2..t).rev() {
+            ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+}
@@ -0,0 +1,178 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::sync::Arc;
+
+use crate::path_tracer::{Ray, HitInfo};
+use crate::path_tracer::math::*;
+use crate::rid::Rid;
+use crate::transform::{ObjectToWorld, Transform};
+
+use super::obj::Object;
+
+
+/// Boolean operation combining the two operands of a `Csg` object
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// The second operand is carved out of the first one
+    Difference
+}
+
+
+impl CsgOperation {
+    fn inside(&self, left: bool, right: bool) -> bool {
+        match self {
+            CsgOperation::Union => left || right,
+            CsgOperation::Intersection => left && right,
+            CsgOperation::Difference => left && !right
+        }
+    }
+}
+
+
+/// Operand of a CSG operation. Its hits are shaded with the material of the object it comes from.
+pub struct CsgOperand {
+    rid: Rid,
+    geometry: Arc<dyn Object>,
+    transform: ObjectToWorld
+}
+
+
+impl CsgOperand {
+
+    pub fn new(rid: Rid, geometry: Arc<dyn Object>, transform: Transform) -> Self {
+        Self {
+            rid: rid,
+            geometry: geometry,
+            transform: ObjectToWorld::new(transform.to_affine())
+        }
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.transform.box_to_world(&self.geometry.bounding_box()?))
+    }
+
+
+    /// Surface crossings in the space of the CSG object, the first one telling whether the ray starts inside
+    fn crossings(&self, ray: &Ray, interval: &Interval) -> Vec<HitInfo> {
+        let local_ray = self.transform.ray_to_object(ray);
+
+        self.geometry.hit_all(&local_ray, interval).into_iter()
+            .map(|hit| {
+                let mut hit = self.transform.hit_to_world(hit);
+                hit.material_object.get_or_insert(self.rid);
+                hit
+            })
+            .collect()
+    }
+}
+
+
+/// Solid built from two operands. Operands should be closed surfaces, or planes bounding half spaces.
+pub struct Csg {
+    operation: CsgOperation,
+    left: CsgOperand,
+    right: CsgOperand
+}
+
+
+impl Csg {
+
+    pub fn new(operation: CsgOperation, left: CsgOperand, right: CsgOperand) -> Self {
+        Self {
+            operation: operation,
+            left: left,
+            right: right
+        }
+    }
+
+
+    /// Crossings of the boundary of the combined solid, merging the crossings of both operands
+    fn crossings(&self, ray: &Ray, interval: &Interval) -> Vec<HitInfo> {
+        // The inside state at the start of the interval is known from the first crossing of each operand
+        let query = Interval::new(interval.start(), f64::INFINITY);
+        let left = self.left.crossings(ray, &query);
+        let right = self.right.crossings(ray, &query);
+
+        let mut left_inside = left.first().is_some_and(|hit| !hit.front_face);
+        let mut right_inside = right.first().is_some_and(|hit| !hit.front_face);
+        let mut inside = self.operation.inside(left_inside, right_inside);
+
+        let mut left = left.into_iter().peekable();
+        let mut right = right.into_iter().peekable();
+        let mut result = Vec::new();
+
+        loop {
+            let from_left = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) => l.distance <= r.distance,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break
+            };
+
+            let mut hit = if from_left {
+                let hit = left.next().unwrap();
+                left_inside = !hit.front_face;
+                hit
+            } else {
+                let hit = right.next().unwrap();
+                right_inside = !hit.front_face;
+                hit
+            };
+
+            let now_inside = self.operation.inside(left_inside, right_inside);
+            if now_inside != inside {
+                inside = now_inside;
+                if hit.distance >= interval.end() {
+                    break;
+                }
+                if interval.surrounds(hit.distance) {
+                    // The side of the combined solid, e.g. the inner surface of a carved hole faces the hole
+                    hit.front_face = now_inside;
+                    result.push(hit);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+
+impl Object for Csg {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        self.crossings(ray, interval).into_iter().next()
+    }
+
+
+    fn hit_all(&self, ray: &Ray, interval: &Interval) -> Vec<HitInfo> {
+        self.crossings(ray, interval)
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self.operation {
+            CsgOperation::Union => Some(self.left.bounding_box()?.union(&self.right.bounding_box()?)),
+            // The result is contained in the first operand
+            CsgOperation::Intersection | CsgOperation::Difference => self.left.bounding_box()
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use simple_term_renderer::math::Vec3;
+use simple_term_renderer::vec3;
+
+use crate::{DebugView, Ray};
+use crate::rid::Rid;
+
+use super::{lerp_vec, mul_elem, sample_cosine_hemisphere, CpuRenderingDevice, Frame};
+
+
+impl CpuRenderingDevice {
+
+    /// Colour of `view` along a camera ray. Colours are squared to undo the gamma correction of the display.
+    pub(super) fn debug_radiance(&self, ray: &Ray, view: DebugView) -> Vec3 {
+        let color = self.debug_color(ray, view);
+        mul_elem(color, color)
+    }
+
+
+    fn debug_color(&self, ray: &Ray, view: DebugView) -> Vec3 {
+        if view == DebugView::BounceCount {
+            return self.bounce_heatmap(ray);
+        }
+
+        let Some((mut hit, obj_rid)) = self.closest_hit(ray) else {
+            return Vec3::ZERO;
+        };
+
+        match view {
+            DebugView::AmbientOcclusion(radius) => {
+                let direction = Frame::from_normal(hit.normal).from_local(sample_cosine_hemisphere());
+                let occlusion_ray = hit.spawn_ray(direction).with_time(ray.time);
+                self.shadow_transmittance(&occlusion_ray, radius, None)
+            },
+            DebugView::ShadingNormal => {
+                self.shade(&mut hit, obj_rid); // Applies normal maps
+                0.5 * (hit.outward_shading_normal() + vec3!(1.0, 1.0, 1.0))
+            },
+            DebugView::GeometricNormal => 0.5 * (hit.outward_normal() + vec3!(1.0, 1.0, 1.0)),
+            DebugView::Depth => {
+                let depth = hit.distance * ray.direction.length();
+                (1.0 / (1.0 + depth)) * vec3!(1.0, 1.0, 1.0)
+            },
+            DebugView::Uv => vec3!(hit.uv.x - hit.uv.x.floor(), hit.uv.y - hit.uv.y.floor(), 0.0),
+            DebugView::ObjectId => id_color(hit.material_object.as_ref().unwrap_or(obj_rid)),
+            DebugView::MaterialId => id_color(&self.hit_material(&hit, obj_rid)),
+            DebugView::Barycentrics => hit.barycentrics.unwrap_or(Vec3::ZERO),
+            DebugView::BounceCount => unreachable!()
+        }
+    }
+
+
+    /// Number of surfaces a path reaches before it ends, as a colour going from blue for none to red for paths cut at
+    /// the maximum number of bounces
+    fn bounce_heatmap(&self, ray: &Ray) -> Vec3 {
+        let max_surfaces = self.max_light_bounce.max(0) as usize + 1;
+        let mut ray = *ray;
+        let mut surfaces = 0;
+
+        while surfaces < max_surfaces {
+            let Some((mut hit, obj_rid)) = self.closest_hit(&ray) else {
+                break;
+            };
+            surfaces += 1;
+
+            let material = self.shade(&mut hit, obj_rid);
+            let Some(sample) = material.sample(-ray.direction.normalized(), &hit) else {
+                break;
+            };
+            ray = hit.spawn_ray(sample.wi).with_time(ray.time);
+        }
+
+        let t = surfaces as f64 / max_surfaces as f64;
+        if t < 0.5 {
+            lerp_vec(2.0 * t, vec3!(0.0, 0.0, 1.0), vec3!(0.0, 1.0, 0.0))
+        } else {
+            lerp_vec(2.0 * t - 1.0, vec3!(0.0, 1.0, 0.0), vec3!(1.0, 0.0, 0.0))
+        }
+    }
+}
+
+
+/// Arbitrary colour derived from a resource id, so that different ids are told apart
+fn id_color(rid: &Rid) -> Vec3 {
+    let mut hasher = DefaultHasher::new();
+    rid.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    let channel = |shift: u64| ((hash >> shift) & 0xff) as f64 / 255.0;
+    vec3!(channel(0), channel(8), channel(16))
+}
@@ -0,0 +1,123 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use simple_term_renderer::math::Vec3;
+
+
+/// Linear radiance of every pixel of a rendered image, rows going from top to bottom
+pub struct Film {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    /// Contributions splatted onto pixels, kept apart as they can be added concurrently. Channels are stored as the
+    /// bits of `f64`s.
+    splats: Vec<[AtomicU64; 3]>
+}
+
+
+impl Film {
+
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width: width,
+            height: height,
+            pixels: vec![Vec3::ZERO; width * height],
+            splats: (0..width * height).map(|_| [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]).collect()
+        }
+    }
+
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+
+    /// Radiance of a pixel, splats included
+    pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        let [r, g, b] = &self.splats[y * self.width + x];
+        let splat = Vec3::new(
+            f64::from_bits(r.load(Ordering::Relaxed)),
+            f64::from_bits(g.load(Ordering::Relaxed)),
+            f64::from_bits(b.load(Ordering::Relaxed))
+        );
+        self.pixels[y * self.width + x] + splat
+    }
+
+
+    /// Sets the radiance of a pixel, apart from its splats
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Vec3) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+
+    pub fn add_sample(&mut self, x: usize, y: usize, color: Vec3) {
+        self.pixels[y * self.width + x] += color;
+    }
+
+
+    /// Adds a contribution to a pixel. It only needs a shared reference, so that several threads can splat at once.
+    pub fn add_splat(&self, x: usize, y: usize, color: Vec3) {
+        let [r, g, b] = &self.splats[y * self.width + x];
+        for (channel, value) in [(r, color.x), (g, color.y), (b, color.z)] {
+            let _ = channel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+        }
+    }
+
+
+    /// Display colour of a pixel: gamma corrected and clamped to [0; 1]
+    pub fn display_pixel(&self, x: usize, y: usize) -> Vec3 {
+        let color = self.pixel(x, y);
+        Vec3::new(
+            color.x.max(0.0).sqrt().clamp(0.0, 1.0),
+            color.y.max(0.0).sqrt().clamp(0.0, 1.0),
+            color.z.max(0.0).sqrt().clamp(0.0, 1.0)
+        )
+    }
+
+
+    /// Writes the display colours to a binary PPM file
+    pub fn write_ppm(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = self.display_pixel(x, y);
+                writer.write_all(&[
+                    (255.0 * color.x).round() as u8,
+                    (255.0 * color.y).round() as u8,
+                    (255.0 * color.z).round() as u8
+                ])?;
+            }
+        }
+
+        writer.flush()
+    }
+}
@@ -0,0 +1,537 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::collections::HashMap;
+use std::f64::consts::{PI, TAU};
+
+use simple_term_renderer::math::Vec3;
+
+use crate::path_tracer::sampler::random;
+use crate::rid::Rid;
+
+use super::{luminance, Aabb, Frame};
+
+
+/// Light emitting from a single point or direction. Delta lights can not be hit by rays, they are only reached by
+/// next event estimation.
+#[derive(Debug, Copy, Clone)]
+pub enum Light {
+    /// Light emitting `intensity` in every direction, decreasing with the distance `d` as `1 / d^falloff`.
+    /// A falloff of 2 is physically based, lower values light distant surfaces more.
+    Point { position: Vec3, intensity: Vec3, falloff: f64 },
+    /// Point light restricted to a cone around `direction`, fading out from the inner to the outer half angle, in
+    /// radians
+    Spot { position: Vec3, direction: Vec3, intensity: Vec3, falloff: f64, inner_angle: f64, outer_angle: f64 },
+    /// Light coming from infinitely far away, travelling along `direction`. Surfaces facing it receive `irradiance`.
+    Directional { direction: Vec3, irradiance: Vec3 }
+}
+
+
+/// Light reaching a point from a light
+#[derive(Debug, Copy, Clone)]
+pub struct LightSample {
+    /// Normalized direction towards the light
+    pub wi: Vec3,
+    /// Distance to the light, infinite for directional lights
+    pub distance: f64,
+    /// Light arriving at the point, before being occluded or attenuated by media
+    pub radiance: Vec3
+}
+
+
+impl Light {
+
+    pub fn point(position: Vec3, intensity: Vec3, falloff: f64) -> Self {
+        Light::Point { position: position, intensity: intensity, falloff: falloff }
+    }
+
+
+    pub fn spot(
+        position: Vec3, direction: Vec3, intensity: Vec3, falloff: f64, inner_angle: f64, outer_angle: f64
+    ) -> Self {
+        Light::Spot {
+            position: position,
+            direction: direction.normalized(),
+            intensity: intensity,
+            falloff: falloff,
+            inner_angle: inner_angle.min(outer_angle),
+            outer_angle: outer_angle
+        }
+    }
+
+
+    pub fn directional(direction: Vec3, irradiance: Vec3) -> Self {
+        Light::Directional { direction: direction.normalized(), irradiance: irradiance }
+    }
+
+
+    /// Light arriving at `point`, or `None` if the point is not lit
+    pub fn sample(&self, point: Vec3) -> Option<LightSample> {
+        match *self {
+            Light::Point { position, intensity, falloff } => {
+                Self::sample_position(point, position, intensity, falloff)
+            },
+            Light::Spot { position, direction, intensity, falloff, inner_angle, outer_angle } => {
+                let sample = Self::sample_position(point, position, intensity, falloff)?;
+                let cos_theta = (-sample.wi).dot(direction);
+                let attenuation = smoothstep(outer_angle.cos(), inner_angle.cos(), cos_theta);
+                if attenuation <= 0.0 {
+                    return None;
+                }
+                Some(LightSample { radiance: attenuation * sample.radiance, ..sample })
+            },
+            Light::Directional { direction, irradiance } => {
+                Some(LightSample { wi: -direction, distance: f64::INFINITY, radiance: irradiance })
+            }
+        }
+    }
+
+
+    /// Position of the light, `None` for lights infinitely far away
+    pub fn position(&self) -> Option<Vec3> {
+        match *self {
+            Light::Point { position, .. } | Light::Spot { position, .. } => Some(position),
+            Light::Directional { .. } => None
+        }
+    }
+
+
+    /// Radiant intensity leaving a positional light along `direction`
+    pub fn intensity(&self, direction: Vec3) -> Vec3 {
+        match *self {
+            Light::Point { intensity, .. } => intensity,
+            Light::Spot { direction: axis, intensity, inner_angle, outer_angle, .. } => {
+                let cos_theta = direction.normalized().dot(axis);
+                smoothstep(outer_angle.cos(), inner_angle.cos(), cos_theta) * intensity
+            },
+            Light::Directional { .. } => Vec3::ZERO
+        }
+    }
+
+
+    /// Factor by which the light of a positional light decreases at `distance`, following its falloff
+    pub fn attenuation(&self, distance: f64) -> f64 {
+        match *self {
+            Light::Point { falloff, .. } | Light::Spot { falloff, .. } => 1.0 / distance.powf(falloff),
+            Light::Directional { .. } => 1.0
+        }
+    }
+
+
+    /// Samples a direction in which a positional light emits, uniformly over the sphere or the outer cone of spot
+    /// lights. Returns the direction with its density over solid angles.
+    pub fn sample_emission(&self) -> Option<(Vec3, f64)> {
+        let (axis, cos_max) = self.emission_cone()?;
+        let cos_theta = 1.0 - random() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = TAU * random();
+
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        Some((Frame::from_normal(axis).from_local(local), 1.0 / (TAU * (1.0 - cos_max))))
+    }
+
+
+    /// Density with which `sample_emission` returns `direction`
+    pub fn emission_pdf(&self, direction: Vec3) -> f64 {
+        match self.emission_cone() {
+            Some((axis, cos_max)) if direction.normalized().dot(axis) >= cos_max => 1.0 / (TAU * (1.0 - cos_max)),
+            _ => 0.0
+        }
+    }
+
+
+    /// Axis and cosine of the half angle of the cone light is emitted in
+    fn emission_cone(&self) -> Option<(Vec3, f64)> {
+        match *self {
+            Light::Point { .. } => Some((Vec3::UNIT_Z, -1.0)),
+            Light::Spot { direction, outer_angle, .. } => Some((direction, outer_angle.cos())),
+            Light::Directional { .. } => None
+        }
+    }
+
+
+    /// Region the light emits from, `None` for lights infinitely far away
+    pub fn bounds(&self) -> Option<Aabb> {
+        match *self {
+            Light::Point { position, .. } | Light::Spot { position, .. } => Some(Aabb::new(position, position)),
+            Light::Directional { .. } => None
+        }
+    }
+
+
+    /// Total luminous power of the light, used to choose between lights
+    pub fn power(&self) -> f64 {
+        match *self {
+            Light::Point { intensity, .. } => 2.0 * TAU * luminance(intensity),
+            Light::Spot { intensity, inner_angle, outer_angle, .. } => {
+                let cone_angle = 0.5 * (inner_angle + outer_angle);
+                TAU * (1.0 - cone_angle.cos()) * luminance(intensity)
+            },
+            Light::Directional { irradiance, .. } => PI * luminance(irradiance)
+        }
+    }
+
+
+    fn sample_position(point: Vec3, position: Vec3, intensity: Vec3, falloff: f64) -> Option<LightSample> {
+        let to_light = position - point;
+        let distance = to_light.length();
+        if distance == 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            wi: to_light / distance,
+            distance: distance,
+            radiance: (1.0 / distance.powf(falloff)) * intensity
+        })
+    }
+}
+
+
+/// Emissive object sampled as a light. Points of its surface are picked by the rendering device, which knows its shape.
+#[derive(Copy, Clone)]
+pub struct AreaLight {
+    pub object: Rid,
+    /// World space box containing the object
+    pub bounds: Aabb,
+    /// Estimate of the luminous power emitted by the surface
+    pub power: f64
+}
+
+
+/// Light of the light hierarchy: a light of the scene or an emissive object
+#[derive(Copy, Clone)]
+pub enum LightSource {
+    Light(Light),
+    Area(AreaLight)
+}
+
+
+impl LightSource {
+
+    /// Region the light emits from, `None` for lights infinitely far away
+    pub fn bounds(&self) -> Option<Aabb> {
+        match self {
+            LightSource::Light(light) => light.bounds(),
+            LightSource::Area(area) => Some(area.bounds)
+        }
+    }
+
+
+    /// Power used to choose between lights
+    pub fn power(&self) -> f64 {
+        match self {
+            LightSource::Light(light) => light.power(),
+            LightSource::Area(area) => area.power
+        }
+    }
+}
+
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 {
+        return if x >= edge0 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+
+/// Bounding volume hierarchy over the lights of a scene. It picks one of them with a probability proportional to an
+/// estimate of its contribution to a point, so that scenes with many lights do not cost more per sample.
+/// Lights infinitely far away are kept apart, since they light every point the same way.
+pub struct LightTree {
+    lights: Vec<LightSource>,
+    nodes: Vec<LightNode>,
+    root: Option<usize>,
+    /// Leaf node of every light
+    leaves: Vec<usize>,
+    /// Index of the area light of every emissive object
+    area_lights: HashMap<Rid, usize>,
+    distant_lights: Vec<Light>
+}
+
+
+struct LightNode {
+    bounds: Aabb,
+    power: f64,
+    parent: Option<usize>,
+    content: LightNodeContent
+}
+
+
+enum LightNodeContent {
+    /// Index of the light
+    Leaf(usize),
+    /// Indices of the children nodes
+    Interior(usize, usize)
+}
+
+
+impl LightTree {
+
+    pub fn new(lights: impl Iterator<Item = LightSource>) -> Self {
+        let mut tree = Self {
+            lights: Vec::new(),
+            nodes: Vec::new(),
+            root: None,
+            leaves: Vec::new(),
+            area_lights: HashMap::new(),
+            distant_lights: Vec::new()
+        };
+
+        for light in lights {
+            match light {
+                LightSource::Light(light) if light.bounds().is_none() => tree.distant_lights.push(light),
+                LightSource::Area(area) => {
+                    tree.area_lights.insert(area.object, tree.lights.len());
+                    tree.lights.push(light);
+                },
+                LightSource::Light(_) => tree.lights.push(light)
+            }
+        }
+
+        tree.leaves = vec![0; tree.lights.len()];
+        let mut indices: Vec<usize> = (0..tree.lights.len()).collect();
+        if !indices.is_empty() {
+            tree.root = Some(tree.build(&mut indices));
+        }
+        tree
+    }
+
+
+    /// Builds the node containing the lights of `indices`, splitting them in halves along the longest axis of their
+    /// bounds
+    fn build(&mut self, indices: &mut [usize]) -> usize {
+        let bounds = indices.iter()
+            .map(|i| self.lights[*i].bounds().unwrap())
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+
+        let node = if let [index] = indices {
+            self.leaves[*index] = self.nodes.len();
+            LightNode {
+                bounds: bounds,
+                power: self.lights[*index].power(),
+                parent: None,
+                content: LightNodeContent::Leaf(*index)
+            }
+        } else {
+            let extent = bounds.extent();
+            let axis = |p: Vec3| if extent.x >= extent.y && extent.x >= extent.z {
+                p.x
+            } else if extent.y >= extent.z {
+                p.y
+            } else {
+                p.z
+            };
+            indices.sort_by(|a, b| {
+                let a = axis(self.lights[*a].bounds().unwrap().center());
+                let b = axis(self.lights[*b].bounds().unwrap().center());
+                a.total_cmp(&b)
+            });
+
+            let (left, right) = indices.split_at_mut(indices.len() / 2);
+            let left = self.build(left);
+            let right = self.build(right);
+            self.nodes[left].parent = Some(self.nodes.len());
+            self.nodes[right].parent = Some(self.nodes.len());
+            LightNode {
+                bounds: bounds,
+                power: self.nodes[left].power + self.nodes[right].power,
+                parent: None,
+                content: LightNodeContent::Interior(left, right)
+            }
+        };
+
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+
+    /// Total power of the lights of the hierarchy
+    pub fn power(&self) -> f64 {
+        self.root.map_or(0.0, |root| self.nodes[root].power)
+    }
+
+
+    /// Picks a light of the hierarchy proportionally to its power, returning it along with the probability of having
+    /// picked it
+    pub fn sample_power(&self) -> Option<(&LightSource, f64)> {
+        let mut node = &self.nodes[self.root?];
+        let mut probability = 1.0;
+
+        while let LightNodeContent::Interior(left, right) = node.content {
+            let (left, right) = (&self.nodes[left], &self.nodes[right]);
+            let left_probability = if node.power > 0.0 { left.power / node.power } else { 0.5 };
+
+            if random() < left_probability {
+                node = left;
+                probability *= left_probability;
+            } else {
+                node = right;
+                probability *= 1.0 - left_probability;
+            }
+        }
+
+        match node.content {
+            LightNodeContent::Leaf(index) => Some((&self.lights[index], probability)),
+            LightNodeContent::Interior(..) => unreachable!()
+        }
+    }
+
+
+    /// Lights infinitely far away, which are not part of the hierarchy
+    pub fn distant_lights(&self) -> &[Light] {
+        &self.distant_lights
+    }
+
+
+    /// Picks a light of the hierarchy for `point`, returning it along with the probability of having picked it
+    pub fn sample(&self, point: Vec3) -> Option<(&LightSource, f64)> {
+        let mut node = &self.nodes[self.root?];
+        let mut probability = 1.0;
+
+        loop {
+            match node.content {
+                LightNodeContent::Leaf(index) => return Some((&self.lights[index], probability)),
+                LightNodeContent::Interior(left, right) => {
+                    let left_probability = self.left_probability(left, right, point);
+                    if random() < left_probability {
+                        node = &self.nodes[left];
+                        probability *= left_probability;
+                    } else {
+                        node = &self.nodes[right];
+                        probability *= 1.0 - left_probability;
+                    }
+                }
+            }
+        }
+    }
+
+
+    /// Probability that `sample` picks the area light of `object` for `point`, zero if the object does not emit
+    pub fn area_light_probability(&self, point: Vec3, object: Rid) -> f64 {
+        self.leaf_probability(object, |left, right| self.left_probability(left, right, point))
+    }
+
+
+    /// Probability that `sample_power` picks the area light of `object`, zero if the object does not emit
+    pub fn area_light_power_probability(&self, object: Rid) -> f64 {
+        self.leaf_probability(object, |left, right| {
+            let power = self.nodes[left].power + self.nodes[right].power;
+            if power > 0.0 { self.nodes[left].power / power } else { 0.5 }
+        })
+    }
+
+
+    /// Probability of reaching the leaf of the area light of `object` when going down to the left child of the
+    /// interior nodes with the probability given by `left_probability`
+    fn leaf_probability(&self, object: Rid, left_probability: impl Fn(usize, usize) -> f64) -> f64 {
+        let Some(&index) = self.area_lights.get(&object) else {
+            return 0.0;
+        };
+
+        let mut node = self.leaves[index];
+        let mut probability = 1.0;
+        while let Some(parent) = self.nodes[node].parent {
+            let LightNodeContent::Interior(left, right) = self.nodes[parent].content else {
+                unreachable!()
+            };
+            let left_probability = left_probability(left, right);
+            probability *= if node == left { left_probability } else { 1.0 - left_probability };
+            node = parent;
+        }
+        probability
+    }
+
+
+    /// Probability of going down to the `left` node rather than the `right` one when sampling a light for `point`
+    fn left_probability(&self, left: usize, right: usize, point: Vec3) -> f64 {
+        let left_importance = self.nodes[left].importance(point);
+        let right_importance = self.nodes[right].importance(point);
+        if left_importance + right_importance > 0.0 {
+            left_importance / (left_importance + right_importance)
+        } else {
+            0.5
+        }
+    }
+}
+
+
+impl LightNode {
+
+    /// Estimate of the light arriving at `point` from the lights of the node, which are assumed to be at the center
+    /// of the bounds. Points close to or inside of the bounds use the size of the bounds instead of the distance.
+    fn importance(&self, point: Vec3) -> f64 {
+        let distance_sq = (point - self.bounds.center()).length_sq();
+        let radius_sq = 0.25 * self.bounds.extent().length_sq();
+        self.power / distance_sq.max(radius_sq).max(1e-6)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rid::RidOwner;
+
+
+    #[test]
+    fn area_light_probability_matches_sampling() {
+        let mut objects = RidOwner::new();
+        let mut area_lights = Vec::new();
+        for i in 0..5 {
+            let center = Vec3::new(2.0 * i as f64, 1.0, 0.0);
+            let half_size = Vec3::new(0.5, 0.5, 0.5);
+            area_lights.push(AreaLight {
+                object: objects.add(()),
+                bounds: Aabb::new(center - half_size, center + half_size),
+                power: 1.0 + i as f64
+            });
+        }
+
+        let point_light = Light::point(Vec3::new(-1.0, 2.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 2.0);
+        let lights = area_lights.iter().copied().map(LightSource::Area).chain([LightSource::Light(point_light)]);
+        let tree = LightTree::new(lights);
+
+        let point = Vec3::new(3.0, 0.0, 1.0);
+        let area_probability: f64 = area_lights.iter()
+            .map(|light| tree.area_light_probability(point, light.object))
+            .sum();
+        assert!(area_probability > 0.0 && area_probability < 1.0);
+
+        for _ in 0..100 {
+            let (light, probability) = tree.sample(point).unwrap();
+            let expected = match light {
+                LightSource::Area(area_light) => tree.area_light_probability(point, area_light.object),
+                LightSource::Light(_) => 1.0 - area_probability
+            };
+            assert!((probability - expected).abs() < 1e-9, "probability {}, expected {}", probability, expected);
+        }
+    }
+
+
+    #[test]
+    fn objects_without_area_light_are_never_sampled() {
+        let mut objects = RidOwner::new();
+        let light = Light::point(Vec3::ZERO, Vec3::new(1.0, 1.0, 1.0), 2.0);
+        let tree = LightTree::new([LightSource::Light(light)].into_iter());
+        assert_eq!(tree.area_light_probability(Vec3::new(1.0, 0.0, 0.0), objects.add(())), 0.0);
+    }
+}
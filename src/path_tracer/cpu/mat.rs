@@ -16,28 +16,256 @@ OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
 */
 
 
+use std::f64::consts::PI;
+use std::ops::BitOr;
+use std::sync::Arc;
+
+use simple_term_renderer::img::Color;
 use simple_term_renderer::math::Vec3;
+use simple_term_renderer::vec2;
+
+use crate::HitInfo;
+use crate::rid::Rid;
+use crate::path_tracer::sampler::random;
+
+use super::{luminance, mul_elem, random_unit_vec, refract, sample_cosine_hemisphere, Frame};
+use super::medium::{HomogeneousMedium, Medium};
+use super::spectrum::Dispersion;
+use super::microfacet::*;
+use super::tex::Texture;
+
+
+
+/// Kinds of lobes a BSDF is made of
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BsdfFlags(u8);
+
+
+impl BsdfFlags {
+    pub const NONE: Self = Self(0);
+    pub const REFLECTION: Self = Self(1 << 0);
+    pub const TRANSMISSION: Self = Self(1 << 1);
+    pub const DIFFUSE: Self = Self(1 << 2);
+    pub const GLOSSY: Self = Self(1 << 3);
+    /// Delta lobe: it can only be sampled, never evaluated
+    pub const SPECULAR: Self = Self(1 << 4);
+
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+
+    pub fn is_specular(self) -> bool {
+        self.intersects(Self::SPECULAR)
+    }
+
+
+    /// Whether the BSDF has at least one lobe that can be evaluated
+    pub fn is_non_specular(self) -> bool {
+        self.intersects(Self::DIFFUSE | Self::GLOSSY)
+    }
+}
+
+
+impl BitOr for BsdfFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+
+/// Direction sampled from a BSDF.
+///
+/// For specular lobes, `f` and `pdf` are given with respect to the delta distribution (`pdf` is the probability of
+/// having chosen that lobe), so that `f * |cos| / pdf` stays the weight of the sample.
+#[derive(Debug, Copy, Clone)]
+pub struct BsdfSample {
+    pub wi: Vec3,
+    pub f: Vec3,
+    pub pdf: f64,
+    pub flags: BsdfFlags
+}
+
+
+impl BsdfSample {
+
+    pub fn new(wi: Vec3, f: Vec3, pdf: f64, flags: BsdfFlags) -> Self {
+        Self {
+            wi: wi,
+            f: f,
+            pdf: pdf,
+            flags: flags
+        }
+    }
+
 
-use crate::{HitInfo, Ray};
+    /// Throughput weight of the sample, `f * |cos| / pdf`, `normal` being the shading normal
+    pub fn weight(&self, normal: Vec3) -> Vec3 {
+        if self.pdf <= 0.0 {
+            return Vec3::ZERO;
+        }
+        self.wi.dot(normal).abs() / self.pdf * self.f
+    }
 
-use super::{is_approx_zero, random_on_hemisphere, random_unit_vec, reflect};
 
+    pub fn is_specular(&self) -> bool {
+        self.flags.is_specular()
+    }
+}
 
 
+/// Scattering behaviour of a surface.
+///
+/// Every direction is given in world space and points away from the surface: `wo` towards the viewer and `wi`
+/// towards the light.
 pub trait Material {
-    fn scatter(&self, in_ray: &Ray, hit_info: &HitInfo) -> (Vec3, Ray);
+    /// Samples an incoming direction for the outgoing direction `wo`
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample>;
+
+    /// Evaluates the non specular lobes of the BSDF for a pair of directions
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3;
+
+    /// Density with which `sample` returns `wi` for `wo`, zero for specular lobes
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64;
+
+    /// Lobes of the BSDF at the hit point
+    fn flags(&self, hit_info: &HitInfo) -> BsdfFlags;
+
+    /// Radiance emitted by the surface towards `wo`
+    fn emitted(&self, _wo: Vec3, _hit_info: &HitInfo) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    /// Rough average of the radiance emitted by the surface, used to weigh emissive objects against the other lights.
+    /// Zero for materials which do not emit light.
+    fn average_emission(&self) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    /// Whether the material scatters differently depending on the wavelength, which the secondary wavelengths of a
+    /// spectral path can not follow
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// Whether the material is an invisible interface, which shadow rays go through
+    fn is_interface(&self) -> bool {
+        false
+    }
+
+    /// Medium filling the inside of objects using the material, in which light entering the surface random walks
+    fn interior_medium(&self) -> Option<&dyn Medium> {
+        None
+    }
+}
+
+
+/// Shading frame of a hit, built around the outward shading normal of the surface
+pub fn shading_frame(hit_info: &HitInfo) -> Frame {
+    Frame::from_normal(hit_info.outward_shading_normal())
+}
+
+
+/// Perturbation of the shading normal of a material, applied before the material is evaluated
+pub enum NormalPerturbation {
+    /// Tangent space normal map, encoded in [0; 1] with `z` along the surface normal
+    NormalMap(Arc<dyn Texture>),
+    /// Height map read from the first channel of the texture, scaled by a strength
+    Bump(Arc<dyn Texture>, f64)
+}
+
+
+impl NormalPerturbation {
+
+    /// UV offset used to take the finite differences of bump maps
+    const BUMP_DELTA: f64 = 5e-4;
+
+
+    pub fn apply(&self, hit_info: &mut HitInfo) {
+        let normal = hit_info.outward_normal();
+
+        let perturbed = match self {
+            NormalPerturbation::NormalMap(texture) => {
+                let value = 2.0 * texture.value(hit_info) - Vec3::new(1.0, 1.0, 1.0);
+
+                // Tangent frame following the UV directions
+                let tangent = hit_info.dpdu - hit_info.dpdu.dot(normal) * normal;
+                if tangent.length_sq() == 0.0 {
+                    return;
+                }
+                let tangent = tangent.normalized();
+                let mut bitangent = normal.cross(tangent);
+                if bitangent.dot(hit_info.dpdv) < 0.0 {
+                    bitangent = -bitangent;
+                }
+
+                value.x * tangent + value.y * bitangent + value.z * normal
+            },
+            NormalPerturbation::Bump(texture, strength) => {
+                let height = |du: f64, dv: f64| {
+                    let mut shifted = hit_info.clone();
+                    shifted.uv.x += du;
+                    shifted.uv.y += dv;
+                    shifted.position = shifted.position + du * hit_info.dpdu + dv * hit_info.dpdv;
+                    strength * texture.value(&shifted).x
+                };
+
+                let displacement = height(0.0, 0.0);
+                let du = (height(Self::BUMP_DELTA, 0.0) - displacement) / Self::BUMP_DELTA;
+                let dv = (height(0.0, Self::BUMP_DELTA) - displacement) / Self::BUMP_DELTA;
+
+                let dpdu = hit_info.dpdu + du * normal;
+                let dpdv = hit_info.dpdv + dv * normal;
+                let perturbed = dpdu.cross(dpdv);
+                if perturbed.dot(normal) < 0.0 { -perturbed } else { perturbed }
+            }
+        };
+
+        if perturbed.length_sq() > 0.0 {
+            hit_info.set_outward_shading_normal(perturbed.normalized());
+        }
+    }
+}
+
+
+/// Flips shading space directions so that `wo` is on the outer side, for materials that look the same on both sides
+fn two_sided(wo: Vec3, wi: Vec3) -> (Vec3, Vec3, bool) {
+    if wo.z < 0.0 {
+        (Vec3::new(wo.x, wo.y, -wo.z), Vec3::new(wi.x, wi.y, -wi.z), true)
+    } else {
+        (wo, wi, false)
+    }
+}
+
+
+fn flip_z(w: Vec3, flip: bool) -> Vec3 {
+    if flip { Vec3::new(w.x, w.y, -w.z) } else { w }
+}
+
+
+/// Brings a shading space sample back to world space
+fn sample_to_world(frame: &Frame, sample: BsdfSample, flip: bool) -> BsdfSample {
+    BsdfSample { wi: frame.from_local(flip_z(sample.wi, flip)), ..sample }
 }
 
 
 
 pub struct Lambertian {
-    albedo: Vec3
+    albedo: Arc<dyn Texture>
 }
 
 
 impl Lambertian {
 
-    pub fn new(albedo: Vec3) -> Self {
+    pub fn new(albedo: Arc<dyn Texture>) -> Self {
         Self {
             albedo: albedo
         }
@@ -46,26 +274,55 @@ impl Lambertian {
 
 
 impl Material for Lambertian {
-    fn scatter(&self, _in_ray: &Ray, hit_info: &HitInfo) -> (Vec3, Ray) {
-        let scattered = random_unit_vec() + random_on_hemisphere(hit_info.normal);
-        if is_approx_zero(scattered) {
-            (self.albedo, Ray::new(hit_info.position, hit_info.normal))
-        } else {
-            (self.albedo, Ray::new(hit_info.position, scattered))
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let frame = shading_frame(hit_info);
+        let flip = frame.to_local(wo).z < 0.0;
+
+        let wi = sample_cosine_hemisphere();
+        if wi.z == 0.0 {
+            return None;
+        }
+
+        let f = self.albedo.value(hit_info) / PI;
+        let sample = BsdfSample::new(wi, f, wi.z / PI, BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION);
+        Some(sample_to_world(&frame, sample, flip))
+    }
+
+
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        let frame = shading_frame(hit_info);
+        if !same_hemisphere(frame.to_local(wo), frame.to_local(wi)) {
+            return Vec3::ZERO;
+        }
+        self.albedo.value(hit_info) / PI
+    }
+
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64 {
+        let frame = shading_frame(hit_info);
+        let (wi, wo) = (frame.to_local(wi), frame.to_local(wo));
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
         }
+        abs_cos_theta(wi) / PI
+    }
+
+
+    fn flags(&self, _hit_info: &HitInfo) -> BsdfFlags {
+        BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION
     }
 }
 
 
 pub struct Metal {
-    albedo: Vec3,
+    albedo: Arc<dyn Texture>,
     fuzz: f64
 }
 
 
 impl Metal {
 
-    pub fn new(albedo: Vec3, fuzz: f64) -> Self {
+    pub fn new(albedo: Arc<dyn Texture>, fuzz: f64) -> Self {
         Self {
             albedo: albedo,
             fuzz: fuzz
@@ -74,14 +331,1101 @@ impl Metal {
 }
 
 
+/// The fuzzed mirror direction has no tractable density, so the whole lobe is treated as specular.
 impl Material for Metal {
-    fn scatter(&self, in_ray: &Ray, hit_info: &HitInfo) -> (Vec3, Ray) {
-        (
-            self.albedo,
-            Ray::new(
-                hit_info.position,
-                reflect(in_ray.direction, hit_info.normal) + self.fuzz * random_unit_vec()
-            )
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let frame = shading_frame(hit_info);
+        let (wo, _, flip) = two_sided(frame.to_local(wo), Vec3::ZERO);
+
+        let wi = (Vec3::new(-wo.x, -wo.y, wo.z) + self.fuzz * random_unit_vec()).normalized();
+        if wi.z <= 0.0 {
+            return None; // The fuzz scattered the ray below the surface
+        }
+
+        let f = self.albedo.value(hit_info) / wi.z;
+        let sample = BsdfSample::new(wi, f, 1.0, BsdfFlags::SPECULAR | BsdfFlags::REFLECTION);
+        Some(sample_to_world(&frame, sample, flip))
+    }
+
+
+    fn eval(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> Vec3 {
+        Vec3::ZERO
+    }
+
+
+    fn pdf(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> f64 {
+        0.0
+    }
+
+
+    fn flags(&self, _hit_info: &HitInfo) -> BsdfFlags {
+        BsdfFlags::SPECULAR | BsdfFlags::REFLECTION
+    }
+}
+
+
+/// Complex index of refraction of a conductor, given per RGB channel.
+#[derive(Debug, Copy, Clone)]
+pub struct ComplexIor {
+    pub eta: Vec3,
+    pub k: Vec3
+}
+
+
+impl ComplexIor {
+
+    pub fn new(eta: Vec3, k: Vec3) -> Self {
+        Self {
+            eta: eta,
+            k: k
+        }
+    }
+
+
+    pub fn gold() -> Self {
+        Self::new(Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603))
+    }
+
+
+    pub fn copper() -> Self {
+        Self::new(Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142))
+    }
+
+
+    pub fn aluminium() -> Self {
+        Self::new(Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837))
+    }
+
+
+    pub fn silver() -> Self {
+        Self::new(Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147))
+    }
+}
+
+
+/// Microfacet conductor using the Trowbridge-Reitz (GGX) distribution.
+pub struct Conductor {
+    ior: ComplexIor,
+    roughness: Arc<dyn Texture>
+}
+
+
+impl Conductor {
+
+    /// `roughness` is read from the first channel of the texture
+    pub fn new(ior: ComplexIor, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            ior: ior,
+            roughness: roughness
+        }
+    }
+
+
+    fn bsdf(&self, hit_info: &HitInfo) -> ConductorBsdf {
+        ConductorBsdf::new(self.ior, self.roughness.value(hit_info).x)
+    }
+}
+
+
+/// Shading space BRDF of a `Conductor` at a given roughness
+pub struct ConductorBsdf {
+    ior: ComplexIor,
+    distribution: TrowbridgeReitz
+}
+
+
+impl ConductorBsdf {
+
+    pub fn new(ior: ComplexIor, roughness: f64) -> Self {
+        Self {
+            ior: ior,
+            distribution: TrowbridgeReitz::from_roughness(roughness)
+        }
+    }
+
+
+    fn fresnel(&self, cos_theta_i: f64) -> Vec3 {
+        Vec3::new(
+            fresnel_conductor(cos_theta_i, self.ior.eta.x, self.ior.k.x),
+            fresnel_conductor(cos_theta_i, self.ior.eta.y, self.ior.k.y),
+            fresnel_conductor(cos_theta_i, self.ior.eta.z, self.ior.k.z)
         )
     }
-}
\ No newline at end of file
+
+
+    /// Evaluates the BRDF in shading space
+    pub fn f(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if !same_hemisphere(wo, wi) || self.distribution.is_smooth() {
+            return Vec3::ZERO;
+        }
+
+        let cos_theta_o = abs_cos_theta(wo);
+        let cos_theta_i = abs_cos_theta(wi);
+        if cos_theta_i == 0.0 || cos_theta_o == 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let wm = wi + wo;
+        if wm.length_sq() == 0.0 {
+            return Vec3::ZERO;
+        }
+        let wm = wm.normalized();
+
+        let fresnel = self.fresnel(wo.dot(wm).abs());
+        self.distribution.d(wm) * self.distribution.g(wo, wi) / (4.0 * cos_theta_i * cos_theta_o) * fresnel
+    }
+
+
+    /// Density of `sample_local` for the pair of shading space directions
+    pub fn pdf_local(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if !same_hemisphere(wo, wi) || self.distribution.is_smooth() {
+            return 0.0;
+        }
+
+        let wm = wo + wi;
+        if wm.length_sq() == 0.0 {
+            return 0.0;
+        }
+        let mut wm = wm.normalized();
+        if wm.z < 0.0 {
+            wm = -wm;
+        }
+
+        self.distribution.pdf(wo, wm) / (4.0 * wo.dot(wm).abs())
+    }
+
+
+    /// Samples an incoming direction in shading space
+    pub fn sample_local(&self, wo: Vec3) -> Option<BsdfSample> {
+        if self.distribution.is_smooth() { // Perfect mirror
+            let wi = Vec3::new(-wo.x, -wo.y, wo.z);
+            let f = 1.0 / abs_cos_theta(wi) * self.fresnel(abs_cos_theta(wi));
+            return Some(BsdfSample::new(wi, f, 1.0, BsdfFlags::SPECULAR | BsdfFlags::REFLECTION));
+        }
+
+        if wo.z == 0.0 {
+            return None;
+        }
+
+        let wm = self.distribution.sample_wm(wo);
+        let wi = reflect_around(wo, wm);
+        if !same_hemisphere(wo, wi) {
+            return None;
+        }
+
+        let pdf = self.distribution.pdf(wo, wm) / (4.0 * wo.dot(wm).abs());
+        Some(BsdfSample::new(wi, self.f(wo, wi), pdf, BsdfFlags::GLOSSY | BsdfFlags::REFLECTION))
+    }
+}
+
+
+impl Material for Conductor {
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let frame = shading_frame(hit_info);
+        let (wo, _, flip) = two_sided(frame.to_local(wo), Vec3::ZERO);
+        Some(sample_to_world(&frame, self.bsdf(hit_info).sample_local(wo)?, flip))
+    }
+
+
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        let frame = shading_frame(hit_info);
+        let (wo, wi, _) = two_sided(frame.to_local(wo), frame.to_local(wi));
+        self.bsdf(hit_info).f(wo, wi)
+    }
+
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64 {
+        let frame = shading_frame(hit_info);
+        let (wo, wi, _) = two_sided(frame.to_local(wo), frame.to_local(wi));
+        self.bsdf(hit_info).pdf_local(wo, wi)
+    }
+
+
+    fn flags(&self, hit_info: &HitInfo) -> BsdfFlags {
+        if self.bsdf(hit_info).distribution.is_smooth() {
+            BsdfFlags::SPECULAR | BsdfFlags::REFLECTION
+        } else {
+            BsdfFlags::GLOSSY | BsdfFlags::REFLECTION
+        }
+    }
+}
+
+
+/// Rough dielectric using the Trowbridge-Reitz (GGX) distribution, falls back to a smooth interface when the
+/// roughness is close to zero.
+pub struct RoughDielectric {
+    eta: f64,
+    /// Overrides `eta` with an index of refraction depending on the wavelength
+    dispersion: Option<Dispersion>,
+    roughness: Arc<dyn Texture>
+}
+
+
+impl RoughDielectric {
+
+    /// Wavelength at which dispersive materials are evaluated when rendering in RGB
+    const RGB_WAVELENGTH: f64 = 550.0;
+
+
+    /// `roughness` is read from the first channel of the texture
+    pub fn new(eta: f64, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            eta: eta,
+            dispersion: None,
+            roughness: roughness
+        }
+    }
+
+
+    pub fn dispersive(dispersion: Dispersion, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            eta: dispersion.ior(Self::RGB_WAVELENGTH),
+            dispersion: Some(dispersion),
+            roughness: roughness
+        }
+    }
+
+
+    fn bsdf(&self, hit_info: &HitInfo) -> DielectricBsdf {
+        let eta = match &self.dispersion {
+            Some(dispersion) => dispersion.ior(hit_info.wavelength.unwrap_or(Self::RGB_WAVELENGTH)),
+            None => self.eta
+        };
+        DielectricBsdf::new(eta, self.roughness.value(hit_info).x)
+    }
+}
+
+
+/// Shading space BSDF of a `RoughDielectric` at a given roughness.
+///
+/// The transmission term is not scaled by `1 / eta²` so that the BSDF stays symmetric.
+pub struct DielectricBsdf {
+    eta: f64,
+    distribution: TrowbridgeReitz
+}
+
+
+impl DielectricBsdf {
+
+    pub fn new(eta: f64, roughness: f64) -> Self {
+        Self {
+            eta: eta,
+            distribution: TrowbridgeReitz::from_roughness(roughness)
+        }
+    }
+
+
+    /// Returns the half vector of the pair of directions facing the outside, and the relative index of refraction
+    fn half_vector(&self, wo: Vec3, wi: Vec3) -> Option<(Vec3, f64)> {
+        let cos_theta_o = cos_theta(wo);
+        let cos_theta_i = cos_theta(wi);
+        let is_reflection = cos_theta_i * cos_theta_o > 0.0;
+
+        let etap = if is_reflection {
+            1.0
+        } else if cos_theta_o > 0.0 {
+            self.eta
+        } else {
+            1.0 / self.eta
+        };
+
+        let wm = etap * wi + wo;
+        if cos_theta_i == 0.0 || cos_theta_o == 0.0 || wm.length_sq() == 0.0 {
+            return None;
+        }
+        let mut wm = wm.normalized();
+        if wm.z < 0.0 {
+            wm = -wm;
+        }
+
+        // Discard back facing microfacets
+        if wm.dot(wi) * cos_theta_i < 0.0 || wm.dot(wo) * cos_theta_o < 0.0 {
+            return None;
+        }
+
+        Some((wm, etap))
+    }
+
+
+    /// Evaluates the BSDF in shading space
+    pub fn f(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if self.is_smooth() {
+            return Vec3::ZERO;
+        }
+
+        let Some((wm, etap)) = self.half_vector(wo, wi) else {
+            return Vec3::ZERO;
+        };
+
+        let fresnel = fresnel_dielectric(wo.dot(wm), self.eta);
+        let d = self.distribution.d(wm);
+        let g = self.distribution.g(wo, wi);
+
+        let value = if same_hemisphere(wo, wi) {
+            d * g * fresnel / (4.0 * cos_theta(wi) * cos_theta(wo)).abs()
+        } else {
+            let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2) * cos_theta(wi) * cos_theta(wo);
+            d * (1.0 - fresnel) * g * (wi.dot(wm) * wo.dot(wm) / denom).abs()
+        };
+
+        Vec3::new(value, value, value)
+    }
+
+
+    /// Density of `sample_local` for the pair of shading space directions
+    pub fn pdf_local(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if self.is_smooth() {
+            return 0.0;
+        }
+
+        let Some((wm, etap)) = self.half_vector(wo, wi) else {
+            return 0.0;
+        };
+
+        let r = fresnel_dielectric(wo.dot(wm), self.eta);
+        let t = 1.0 - r;
+
+        if same_hemisphere(wo, wi) {
+            self.distribution.pdf(wo, wm) / (4.0 * wo.dot(wm).abs()) * r / (r + t)
+        } else {
+            let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2);
+            let dwm_dwi = wi.dot(wm).abs() / denom;
+            self.distribution.pdf(wo, wm) * dwm_dwi * t / (r + t)
+        }
+    }
+
+
+    pub fn is_smooth(&self) -> bool {
+        self.eta == 1.0 || self.distribution.is_smooth()
+    }
+
+
+    /// Samples an incoming direction in shading space
+    pub fn sample_local(&self, wo: Vec3) -> Option<BsdfSample> {
+        if self.is_smooth() {
+            let r = fresnel_dielectric(cos_theta(wo), self.eta);
+            let t = 1.0 - r;
+
+            if random() < r / (r + t) {
+                let wi = Vec3::new(-wo.x, -wo.y, wo.z);
+                let f = r / abs_cos_theta(wi);
+                let flags = BsdfFlags::SPECULAR | BsdfFlags::REFLECTION;
+                return Some(BsdfSample::new(wi, Vec3::new(f, f, f), r / (r + t), flags));
+            }
+
+            let (wi, _etap) = refract(wo, Vec3::UNIT_Z, self.eta)?;
+            let f = t / abs_cos_theta(wi);
+            let flags = BsdfFlags::SPECULAR | BsdfFlags::TRANSMISSION;
+            return Some(BsdfSample::new(wi, Vec3::new(f, f, f), t / (r + t), flags));
+        }
+
+        if wo.z == 0.0 {
+            return None;
+        }
+
+        // Rough interface
+        let wm = self.distribution.sample_wm(wo);
+        let r = fresnel_dielectric(wo.dot(wm), self.eta);
+        let t = 1.0 - r;
+
+        if random() < r / (r + t) {
+            let wi = reflect_around(wo, wm);
+            if !same_hemisphere(wo, wi) {
+                return None;
+            }
+
+            let pdf = self.distribution.pdf(wo, wm) / (4.0 * wo.dot(wm).abs()) * r / (r + t);
+            let f = self.distribution.d(wm) * self.distribution.g(wo, wi) * r
+                / (4.0 * cos_theta(wi) * cos_theta(wo));
+            Some(BsdfSample::new(wi, Vec3::new(f, f, f), pdf, BsdfFlags::GLOSSY | BsdfFlags::REFLECTION))
+        } else {
+            let (wi, etap) = refract(wo, wm, self.eta)?;
+            if same_hemisphere(wo, wi) || wi.z == 0.0 {
+                return None;
+            }
+
+            let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2);
+            let dwm_dwi = wi.dot(wm).abs() / denom;
+            let pdf = self.distribution.pdf(wo, wm) * dwm_dwi * t / (r + t);
+
+            let f = t * self.distribution.d(wm) * self.distribution.g(wo, wi)
+                * (wi.dot(wm) * wo.dot(wm) / (cos_theta(wi) * cos_theta(wo) * denom)).abs();
+            Some(BsdfSample::new(wi, Vec3::new(f, f, f), pdf, BsdfFlags::GLOSSY | BsdfFlags::TRANSMISSION))
+        }
+    }
+
+
+    /// Evaluates the refracted part of the BSDF only
+    pub fn f_transmission(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if same_hemisphere(wo, wi) {
+            return Vec3::ZERO;
+        }
+        self.f(wo, wi)
+    }
+
+
+    /// Density of `sample_transmission` for the pair of shading space directions
+    pub fn pdf_transmission(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if self.is_smooth() || same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+
+        let Some((wm, etap)) = self.half_vector(wo, wi) else {
+            return 0.0;
+        };
+
+        let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2);
+        self.distribution.pdf(wo, wm) * wi.dot(wm).abs() / denom
+    }
+
+
+    /// Samples a refracted direction in shading space, leaving the Fresnel reflection to another lobe
+    pub fn sample_transmission(&self, wo: Vec3) -> Option<BsdfSample> {
+        if self.is_smooth() {
+            let t = 1.0 - fresnel_dielectric(cos_theta(wo), self.eta);
+            let (wi, _etap) = refract(wo, Vec3::UNIT_Z, self.eta)?;
+            let f = t / abs_cos_theta(wi);
+            return Some(BsdfSample::new(wi, Vec3::new(f, f, f), 1.0, BsdfFlags::SPECULAR | BsdfFlags::TRANSMISSION));
+        }
+
+        if wo.z == 0.0 {
+            return None;
+        }
+
+        let wm = self.distribution.sample_wm(wo);
+        let (wi, _etap) = refract(wo, wm, self.eta)?;
+        if same_hemisphere(wo, wi) || wi.z == 0.0 {
+            return None;
+        }
+        Some(BsdfSample::new(
+            wi,
+            self.f(wo, wi),
+            self.pdf_transmission(wo, wi),
+            BsdfFlags::GLOSSY | BsdfFlags::TRANSMISSION
+        ))
+    }
+}
+
+
+/// The shading frame follows the outward normal, so that `eta` always is inside / outside.
+impl Material for RoughDielectric {
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let frame = shading_frame(hit_info);
+        Some(sample_to_world(&frame, self.bsdf(hit_info).sample_local(frame.to_local(wo))?, false))
+    }
+
+
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        let frame = shading_frame(hit_info);
+        self.bsdf(hit_info).f(frame.to_local(wo), frame.to_local(wi))
+    }
+
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64 {
+        let frame = shading_frame(hit_info);
+        self.bsdf(hit_info).pdf_local(frame.to_local(wo), frame.to_local(wi))
+    }
+
+
+    fn flags(&self, hit_info: &HitInfo) -> BsdfFlags {
+        if self.bsdf(hit_info).is_smooth() {
+            BsdfFlags::SPECULAR | BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION
+        } else {
+            BsdfFlags::GLOSSY | BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION
+        }
+    }
+
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+}
+
+
+
+/// Artist facing parameters of the `Principled` material. Every factor is in [0; 1].
+#[derive(Clone, Copy)]
+pub struct PrincipledParameters {
+    pub base_color: Color,
+    /// Overrides `base_color` when set
+    pub base_color_texture: Option<Rid>,
+    pub metallic: f64,
+    pub roughness: f64,
+    /// Overrides `roughness` when set, read from the first channel of the texture
+    pub roughness_texture: Option<Rid>,
+    /// Specular reflectance of dielectrics, 0.5 maps to a reflectance of 4% at normal incidence
+    pub specular: f64,
+    pub transmission: f64,
+    pub ior: f64,
+    /// Overrides `ior` for the transmission lobe with an index of refraction depending on the wavelength
+    pub dispersion: Option<Dispersion>,
+    pub clearcoat: f64,
+    pub clearcoat_roughness: f64,
+    pub sheen: f64,
+    /// Blends the sheen from white to the hue of the base color
+    pub sheen_tint: f64
+}
+
+
+impl Default for PrincipledParameters {
+    fn default() -> Self {
+        Self {
+            base_color: Color::raw_rgb(0.8, 0.8, 0.8),
+            base_color_texture: None,
+            metallic: 0.0,
+            roughness: 0.5,
+            roughness_texture: None,
+            specular: 0.5,
+            transmission: 0.0,
+            ior: 1.5,
+            dispersion: None,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            sheen: 0.0,
+            sheen_tint: 0.5
+        }
+    }
+}
+
+
+/// Disney style uber material made of a diffuse, a sheen, a specular, a transmission and a clearcoat lobe.
+///
+/// The base lobes are layered under the clearcoat and attenuated by its Fresnel reflectance, and the diffuse lobe
+/// only receives the energy that was not reflected by the specular lobe.
+pub struct Principled {
+    base_color: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    metallic: f64,
+    specular: f64,
+    transmission: f64,
+    ior: f64,
+    dispersion: Option<Dispersion>,
+    clearcoat: f64,
+    clearcoat_roughness: f64,
+    sheen: f64,
+    sheen_tint: f64
+}
+
+
+impl Principled {
+
+    /// The textures replace the base color and roughness of `parameters`
+    pub fn new(parameters: &PrincipledParameters, base_color: Arc<dyn Texture>, roughness: Arc<dyn Texture>) -> Self {
+        Self {
+            base_color: base_color,
+            roughness: roughness,
+            metallic: parameters.metallic.clamp(0.0, 1.0),
+            specular: parameters.specular.clamp(0.0, 1.0),
+            transmission: parameters.transmission.clamp(0.0, 1.0),
+            ior: parameters.ior,
+            dispersion: parameters.dispersion,
+            clearcoat: parameters.clearcoat.clamp(0.0, 1.0),
+            clearcoat_roughness: parameters.clearcoat_roughness,
+            sheen: parameters.sheen.max(0.0),
+            sheen_tint: parameters.sheen_tint
+        }
+    }
+
+
+    fn bsdf(&self, hit_info: &HitInfo) -> PrincipledBsdf {
+        let ior = match &self.dispersion {
+            Some(dispersion) => dispersion.ior(hit_info.wavelength.unwrap_or(RoughDielectric::RGB_WAVELENGTH)),
+            None => self.ior
+        };
+        PrincipledBsdf::new(self, self.base_color.value(hit_info), self.roughness.value(hit_info).x, ior)
+    }
+}
+
+
+/// Shading space BSDF of a `Principled` material at a given hit point
+pub struct PrincipledBsdf {
+    base_color: Vec3,
+    sheen_color: Vec3,
+    specular_f0: Vec3,
+
+    diffuse_weight: f64,
+    transmission_weight: f64,
+    clearcoat: f64,
+    sheen: f64,
+
+    specular_distribution: TrowbridgeReitz,
+    clearcoat_distribution: TrowbridgeReitz,
+    dielectric: DielectricBsdf
+}
+
+
+impl PrincipledBsdf {
+
+    /// Reflectance of the clearcoat at normal incidence (index of refraction of 1.5)
+    const CLEARCOAT_F0: f64 = 0.04;
+
+
+    /// `ior` is the index of refraction of the transmission lobe at the wavelength of the path
+    pub fn new(material: &Principled, base_color: Vec3, roughness: f64, ior: f64) -> Self {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let base_luminance = luminance(base_color);
+        let tint = if base_luminance > 0.0 { base_color / base_luminance } else { white };
+        let sheen_color = (1.0 - material.sheen_tint) * white + material.sheen_tint * tint;
+
+        let dielectric_f0 = 0.08 * material.specular;
+        let specular_f0 = (1.0 - material.metallic) * Vec3::new(dielectric_f0, dielectric_f0, dielectric_f0)
+            + material.metallic * base_color;
+
+        Self {
+            base_color: base_color,
+            sheen_color: sheen_color,
+            specular_f0: specular_f0,
+            diffuse_weight: (1.0 - material.metallic) * (1.0 - material.transmission),
+            transmission_weight: (1.0 - material.metallic) * material.transmission,
+            clearcoat: material.clearcoat,
+            sheen: material.sheen,
+            specular_distribution: TrowbridgeReitz::from_roughness(roughness),
+            clearcoat_distribution: TrowbridgeReitz::from_roughness(material.clearcoat_roughness),
+            dielectric: DielectricBsdf::new(ior, roughness)
+        }
+    }
+
+
+    fn specular_fresnel(&self, cos_theta_i: f64) -> Vec3 {
+        Vec3::new(
+            fresnel_schlick(self.specular_f0.x, cos_theta_i),
+            fresnel_schlick(self.specular_f0.y, cos_theta_i),
+            fresnel_schlick(self.specular_f0.z, cos_theta_i)
+        )
+    }
+
+
+    /// Fraction of the energy that goes through the clearcoat
+    fn clearcoat_transmittance(&self, wo: Vec3) -> f64 {
+        1.0 - self.clearcoat * fresnel_schlick(Self::CLEARCOAT_F0, abs_cos_theta(wo))
+    }
+
+
+    /// Probabilities of sampling the diffuse, specular, clearcoat and transmission lobes, in that order
+    fn lobe_probabilities(&self, wo: Vec3) -> [f64; 4] {
+        if wo.z <= 0.0 { // Only the transmission lobe is visible from the inside
+            return [0.0, 0.0, 0.0, if self.transmission_weight > 0.0 { 1.0 } else { 0.0 }];
+        }
+
+        let cos_theta_o = abs_cos_theta(wo);
+        let transmittance = self.clearcoat_transmittance(wo);
+
+        let probabilities = [
+            transmittance * self.diffuse_weight * (luminance(self.base_color) + self.sheen),
+            transmittance * luminance(self.specular_fresnel(cos_theta_o)),
+            self.clearcoat * fresnel_schlick(Self::CLEARCOAT_F0, cos_theta_o),
+            transmittance * self.transmission_weight
+        ];
+
+        let total: f64 = probabilities.iter().sum();
+        if total <= 0.0 {
+            return [0.0; 4];
+        }
+        probabilities.map(|p| p / total)
+    }
+
+
+    /// Value of a GGX reflection lobe without its Fresnel term
+    fn microfacet_reflection(distribution: &TrowbridgeReitz, wo: Vec3, wi: Vec3, wm: Vec3) -> f64 {
+        if distribution.is_smooth() {
+            return 0.0;
+        }
+        distribution.d(wm) * distribution.g(wo, wi) / (4.0 * abs_cos_theta(wi) * abs_cos_theta(wo))
+    }
+
+
+    /// Density of sampling `wi` from a GGX reflection lobe
+    fn microfacet_reflection_pdf(distribution: &TrowbridgeReitz, wo: Vec3, wm: Vec3) -> f64 {
+        if distribution.is_smooth() {
+            return 0.0;
+        }
+        distribution.pdf(wo, wm) / (4.0 * wo.dot(wm).abs())
+    }
+
+
+    /// Transmission lobe, tinted by the base color when light goes through the surface
+    fn transmission_f(&self, wo: Vec3, wi: Vec3, f: Vec3) -> Vec3 {
+        if same_hemisphere(wo, wi) {
+            self.transmission_weight * f
+        } else {
+            self.transmission_weight * mul_elem(self.base_color, f)
+        }
+    }
+
+
+    /// Value of the dielectric under the transmission lobe. Seen from the outside, its reflection is already accounted
+    /// for by the specular lobe and only the refracted part is kept.
+    fn dielectric_f(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if wo.z > 0.0 {
+            self.dielectric.f_transmission(wo, wi)
+        } else {
+            self.dielectric.f(wo, wi)
+        }
+    }
+
+
+    fn dielectric_pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if wo.z > 0.0 {
+            self.dielectric.pdf_transmission(wo, wi)
+        } else {
+            self.dielectric.pdf_local(wo, wi)
+        }
+    }
+
+
+    fn sample_dielectric(&self, wo: Vec3) -> Option<BsdfSample> {
+        if wo.z > 0.0 {
+            self.dielectric.sample_transmission(wo)
+        } else {
+            self.dielectric.sample_local(wo)
+        }
+    }
+
+
+    /// Evaluates the non specular lobes of the BSDF in shading space
+    pub fn f(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        let mut value = Vec3::ZERO;
+        let transmittance = if wo.z > 0.0 { self.clearcoat_transmittance(wo) } else { 1.0 };
+
+        if wo.z > 0.0 && wi.z > 0.0 {
+            let wm = (wo + wi).normalized();
+            let cos_theta_d = wi.dot(wm);
+
+            // Diffuse and sheen
+            let specular_albedo = self.specular_fresnel(abs_cos_theta(wo));
+            let diffuse = mul_elem(Vec3::new(1.0, 1.0, 1.0) - specular_albedo, self.base_color) / PI;
+            let sheen = self.sheen * (1.0 - cos_theta_d).clamp(0.0, 1.0).powi(5) * self.sheen_color;
+
+            // Specular
+            let specular = Self::microfacet_reflection(&self.specular_distribution, wo, wi, wm)
+                * self.specular_fresnel(wo.dot(wm));
+
+            value += transmittance * (self.diffuse_weight * (diffuse + sheen) + specular);
+
+            // Clearcoat
+            let clearcoat = Self::microfacet_reflection(&self.clearcoat_distribution, wo, wi, wm)
+                * fresnel_schlick(Self::CLEARCOAT_F0, wo.dot(wm));
+            value += (self.clearcoat * clearcoat) * Vec3::new(1.0, 1.0, 1.0);
+        }
+
+        if self.transmission_weight > 0.0 {
+            value += transmittance * self.transmission_f(wo, wi, self.dielectric_f(wo, wi));
+        }
+
+        value
+    }
+
+
+    /// Density of `sample_local` for the non specular lobes
+    pub fn pdf_local(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let [p_diffuse, p_specular, p_clearcoat, p_transmission] = self.lobe_probabilities(wo);
+        let mut pdf = 0.0;
+
+        if wo.z > 0.0 && wi.z > 0.0 {
+            let wm = (wo + wi).normalized();
+            pdf += p_diffuse * abs_cos_theta(wi) / PI;
+            pdf += p_specular * Self::microfacet_reflection_pdf(&self.specular_distribution, wo, wm);
+            pdf += p_clearcoat * Self::microfacet_reflection_pdf(&self.clearcoat_distribution, wo, wm);
+        }
+
+        if p_transmission > 0.0 {
+            pdf += p_transmission * self.dielectric_pdf(wo, wi);
+        }
+
+        pdf
+    }
+
+
+    /// Samples a GGX reflection lobe, smooth distributions produce a mirror direction
+    fn sample_reflection(distribution: &TrowbridgeReitz, wo: Vec3) -> Option<(Vec3, bool)> {
+        if distribution.is_smooth() {
+            return Some((Vec3::new(-wo.x, -wo.y, wo.z), true));
+        }
+
+        let wm = distribution.sample_wm(wo);
+        let wi = reflect_around(wo, wm);
+        if !same_hemisphere(wo, wi) {
+            return None;
+        }
+        Some((wi, false))
+    }
+
+
+    /// Sample of the non specular lobes, whose density accounts for every lobe
+    fn non_specular_sample(&self, wo: Vec3, wi: Vec3, flags: BsdfFlags) -> BsdfSample {
+        BsdfSample::new(wi, self.f(wo, wi), self.pdf_local(wo, wi), flags)
+    }
+
+
+    /// Samples an incoming direction in shading space
+    pub fn sample_local(&self, wo: Vec3) -> Option<BsdfSample> {
+        if wo.z == 0.0 {
+            return None;
+        }
+
+        let [p_diffuse, p_specular, p_clearcoat, p_transmission] = self.lobe_probabilities(wo);
+        let u = random();
+        let specular_reflection = BsdfFlags::SPECULAR | BsdfFlags::REFLECTION;
+        let glossy_reflection = BsdfFlags::GLOSSY | BsdfFlags::REFLECTION;
+
+        if u < p_diffuse {
+            let wi = sample_cosine_hemisphere();
+            return Some(self.non_specular_sample(wo, wi, BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION));
+        }
+
+        if u < p_diffuse + p_specular {
+            let (wi, specular) = Self::sample_reflection(&self.specular_distribution, wo)?;
+            if specular {
+                let f = self.clearcoat_transmittance(wo) / abs_cos_theta(wi) * self.specular_fresnel(wi.z);
+                return Some(BsdfSample::new(wi, f, p_specular, specular_reflection));
+            }
+            return Some(self.non_specular_sample(wo, wi, glossy_reflection));
+        }
+
+        if u < p_diffuse + p_specular + p_clearcoat {
+            let (wi, specular) = Self::sample_reflection(&self.clearcoat_distribution, wo)?;
+            if specular {
+                let f = self.clearcoat * fresnel_schlick(Self::CLEARCOAT_F0, wi.z) / abs_cos_theta(wi);
+                return Some(BsdfSample::new(wi, Vec3::new(f, f, f), p_clearcoat, specular_reflection));
+            }
+            return Some(self.non_specular_sample(wo, wi, glossy_reflection));
+        }
+
+        if p_transmission > 0.0 {
+            let sample = self.sample_dielectric(wo)?;
+            if sample.is_specular() {
+                let transmittance = if wo.z > 0.0 { self.clearcoat_transmittance(wo) } else { 1.0 };
+                return Some(BsdfSample::new(
+                    sample.wi,
+                    transmittance * self.transmission_f(wo, sample.wi, sample.f),
+                    p_transmission * sample.pdf,
+                    sample.flags
+                ));
+            }
+            return Some(self.non_specular_sample(wo, sample.wi, sample.flags));
+        }
+
+        None
+    }
+}
+
+
+/// The shading frame follows the outward normal, the transmission lobe being the only one visible from the inside.
+impl Material for Principled {
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let frame = shading_frame(hit_info);
+        Some(sample_to_world(&frame, self.bsdf(hit_info).sample_local(frame.to_local(wo))?, false))
+    }
+
+
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        let frame = shading_frame(hit_info);
+        self.bsdf(hit_info).f(frame.to_local(wo), frame.to_local(wi))
+    }
+
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64 {
+        let frame = shading_frame(hit_info);
+        self.bsdf(hit_info).pdf_local(frame.to_local(wo), frame.to_local(wi))
+    }
+
+
+    fn flags(&self, hit_info: &HitInfo) -> BsdfFlags {
+        let bsdf = self.bsdf(hit_info);
+
+        let lobe = |smooth: bool| if smooth { BsdfFlags::SPECULAR } else { BsdfFlags::GLOSSY };
+
+        let mut flags = BsdfFlags::REFLECTION | lobe(bsdf.specular_distribution.is_smooth());
+        if bsdf.diffuse_weight > 0.0 {
+            flags = flags | BsdfFlags::DIFFUSE;
+        }
+        if bsdf.clearcoat > 0.0 {
+            flags = flags | lobe(bsdf.clearcoat_distribution.is_smooth());
+        }
+        if bsdf.transmission_weight > 0.0 {
+            flags = flags | BsdfFlags::TRANSMISSION | lobe(bsdf.dielectric.is_smooth());
+        }
+        flags
+    }
+
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some() && self.transmission > 0.0 && self.metallic < 1.0
+    }
+}
+
+
+/// Light emitting surface, only emitting from its front face. It does not scatter any light.
+pub struct Emissive {
+    emission: Arc<dyn Texture>,
+    strength: f64
+}
+
+
+impl Emissive {
+
+    pub fn new(emission: Arc<dyn Texture>, strength: f64) -> Self {
+        Self {
+            emission: emission,
+            strength: strength
+        }
+    }
+}
+
+
+impl Material for Emissive {
+    fn sample(&self, _wo: Vec3, _hit_info: &HitInfo) -> Option<BsdfSample> {
+        None
+    }
+
+
+    fn eval(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> Vec3 {
+        Vec3::ZERO
+    }
+
+
+    fn pdf(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> f64 {
+        0.0
+    }
+
+
+    fn flags(&self, _hit_info: &HitInfo) -> BsdfFlags {
+        BsdfFlags::NONE
+    }
+
+
+    fn emitted(&self, _wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        if !hit_info.front_face {
+            return Vec3::ZERO;
+        }
+        self.strength * self.emission.value(hit_info)
+    }
+
+
+    /// Averages the emission texture over a grid of UV coordinates
+    fn average_emission(&self) -> Vec3 {
+        const STEPS: usize = 4;
+
+        let mut sum = Vec3::ZERO;
+        for i in 0..STEPS {
+            for j in 0..STEPS {
+                let uv = vec2!((i as f64 + 0.5) / STEPS as f64, (j as f64 + 0.5) / STEPS as f64);
+                let hit_info = HitInfo::front_face(0.0, Vec3::ZERO, Vec3::UNIT_Z, uv, Vec3::UNIT_X, Vec3::UNIT_Y);
+                sum += self.emission.value(&hit_info);
+            }
+        }
+        (self.strength / (STEPS * STEPS) as f64) * sum
+    }
+}
+
+
+/// Invisible surface letting light through unchanged, used to bound participating media
+pub struct Interface;
+
+
+impl Material for Interface {
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        let cos = wo.dot(hit_info.shading_normal).abs();
+        if cos == 0.0 {
+            return None;
+        }
+        // Delta transmission, `f` cancelling the cosine of the sample weight
+        Some(BsdfSample::new(
+            -wo, (1.0 / cos) * Vec3::new(1.0, 1.0, 1.0), 1.0, BsdfFlags::TRANSMISSION | BsdfFlags::SPECULAR
+        ))
+    }
+
+
+    fn eval(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> Vec3 {
+        Vec3::ZERO
+    }
+
+
+    fn pdf(&self, _wi: Vec3, _wo: Vec3, _hit_info: &HitInfo) -> f64 {
+        0.0
+    }
+
+
+    fn flags(&self, _hit_info: &HitInfo) -> BsdfFlags {
+        BsdfFlags::TRANSMISSION | BsdfFlags::SPECULAR
+    }
+
+
+    fn is_interface(&self) -> bool {
+        true
+    }
+}
+
+
+/// Dielectric boundary of a scattering medium, giving translucent materials such as skin, wax or marble.
+///
+/// Light refracted into the object random walks through its inside until it leaves through the surface again.
+pub struct Subsurface {
+    boundary: RoughDielectric,
+    medium: HomogeneousMedium
+}
+
+
+impl Subsurface {
+
+    /// Shortest mean free path, shorter ones making the extinction coefficient infinite
+    const MIN_MEAN_FREE_PATH: f64 = 1e-6;
+
+
+    /// `albedo` is the multiple scattering albedo, the colour of the material when seen from afar, and
+    /// `mean_free_path` the average distance travelled by light between two interactions, for each channel. It is
+    /// clamped to `MIN_MEAN_FREE_PATH`.
+    pub fn new(albedo: Vec3, mean_free_path: Vec3, eta: f64, roughness: Arc<dyn Texture>, g: f64) -> Self {
+        let sigma_t = Vec3::new(
+            1.0 / mean_free_path.x.max(Self::MIN_MEAN_FREE_PATH),
+            1.0 / mean_free_path.y.max(Self::MIN_MEAN_FREE_PATH),
+            1.0 / mean_free_path.z.max(Self::MIN_MEAN_FREE_PATH)
+        );
+        let single_albedo = Vec3::new(
+            Self::single_scattering_albedo(albedo.x),
+            Self::single_scattering_albedo(albedo.y),
+            Self::single_scattering_albedo(albedo.z)
+        );
+        let sigma_s = mul_elem(single_albedo, sigma_t);
+
+        Self {
+            boundary: RoughDielectric::new(eta, roughness),
+            medium: HomogeneousMedium::new(sigma_t - sigma_s, sigma_s, g)
+        }
+    }
+
+
+    /// Albedo of a single scattering event giving `albedo` after many events (Kulla and Conty, 2017)
+    fn single_scattering_albedo(albedo: f64) -> f64 {
+        let albedo = albedo.clamp(0.0, 0.999);
+        let s = 4.09712 + 4.20863 * albedo - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+        1.0 - s * s
+    }
+}
+
+
+impl Material for Subsurface {
+    fn sample(&self, wo: Vec3, hit_info: &HitInfo) -> Option<BsdfSample> {
+        self.boundary.sample(wo, hit_info)
+    }
+
+
+    fn eval(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> Vec3 {
+        self.boundary.eval(wi, wo, hit_info)
+    }
+
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit_info: &HitInfo) -> f64 {
+        self.boundary.pdf(wi, wo, hit_info)
+    }
+
+
+    fn flags(&self, hit_info: &HitInfo) -> BsdfFlags {
+        self.boundary.flags(hit_info)
+    }
+
+
+    fn interior_medium(&self) -> Option<&dyn Medium> {
+        Some(&self.medium)
+    }
+}
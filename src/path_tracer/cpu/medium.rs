@@ -0,0 +1,174 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::f64::consts::PI;
+
+use simple_term_renderer::math::Vec3;
+
+use crate::Ray;
+use crate::path_tracer::sampler::random;
+
+use super::{mul_elem, Frame};
+
+
+/// Henyey-Greenstein phase function. `g` is the mean cosine of the scattering angle, positive values scattering
+/// forward and negative values backward.
+#[derive(Debug, Copy, Clone)]
+pub struct HenyeyGreenstein {
+    g: f64
+}
+
+
+impl HenyeyGreenstein {
+
+    pub fn new(g: f64) -> Self {
+        Self {
+            g: g.clamp(-0.99, 0.99)
+        }
+    }
+
+
+    /// Density of scattering by an angle of cosine `cos_theta`, relative to the direction of propagation
+    pub fn eval(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+
+    /// Samples a new direction of propagation, proportionally to the phase function.
+    /// The phase function being importance sampled exactly, the sample weight is always one.
+    pub fn sample(&self, direction: Vec3) -> Vec3 {
+        let g = self.g;
+        let (u1, u2): (f64, f64) = (random(), random());
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            ((1.0 + g * g - sq * sq) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+
+        Frame::from_normal(direction.normalized()).from_local(local)
+    }
+}
+
+
+/// Outcome of free flight sampling along a ray segment. `emitted` is the radiance emitted by the medium before the
+/// event, already weighted.
+pub enum MediumEvent {
+    /// The ray scattered at `distance`, `weight` accounting for the transmittance, scattering coefficient and pdf
+    Scatter { distance: f64, weight: Vec3, emitted: Vec3 },
+    /// The ray went through the whole segment
+    Pass { weight: Vec3, emitted: Vec3 },
+    /// The path was absorbed by the medium
+    Absorb { emitted: Vec3 }
+}
+
+
+/// Participating medium filling a region of space
+pub trait Medium {
+    /// Samples where a ray travelling through the medium interacts with it, before the ray parameter `t_max`
+    fn sample(&self, ray: &Ray, t_max: f64) -> MediumEvent;
+
+    /// Fraction of light going through the medium along the ray, up to the ray parameter `t_max`
+    fn transmittance(&self, ray: &Ray, t_max: f64) -> Vec3;
+
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+
+/// Medium of constant density. Coefficients are per unit of distance, given for each colour channel.
+pub struct HomogeneousMedium {
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    phase: HenyeyGreenstein
+}
+
+
+impl HomogeneousMedium {
+
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f64) -> Self {
+        Self {
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            phase: HenyeyGreenstein::new(g)
+        }
+    }
+
+
+    fn transmittance_over(&self, distance: f64) -> Vec3 {
+        // Checked so that empty channels stay transparent over infinite distances
+        let channel = |sigma: f64| if sigma > 0.0 { (-sigma * distance).exp() } else { 1.0 };
+        let sigma_t = self.sigma_a + self.sigma_s;
+        Vec3::new(channel(sigma_t.x), channel(sigma_t.y), channel(sigma_t.z))
+    }
+}
+
+
+impl Medium for HomogeneousMedium {
+    fn sample(&self, ray: &Ray, t_max: f64) -> MediumEvent {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let speed = ray.direction.length();
+
+        // The distance is sampled from a random channel, the pdf being the average over the channels
+        let channel_sigma = match (3.0 * random()) as usize {
+            0 => sigma_t.x,
+            1 => sigma_t.y,
+            _ => sigma_t.z
+        };
+        let distance = if channel_sigma > 0.0 {
+            -(1.0 - random()).ln() / channel_sigma
+        } else {
+            f64::INFINITY
+        };
+        let max_distance = t_max * speed;
+
+        if distance < max_distance {
+            let transmittance = self.transmittance_over(distance);
+            let density = mul_elem(sigma_t, transmittance);
+            let pdf = (density.x + density.y + density.z) / 3.0;
+            return MediumEvent::Scatter {
+                distance: distance / speed,
+                weight: (1.0 / pdf) * mul_elem(self.sigma_s, transmittance),
+                emitted: Vec3::ZERO
+            };
+        }
+
+        let transmittance = self.transmittance_over(max_distance);
+        let pdf = (transmittance.x + transmittance.y + transmittance.z) / 3.0;
+        if pdf <= 0.0 {
+            return MediumEvent::Pass { weight: Vec3::ZERO, emitted: Vec3::ZERO };
+        }
+        MediumEvent::Pass { weight: (1.0 / pdf) * transmittance, emitted: Vec3::ZERO }
+    }
+
+
+    fn transmittance(&self, ray: &Ray, t_max: f64) -> Vec3 {
+        self.transmittance_over(t_max * ray.direction.length())
+    }
+
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
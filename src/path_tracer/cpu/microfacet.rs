@@ -0,0 +1,238 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::f64::consts::PI;
+
+use simple_term_renderer::math::Vec3;
+
+use crate::path_tracer::sampler::random;
+
+use super::{lerp, sample_uniform_disk_polar};
+
+
+// Shading space helpers, the normal being the `z` axis.
+
+pub fn cos_theta(w: Vec3) -> f64 {
+    w.z
+}
+
+
+pub fn cos2_theta(w: Vec3) -> f64 {
+    w.z * w.z
+}
+
+
+pub fn abs_cos_theta(w: Vec3) -> f64 {
+    w.z.abs()
+}
+
+
+pub fn sin2_theta(w: Vec3) -> f64 {
+    (1.0 - cos2_theta(w)).max(0.0)
+}
+
+
+pub fn tan2_theta(w: Vec3) -> f64 {
+    sin2_theta(w) / cos2_theta(w)
+}
+
+
+pub fn cos_phi(w: Vec3) -> f64 {
+    let sin_theta = sin2_theta(w).sqrt();
+    if sin_theta == 0.0 { 1.0 } else { (w.x / sin_theta).clamp(-1.0, 1.0) }
+}
+
+
+pub fn sin_phi(w: Vec3) -> f64 {
+    let sin_theta = sin2_theta(w).sqrt();
+    if sin_theta == 0.0 { 0.0 } else { (w.y / sin_theta).clamp(-1.0, 1.0) }
+}
+
+
+pub fn same_hemisphere(w: Vec3, wp: Vec3) -> bool {
+    w.z * wp.z > 0.0
+}
+
+
+/// Reflects `w` (pointing away from the surface) around the normal `n`
+pub fn reflect_around(w: Vec3, n: Vec3) -> Vec3 {
+    -w + 2.0 * w.dot(n) * n
+}
+
+
+/// Fresnel reflectance of a dielectric interface, `eta` being the relative index of refraction (inside / outside).
+pub fn fresnel_dielectric(cos_theta_i: f64, eta: f64) -> f64 {
+    let mut cos_theta_i = cos_theta_i.clamp(-1.0, 1.0);
+    let mut eta = eta;
+
+    if cos_theta_i < 0.0 { // Coming from the inside
+        eta = 1.0 / eta;
+        cos_theta_i = -cos_theta_i;
+    }
+
+    let sin2_theta_i = 1.0 - cos_theta_i * cos_theta_i;
+    let sin2_theta_t = sin2_theta_i / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return 1.0; // Total internal reflection
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+
+    let r_parl = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perp = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+
+/// Schlick's approximation of the Fresnel reflectance for a reflectance at normal incidence of `f0`
+pub fn fresnel_schlick(f0: f64, cos_theta_i: f64) -> f64 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta_i.clamp(0.0, 1.0)).powi(5)
+}
+
+
+/// Fresnel reflectance of a conductor with complex index of refraction `eta + i k`.
+pub fn fresnel_conductor(cos_theta_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_theta_i.clamp(0.0, 1.0) * a;
+    let r_s = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+    0.5 * (r_p + r_s)
+}
+
+
+/// Trowbridge-Reitz (GGX) microfacet distribution.
+#[derive(Debug, Copy, Clone)]
+pub struct TrowbridgeReitz {
+    alpha_x: f64,
+    alpha_y: f64
+}
+
+
+impl TrowbridgeReitz {
+
+    pub fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        Self {
+            alpha_x: alpha_x.max(1e-4),
+            alpha_y: alpha_y.max(1e-4)
+        }
+    }
+
+
+    /// Maps a perceptual roughness in [0; 1] to the distribution's alpha
+    pub fn from_roughness(roughness: f64) -> Self {
+        let alpha = roughness * roughness;
+        Self::new(alpha, alpha)
+    }
+
+
+    /// Whether the distribution is so narrow that it should be handled as a perfect specular surface
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+
+    /// Distribution of microfacet normals
+    pub fn d(&self, wm: Vec3) -> f64 {
+        let tan2_theta = tan2_theta(wm);
+        if tan2_theta.is_infinite() || tan2_theta.is_nan() {
+            return 0.0;
+        }
+
+        let cos4_theta = cos2_theta(wm).powi(2);
+        if cos4_theta < 1e-16 {
+            return 0.0;
+        }
+
+        let e = tan2_theta * ((cos_phi(wm) / self.alpha_x).powi(2) + (sin_phi(wm) / self.alpha_y).powi(2));
+        1.0 / (PI * self.alpha_x * self.alpha_y * cos4_theta * (1.0 + e).powi(2))
+    }
+
+
+    pub fn lambda(&self, w: Vec3) -> f64 {
+        let tan2_theta = tan2_theta(w);
+        if tan2_theta.is_infinite() || tan2_theta.is_nan() {
+            return 0.0;
+        }
+
+        let alpha2 = (cos_phi(w) * self.alpha_x).powi(2) + (sin_phi(w) * self.alpha_y).powi(2);
+        ((1.0 + alpha2 * tan2_theta).sqrt() - 1.0) / 2.0
+    }
+
+
+    /// Masking function
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+
+    /// Height-correlated masking-shadowing function
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+
+    /// Distribution of the normals visible from `w`
+    pub fn d_visible(&self, w: Vec3, wm: Vec3) -> f64 {
+        self.g1(w) / abs_cos_theta(w) * self.d(wm) * w.dot(wm).abs()
+    }
+
+
+    /// Density of `sample_wm` for the microfacet normal `wm` seen from `w`
+    pub fn pdf(&self, w: Vec3, wm: Vec3) -> f64 {
+        self.d_visible(w, wm)
+    }
+
+
+    /// Samples a microfacet normal visible from `w` (Heitz, "Sampling the GGX Distribution of Visible Normals")
+    pub fn sample_wm(&self, w: Vec3) -> Vec3 {
+        // Transform w to the hemispherical configuration
+        let mut wh = Vec3::new(self.alpha_x * w.x, self.alpha_y * w.y, w.z).normalized();
+        if wh.z < 0.0 {
+            wh = -wh;
+        }
+
+        // Orthonormal basis for visible normal sampling
+        let t1 = if wh.z < 0.99999 {
+            Vec3::UNIT_Z.cross(wh).normalized()
+        } else {
+            Vec3::UNIT_X
+        };
+        let t2 = wh.cross(t1);
+
+        // Generate a uniformly distributed point on the projected hemisphere
+        let (px, py) = sample_uniform_disk_polar((random(), random()));
+        let h = (1.0 - px * px).sqrt();
+        let py = lerp((1.0 + wh.z) / 2.0, h, py);
+        let pz = (1.0 - px * px - py * py).max(0.0).sqrt();
+
+        // Transform back to the ellipsoid configuration
+        let nh = px * t1 + py * t2 + pz * wh;
+        Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalized()
+    }
+}
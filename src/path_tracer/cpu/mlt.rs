@@ -0,0 +1,135 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use simple_term_renderer::math::Vec3;
+
+use crate::{Camera, Ray};
+use crate::path_tracer::sampler::{random, PrimarySampler};
+
+use super::{lerp, luminance, CpuRenderingDevice, Film};
+
+
+/// Markov chains run by the Metropolis integrator, each one starting from a path picked among the bootstrap paths
+const MLT_CHAIN_COUNT: usize = 256;
+
+/// Standard deviation of small steps in the primary sample space
+const MLT_SMALL_STEP_SIGMA: f64 = 0.01;
+
+
+impl CpuRenderingDevice {
+
+    /// Renders with primary sample space Metropolis light transport, mutating the random numbers consumed by the path
+    /// tracer.
+    ///
+    /// The brightness of the image is found from `mlt_bootstrap_samples` independent paths. Chains then take
+    /// `mlt_mutations_per_pixel` mutations per pixel overall, replacing all of the numbers with a probability of
+    /// `mlt_large_step_probability`.
+    pub(super) fn render_metropolis(&self, camera: &Camera, width: usize, height: usize) -> Film {
+        let film = Film::new(width, height);
+        let new_sampler = |seed: usize| {
+            let sampler = PrimarySampler::new(seed as u64, MLT_SMALL_STEP_SIGMA, self.mlt_large_step_probability);
+            Rc::new(RefCell::new(sampler))
+        };
+
+        // Bootstrap paths, telling the average brightness and where chains may start
+        let weights: Vec<f64> = (0..self.mlt_bootstrap_samples)
+            .map(|seed| luminance(self.sample_primary_path(&new_sampler(seed), camera, width, height).1))
+            .collect();
+        let weight_sum: f64 = weights.iter().sum();
+        if weight_sum <= 0.0 {
+            return film;
+        }
+        let brightness = weight_sum / self.mlt_bootstrap_samples as f64;
+
+        let mutation_count = self.mlt_mutations_per_pixel * width * height;
+        let chain_count = MLT_CHAIN_COUNT.min(mutation_count);
+        // Each splat is an estimate of the image, which averages the radiance of `width * height` pixels
+        let scale = brightness * (width * height) as f64 / mutation_count as f64;
+
+        for chain in 0..chain_count {
+            let seed = pick_proportionally(&weights, weight_sum);
+            let sampler = new_sampler(seed);
+            let (mut current_pixel, mut current_radiance) = self.sample_primary_path(&sampler, camera, width, height);
+            let mut current_weight = luminance(current_radiance);
+
+            // The remaining mutations go to the first chains
+            let chain_mutations = mutation_count / chain_count + usize::from(chain < mutation_count % chain_count);
+
+            for _ in 0..chain_mutations {
+                sampler.borrow_mut().start_iteration();
+                let (pixel, radiance) = self.sample_primary_path(&sampler, camera, width, height);
+                let weight = luminance(radiance);
+
+                let acceptance = if current_weight > 0.0 { (weight / current_weight).min(1.0) } else { 1.0 };
+
+                // Both states are splatted, weighted by their expected presence
+                if weight > 0.0 {
+                    film.add_splat(pixel.0, pixel.1, (acceptance * scale / weight) * radiance);
+                }
+                if current_weight > 0.0 {
+                    let current = ((1.0 - acceptance) * scale / current_weight) * current_radiance;
+                    film.add_splat(current_pixel.0, current_pixel.1, current);
+                }
+
+                let mut primary = sampler.borrow_mut();
+                if primary.uniform() < acceptance {
+                    primary.accept();
+                    current_pixel = pixel;
+                    current_radiance = radiance;
+                    current_weight = weight;
+                } else {
+                    primary.reject();
+                }
+            }
+        }
+
+        film
+    }
+
+
+    /// Samples a camera path with the numbers of `sampler`, returning the pixel it goes through and its radiance
+    fn sample_primary_path(
+        &self, sampler: &Rc<RefCell<PrimarySampler>>, camera: &Camera, width: usize, height: usize
+    ) -> ((usize, usize), Vec3) {
+        PrimarySampler::run(sampler, || {
+            let (u, v) = (random(), random());
+            let time = lerp(random(), camera.shutter_open, camera.shutter_close);
+
+            let ray_direction = camera.direction(u, v, width as f64 / height as f64, time);
+            let ray = Ray::new(camera.position_at(time), ray_direction).with_time(time);
+            let pixel = (((u * width as f64) as usize).min(width - 1), ((v * height as f64) as usize).min(height - 1));
+            (pixel, self.traced_radiance(&ray))
+        })
+    }
+}
+
+
+/// Index picked with a probability proportional to its weight
+fn pick_proportionally(weights: &[f64], weight_sum: f64) -> usize {
+    let mut u = random() * weight_sum;
+    for (index, weight) in weights.iter().enumerate() {
+        if u < *weight {
+            return index;
+        }
+        u -= weight;
+    }
+    weights.len() - 1
+}
@@ -17,54 +17,164 @@ OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
 
 
 mod obj;
+mod quadric;
+mod csg;
+mod sdf;
 mod mat;
+mod microfacet;
+mod tex;
+mod medium;
+mod volume;
+mod light;
+mod spectrum;
+mod scene;
+mod anim;
+mod scene_file;
+mod film;
+mod bdpt;
+mod photon;
+mod particle;
+mod mlt;
+mod debug;
 
-use std::collections::HashMap;
-use std::f64::consts::TAU;
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::{PI, TAU};
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
 
 use simple_term_renderer::img::Color;
-use simple_term_renderer::{img::Image, vec2, vec3};
+use simple_term_renderer::{img::Image, vec3};
 use simple_term_renderer::math::*;
 use super::math::*;
 
 use crate::rid::{Rid, RidOwner};
-use crate::{Camera, HitInfo, PTRenderer, Ray};
+use crate::transform::Transform;
+use crate::{Camera, HitInfo, Integrator, PTRenderer, Ray};
+use crate::path_tracer::sampler::random;
 
 
 use obj::*;
+use quadric::*;
+use csg::*;
+use sdf::*;
 use mat::*;
+use tex::*;
+use medium::*;
+use volume::*;
+use light::*;
+use spectrum::{blackbody, blackbody_rgb, Wavelengths};
+use scene::SceneGraph;
+use photon::PhotonMap;
+
+pub use anim::{Animation, Interpolation, Track};
+pub use csg::CsgOperation;
+pub use film::Film;
+pub use light::Light;
+pub use mat::{ComplexIor, PrincipledParameters};
+pub use scene_file::SceneDescription;
+pub use sdf::SdfExpr;
+pub use spectrum::{BlackbodyUnits, Dispersion};
+pub use tex::WrapMode;
+pub use volume::VolumeParameters;
 
 
 pub struct CpuRenderingDevice {
-    objects: RidOwner<Box<dyn Object>>,
+    objects: RidOwner<Arc<dyn Object>>,
+    scene: SceneGraph,
+    hidden_objects: HashSet<Rid>,
 
     materials: RidOwner<Box<dyn Material>>,
     default_material: Rid,
     object_materials: HashMap<Rid, Rid>,
+    material_normals: HashMap<Rid, NormalPerturbation>,
+
+    textures: RidOwner<Arc<dyn Texture>>,
+
+    media: RidOwner<Box<dyn Medium>>,
+    /// Medium filling the space outside of objects
+    global_medium: Option<Rid>,
+    /// Media filling the inside of closed objects
+    object_media: HashMap<Rid, Rid>,
+
+    lights: RidOwner<Light>,
+    /// Hierarchy used to sample `lights` and the emissive objects, rebuilt before rendering when they changed
+    light_tree: LightTree,
+    light_tree_dirty: bool,
+
+    sun_temperature: f64,
+    /// Colour of the sun, cached as integrating the blackbody spectrum is expensive
+    sun_color: Vec3,
+
+    integrator: Integrator,
 
     pub max_light_bounce: i64,
-    pub pixel_sample_count: i64
+    pub pixel_sample_count: i64,
+    /// Renders with wavelengths sampled per path instead of RGB channels, needed for dispersion. Only the path
+    /// tracing and Metropolis integrators support it, the other ones rendering in RGB: see `spectral_supported`.
+    pub spectral: bool,
+    /// Photons shot for every pass of the photon mapping integrator
+    pub photon_count: usize,
+    /// Radius within which photons are gathered during the first pass, shrinking over the next ones. Radii too small
+    /// to gather any photon are clamped.
+    pub photon_radius: f64,
+    /// Paths sampled to estimate the brightness of the image before running the Metropolis integrator
+    pub mlt_bootstrap_samples: usize,
+    pub mlt_mutations_per_pixel: usize,
+    /// Probability of a Metropolis mutation to sample a whole new path
+    pub mlt_large_step_probability: f64
 }
 
 
 impl CpuRenderingDevice {
 
+    /// Temperature of the surface of the sun, in kelvins
+    pub const DEFAULT_SUN_TEMPERATURE: f64 = 5800.0;
+
+
+    /// Luminance of the sun in the sky, its colour being given at unit luminance by its temperature. It is the
+    /// luminance of the warm white `1.8 * (0.95, 0.9, 0.6)` sun, so that the temperature only changes the hue.
+    const SUN_LUMINANCE: f64 = 1.6;
+
+
     pub fn new(max_light_bounce: i64, pixel_sample_count: i64) -> Self {
         let mut materials: RidOwner<Box<dyn Material>> = RidOwner::new();
-        let default_material = materials.add(Box::new(Lambertian::new(vec3!(0.5, 0.5, 0.5))));
+        let default_material = materials.add(Box::new(Lambertian::new(
+            Arc::new(ConstantTexture::new(vec3!(0.5, 0.5, 0.5)))
+        )));
 
         Self {
             objects: RidOwner::new(),
+            scene: SceneGraph::new(),
+            hidden_objects: HashSet::new(),
             materials: materials,
             object_materials: HashMap::new(),
+            material_normals: HashMap::new(),
             default_material: default_material,
+            textures: RidOwner::new(),
+            media: RidOwner::new(),
+            global_medium: None,
+            object_media: HashMap::new(),
+            lights: RidOwner::new(),
+            light_tree: LightTree::new(std::iter::empty()),
+            light_tree_dirty: false,
+            sun_temperature: Self::DEFAULT_SUN_TEMPERATURE,
+            sun_color: blackbody_rgb(Self::DEFAULT_SUN_TEMPERATURE),
+            integrator: Integrator::PathTracing,
             max_light_bounce: max_light_bounce,
-            pixel_sample_count: pixel_sample_count
+            pixel_sample_count: pixel_sample_count,
+            spectral: false,
+            photon_count: 100_000,
+            photon_radius: 0.1,
+            mlt_bootstrap_samples: 100_000,
+            mlt_mutations_per_pixel: 100,
+            mlt_large_step_probability: 0.3
         }
     }
 
     pub fn create_sphere(&mut self, position: Vec3, radius: f64) -> Rid {
-        let rid = self.objects.add(Box::new(
+        let rid = self.objects.add(Arc::new(
             Sphere::new(position, radius)
         ));
         self.object_set_material(rid, self.default_material);
@@ -72,8 +182,18 @@ impl CpuRenderingDevice {
     }
 
 
+    /// Creates a sphere moving from `start` to `end` during the frame, for motion blur.
+    pub fn create_moving_sphere(&mut self, start: Vec3, end: Vec3, radius: f64) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Sphere::moving(start, end, radius)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
     pub fn create_plane(&mut self, normal: Vec3, position: Vec3) -> Rid {
-        let rid = self.objects.add(Box::new(
+        let rid = self.objects.add(Arc::new(
             Plane::new(normal, position)
         ));
         self.object_set_material(rid, self.default_material);
@@ -81,48 +201,1038 @@ impl CpuRenderingDevice {
     }
 
 
+    /// Creates a parallelogram spanned by `u` and `v` from `corner`, facing towards `u x v`.
+    pub fn create_quad(&mut self, corner: Vec3, u: Vec3, v: Vec3) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Quad::new(corner, u, v)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    pub fn create_disk(&mut self, center: Vec3, normal: Vec3, radius: f64) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Disk::new(center, normal, radius)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a box aligned with the axes of its object space, spanning from `min` to `max`.
+    pub fn create_box(&mut self, min: Vec3, max: Vec3) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            AxisAlignedBox::new(min, max)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a triangle facing towards `(b - a) x (c - a)`.
+    pub fn create_triangle(&mut self, a: Vec3, b: Vec3, c: Vec3) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Triangle::new(a, b, c)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a cylinder along the `y` axis, centered on `center`. Caps close both ends when `capped` is set.
+    pub fn create_cylinder(&mut self, center: Vec3, radius: f64, height: f64, capped: bool) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Cylinder::new(center, radius, height, capped)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a cone along the `y` axis, its base centered on `base_center` and its apex `height` above it.
+    pub fn create_cone(&mut self, base_center: Vec3, radius: f64, height: f64, capped: bool) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Cone::new(base_center, radius, height, capped)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a capsule along the `y` axis, `height` being the length of its cylindrical part.
+    pub fn create_capsule(&mut self, center: Vec3, radius: f64, height: f64) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Capsule::new(center, radius, height)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates a torus lying in the `xz` plane.
+    pub fn create_torus(&mut self, center: Vec3, major_radius: f64, minor_radius: f64) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            Torus::new(center, major_radius, minor_radius)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates an object bounded by the zero level of a signed distance expression, rendered by sphere tracing.
+    ///
+    /// Marching gives up after `max_steps` and stops once closer than `epsilon` to the surface, which should stay
+    /// below `1e-4` for secondary rays not to hit the surface they leave.
+    pub fn create_sdf(&mut self, expr: SdfExpr, max_steps: u32, epsilon: f64) -> Rid {
+        let rid = self.objects.add(Arc::new(
+            SdfObject::new(expr, max_steps, epsilon)
+        ));
+        self.object_set_material(rid, self.default_material);
+        rid
+    }
+
+
+    /// Creates an object sharing the geometry of `geometry`, returns `None` if it does not exist.
+    ///
+    /// The instance has its own material and transform. The original object can be hidden with `object_set_visible`.
+    pub fn create_instance(&mut self, geometry: Rid) -> Option<Rid> {
+        let geometry = self.objects.get(geometry)?.clone();
+        let rid = self.objects.add(Arc::new(
+            Instance::new(geometry)
+        ));
+        self.object_set_material(rid, self.default_material);
+        Some(rid)
+    }
+
+
+    /// Combines two objects into a solid, returns `None` if one of them does not exist.
+    ///
+    /// The operands keep their own materials and are placed with their current local transforms, relatively to the
+    /// new object. They are hidden, as they would otherwise be rendered on their own too.
+    pub fn create_csg(&mut self, operation: CsgOperation, left: Rid, right: Rid) -> Option<Rid> {
+        let left_operand = CsgOperand::new(left, self.objects.get(left)?.clone(), self.scene.local_transform(left));
+        let right_operand = CsgOperand::new(right, self.objects.get(right)?.clone(), self.scene.local_transform(right));
+
+        let rid = self.objects.add(Arc::new(
+            Csg::new(operation, left_operand, right_operand)
+        ));
+        self.object_set_material(rid, self.default_material);
+        self.object_set_visible(left, false);
+        self.object_set_visible(right, false);
+        Some(rid)
+    }
+
+
+    /// Creates an empty node of the scene graph, used to move several objects at once.
+    pub fn create_group(&mut self) -> Rid {
+        self.objects.add(Arc::new(Group))
+    }
+
+
+    /// Places an object relatively to its parent, the transformation being applied to its object space definition.
+    pub fn object_set_transform(&mut self, rid: Rid, transform: Transform) {
+        self.scene.set_local_transform(rid, transform);
+        self.light_tree_dirty = true;
+    }
+
+
+    pub fn object_get_transform(&self, rid: Rid) -> Transform {
+        self.scene.local_transform(rid)
+    }
+
+
+    /// Makes an object move during the frame, from its transform to `end`, for motion blur. `None` stops the motion.
+    pub fn object_set_motion(&mut self, rid: Rid, end: Option<Transform>) {
+        self.scene.set_local_motion(rid, end);
+        self.light_tree_dirty = true;
+    }
+
+
+    pub fn object_get_motion(&self, rid: Rid) -> Option<Transform> {
+        self.scene.local_motion(rid)
+    }
+
+
+    /// Moves an object under `parent` (or at the root), keeping its local transform.
+    /// Returns `false` if one of the objects does not exist or if `parent` is a descendant of the object.
+    pub fn object_set_parent(&mut self, rid: Rid, parent: Option<Rid>) -> bool {
+        let exists = |rid: Rid| self.objects.get(rid).is_some();
+        if !exists(rid) || parent.is_some_and(|parent| !exists(parent)) {
+            return false;
+        }
+        let moved = self.scene.set_parent(rid, parent);
+        self.light_tree_dirty = true;
+        moved
+    }
+
+
+    pub fn object_get_parent(&self, rid: Rid) -> Option<Rid> {
+        self.scene.parent(rid)
+    }
+
+
+    pub fn object_get_children(&self, rid: Rid) -> &[Rid] {
+        self.scene.children(rid)
+    }
+
+
+    pub fn object_set_visible(&mut self, rid: Rid, visible: bool) {
+        if visible {
+            self.hidden_objects.remove(&rid);
+        } else {
+            self.hidden_objects.insert(rid);
+        }
+        self.light_tree_dirty = true;
+    }
+
+
+    pub fn create_constant_texture(&mut self, color: Color) -> Rid {
+        self.textures.add(Arc::new(
+            ConstantTexture::new(color.get_raw_vec3f())
+        ))
+    }
+
+
+    /// Creates a texture alternating between `even` and `odd` on a grid of `scale` cells per unit of UV.
+    pub fn create_checker_texture(&mut self, even: Rid, odd: Rid, scale: f64) -> Rid {
+        let (even, odd) = (self.get_texture(even), self.get_texture(odd));
+        self.textures.add(Arc::new(
+            CheckerTexture::new(even, odd, scale)
+        ))
+    }
+
+
+    /// Creates a solid fBm noise texture, `scale` being the frequency of the first of the `octaves`.
+    pub fn create_noise_texture(&mut self, seed: u64, scale: f64, octaves: u32, low: Color, high: Color) -> Rid {
+        self.textures.add(Arc::new(
+            NoiseTexture::new(seed, scale, octaves, low.get_raw_vec3f(), high.get_raw_vec3f())
+        ))
+    }
+
+
+    /// Loads a PNG or PPM image texture. `color` images are linearized, other ones (roughness...) are kept as is.
+    pub fn load_image_texture(&mut self, path: &Path, wrap: WrapMode, color: bool) -> io::Result<Rid> {
+        let texture = ImageTexture::load(path, wrap, color)?;
+        Ok(self.textures.add(Arc::new(texture)))
+    }
+
+
     pub fn create_lambertial_material(&mut self, albedo: Color) -> Rid {
+        let albedo = self.constant_texture(albedo);
         self.materials.add(Box::new(
-            Lambertian::new(albedo.get_raw_vec3f()))
+            Lambertian::new(albedo))
+        )
+    }
+
+
+    pub fn create_textured_lambertian_material(&mut self, albedo: Rid) -> Rid {
+        let albedo = self.get_texture(albedo);
+        self.materials.add(Box::new(
+            Lambertian::new(albedo))
         )
     }
 
 
     pub fn create_metal_material(&mut self, albedo: Color, fuzz: f64) -> Rid {
+        let albedo = self.constant_texture(albedo);
+        self.materials.add(Box::new(
+            Metal::new(albedo, fuzz)
+        ))
+    }
+
+
+    pub fn create_textured_metal_material(&mut self, albedo: Rid, fuzz: f64) -> Rid {
+        let albedo = self.get_texture(albedo);
+        self.materials.add(Box::new(
+            Metal::new(albedo, fuzz)
+        ))
+    }
+
+
+    /// Creates a GGX microfacet conductor, `roughness` being in [0; 1].
+    pub fn create_conductor_material(&mut self, ior: ComplexIor, roughness: f64) -> Rid {
+        let roughness = Self::scalar_texture(roughness);
+        self.materials.add(Box::new(
+            Conductor::new(ior, roughness)
+        ))
+    }
+
+
+    /// Creates a GGX microfacet conductor whose roughness is read from the first channel of a texture.
+    pub fn create_textured_conductor_material(&mut self, ior: ComplexIor, roughness: Rid) -> Rid {
+        let roughness = self.get_texture(roughness);
+        self.materials.add(Box::new(
+            Conductor::new(ior, roughness)
+        ))
+    }
+
+
+    /// Creates a GGX microfacet dielectric (glass, water...) of index of refraction `ior`, `roughness` being in [0; 1].
+    pub fn create_dielectric_material(&mut self, ior: f64, roughness: f64) -> Rid {
+        let roughness = Self::scalar_texture(roughness);
+        self.materials.add(Box::new(
+            RoughDielectric::new(ior, roughness)
+        ))
+    }
+
+
+    /// Creates a GGX microfacet dielectric whose index of refraction depends on the wavelength. Dispersion is only
+    /// visible when rendering spectrally, the index at 550 nm being used otherwise. `PrincipledParameters::dispersion`
+    /// does the same for the transmission lobe of principled materials.
+    pub fn create_dispersive_dielectric_material(&mut self, dispersion: Dispersion, roughness: f64) -> Rid {
+        let roughness = Self::scalar_texture(roughness);
+        self.materials.add(Box::new(
+            RoughDielectric::dispersive(dispersion, roughness)
+        ))
+    }
+
+
+    /// Creates a GGX microfacet dielectric whose roughness is read from the first channel of a texture.
+    pub fn create_textured_dielectric_material(&mut self, ior: f64, roughness: Rid) -> Rid {
+        let roughness = self.get_texture(roughness);
+        self.materials.add(Box::new(
+            RoughDielectric::new(ior, roughness)
+        ))
+    }
+
+
+    /// Creates a translucent material, light entering objects random walking through them before leaving.
+    ///
+    /// `albedo` is the overall colour of the material, `mean_free_path` the average distance travelled by light
+    /// inside of it between two scattering events for each channel, in scene units. Shorter paths than a small positive
+    /// length, zero and negative ones included, are clamped to it.
+    pub fn create_subsurface_material(
+        &mut self, albedo: Color, mean_free_path: Vec3, ior: f64, roughness: f64, anisotropy: f64
+    ) -> Rid {
+        let roughness = Self::scalar_texture(roughness);
+        self.materials.add(Box::new(
+            Subsurface::new(albedo.get_raw_vec3f(), mean_free_path, ior, roughness, anisotropy)
+        ))
+    }
+
+
+    /// Creates a principled (Disney style) material from artist facing parameters.
+    pub fn create_principled_material(&mut self, parameters: &PrincipledParameters) -> Rid {
+        let base_color = match parameters.base_color_texture {
+            Some(rid) => self.get_texture(rid),
+            None => self.constant_texture(parameters.base_color)
+        };
+        let roughness = match parameters.roughness_texture {
+            Some(rid) => self.get_texture(rid),
+            None => Self::scalar_texture(parameters.roughness)
+        };
+
         self.materials.add(Box::new(
-            Metal::new(albedo.get_raw_vec3f(), fuzz)
+            Principled::new(parameters, base_color, roughness)
         ))
     }
 
 
+    /// Replaces a material by a principled material, keeping its `Rid`. Used to animate material parameters.
+    pub fn material_set_principled(&mut self, rid: Rid, parameters: &PrincipledParameters) {
+        if self.materials.get(rid).is_none() {
+            return;
+        }
+
+        let base_color = match parameters.base_color_texture {
+            Some(texture) => self.get_texture(texture),
+            None => self.constant_texture(parameters.base_color)
+        };
+        let roughness = match parameters.roughness_texture {
+            Some(texture) => self.get_texture(texture),
+            None => Self::scalar_texture(parameters.roughness)
+        };
+
+        let material: Box<dyn Material> = Box::new(Principled::new(parameters, base_color, roughness));
+        self.materials.modify(rid, |entry| *entry = material);
+        self.light_tree_dirty = true;
+    }
+
+
+    /// Creates a light emitting material of radiance `strength * color`.
+    pub fn create_emissive_material(&mut self, color: Color, strength: f64) -> Rid {
+        let emission = self.constant_texture(color);
+        self.materials.add(Box::new(
+            Emissive::new(emission, strength)
+        ))
+    }
+
+
+    /// Creates a light emitting material whose colour is the one of a black body at `temperature` kelvins, its
+    /// radiance being scaled by `strength`.
+    pub fn create_blackbody_emissive_material(
+        &mut self, temperature: f64, strength: f64, units: BlackbodyUnits
+    ) -> Rid {
+        let emission = Arc::new(ConstantTexture::new(blackbody(temperature, units)));
+        self.materials.add(Box::new(
+            Emissive::new(emission, strength)
+        ))
+    }
+
+
+    pub fn create_textured_emissive_material(&mut self, emission: Rid, strength: f64) -> Rid {
+        let emission = self.get_texture(emission);
+        self.materials.add(Box::new(
+            Emissive::new(emission, strength)
+        ))
+    }
+
+
+    /// Perturbs the shading normal of a material with a tangent space normal map.
+    pub fn material_set_normal_map(&mut self, mat_rid: Rid, normal_map: Rid) {
+        let texture = self.get_texture(normal_map);
+        self.material_normals.insert(mat_rid, NormalPerturbation::NormalMap(texture));
+    }
+
+
+    /// Perturbs the shading normal of a material with a height map, scaled by `strength`.
+    pub fn material_set_bump_map(&mut self, mat_rid: Rid, height_map: Rid, strength: f64) {
+        let texture = self.get_texture(height_map);
+        self.material_normals.insert(mat_rid, NormalPerturbation::Bump(texture, strength));
+    }
+
+
+    pub fn material_clear_normal_perturbation(&mut self, mat_rid: Rid) {
+        self.material_normals.remove(&mat_rid);
+    }
+
+
+    /// Creates a medium of constant density. `sigma_a` and `sigma_s` are the absorption and scattering coefficients
+    /// per unit of distance, `g` the asymmetry of the Henyey-Greenstein phase function.
+    pub fn create_homogeneous_medium(&mut self, sigma_a: Vec3, sigma_s: Vec3, g: f64) -> Rid {
+        self.media.add(Box::new(
+            HomogeneousMedium::new(sigma_a, sigma_s, g)
+        ))
+    }
+
+
+    /// Loads a voxel grid medium spanning the box from `min` to `max`, see `VoxelGrid::load` for the file format.
+    ///
+    /// The medium is defined in world space. It is usually bounded by a box with an interface material, having the
+    /// medium as interior.
+    pub fn load_grid_medium(
+        &mut self, path: &Path, min: Vec3, max: Vec3, params: &VolumeParameters
+    ) -> io::Result<Rid> {
+        let (density, temperature) = VoxelGrid::load(path)?;
+        Ok(self.media.add(Box::new(
+            GridMedium::new(Aabb::new(min, max), density, temperature, params)
+        )))
+    }
+
+
+    /// Creates a cloud-like voxel grid medium of `resolution`³ voxels from fBm noise, spanning the box from `min` to
+    /// `max` in world space.
+    pub fn create_noise_grid_medium(
+        &mut self, min: Vec3, max: Vec3, resolution: usize, seed: u64, params: &VolumeParameters
+    ) -> Rid {
+        let density = VoxelGrid::from_noise(resolution, seed, 2.0, 4);
+        self.media.add(Box::new(
+            GridMedium::new(Aabb::new(min, max), density, None, params)
+        ))
+    }
+
+
+    /// Fills the space outside of objects with a medium, such as atmospheric fog.
+    pub fn set_global_medium(&mut self, medium: Option<Rid>) {
+        self.global_medium = medium;
+    }
+
+
+    /// Fills the inside of a closed object with a medium. Rays entering the object travel through it until they
+    /// leave the object, media of nested objects are not stacked.
+    ///
+    /// The surface of the object still scatters light with its material, use an interface material for it to be
+    /// invisible.
+    pub fn object_set_interior_medium(&mut self, obj_rid: Rid, medium: Option<Rid>) {
+        match medium {
+            Some(medium) => { self.object_media.insert(obj_rid, medium); },
+            None => { self.object_media.remove(&obj_rid); }
+        }
+    }
+
+
+    pub fn remove_medium(&mut self, rid: Rid) {
+        self.media.remove(rid);
+    }
+
+
+    /// Creates a light emitting `intensity * color` in every direction, decreasing with the distance `d` as
+    /// `1 / d^falloff`.
+    pub fn create_point_light(&mut self, position: Vec3, color: Color, intensity: f64, falloff: f64) -> Rid {
+        self.add_light(Light::point(position, intensity * color.get_raw_vec3f(), falloff))
+    }
+
+
+    /// Creates a point light shining in a cone around `direction`. Its light fades out between the inner and outer
+    /// half angles, in radians.
+    pub fn create_spot_light(
+        &mut self, position: Vec3, direction: Vec3, color: Color, intensity: f64, inner_angle: f64, outer_angle: f64
+    ) -> Rid {
+        let intensity = intensity * color.get_raw_vec3f();
+        self.add_light(Light::spot(position, direction, intensity, 2.0, inner_angle, outer_angle))
+    }
+
+
+    /// Creates a light coming from infinitely far away, travelling along `direction`, like sunlight.
+    pub fn create_directional_light(&mut self, direction: Vec3, color: Color, irradiance: f64) -> Rid {
+        self.add_light(Light::directional(direction, irradiance * color.get_raw_vec3f()))
+    }
+
+
+    fn add_light(&mut self, light: Light) -> Rid {
+        let rid = self.lights.add(light);
+        self.light_tree_dirty = true;
+        rid
+    }
+
+
+    /// Rebuilds the light hierarchy from the lights and the emissive objects if one of them may have changed since it
+    /// was built. Called before rendering, so that building or animating a scene does not rebuild it on every edit.
+    fn update_light_tree(&mut self) {
+        if !self.light_tree_dirty {
+            return;
+        }
+        self.light_tree_dirty = false;
+
+        let area_lights: Vec<AreaLight> = self.object_materials.iter()
+            .filter_map(|(obj_rid, mat_rid)| self.area_light(*obj_rid, *mat_rid))
+            .collect();
+
+        let lights = self.lights.value_iter().map(|light| LightSource::Light(*light));
+        self.light_tree = LightTree::new(lights.chain(area_lights.into_iter().map(LightSource::Area)));
+    }
+
+
+    /// Light emitted by an object, `None` if its material does not emit or if its surface can not be sampled
+    fn area_light(&self, obj_rid: Rid, mat_rid: Rid) -> Option<AreaLight> {
+        if self.hidden_objects.contains(&obj_rid) {
+            return None;
+        }
+        let emission = luminance(self.materials.get(mat_rid)?.average_emission());
+        if emission <= 0.0 {
+            return None;
+        }
+
+        let obj = self.objects.get(obj_rid)?;
+        let (mut area, mut bounds) = (obj.area()?, obj.bounding_box()?);
+
+        // The bounds cover the whole frame, during which the object may move
+        let start = self.scene.world_transform_at(obj_rid, 0.0);
+        let end = self.scene.world_transform_at(obj_rid, 1.0);
+        if let (Some(start), Some(end)) = (start, end) {
+            area *= start.mean_area_scale();
+            bounds = start.box_to_world(&bounds).union(&end.box_to_world(&bounds));
+        }
+
+        Some(AreaLight {
+            object: obj_rid,
+            bounds: bounds,
+            power: PI * emission * area
+        })
+    }
+
+
+    pub fn light_get(&self, rid: Rid) -> Option<Light> {
+        self.lights.get(rid).copied()
+    }
+
+
+    pub fn light_set(&mut self, rid: Rid, light: Light) {
+        self.lights.modify(rid, |entry| *entry = light);
+        self.light_tree_dirty = true;
+    }
+
+
+    pub fn remove_light(&mut self, rid: Rid) {
+        self.lights.remove(rid);
+        self.light_tree_dirty = true;
+    }
+
+
+    /// Creates an invisible material, letting light through unchanged. Used to bound media.
+    pub fn create_interface_material(&mut self) -> Rid {
+        self.materials.add(Box::new(Interface))
+    }
+
+
     pub fn object_set_material(&mut self, obj_rid: Rid, mat_rid: Rid) {
         self.object_materials.entry(obj_rid)
             .and_modify(|entry| {*entry = mat_rid})
             .or_insert(mat_rid);
+        self.light_tree_dirty = true;
     }
 
 
-    pub fn remove_object(&mut self, rid: Rid) {
+    /// Removes an object, its children are moved to its parent. Returns `false`, removing nothing, if its transform
+    /// can not be folded into the ones of its children: see `SceneGraph::remove`.
+    pub fn remove_object(&mut self, rid: Rid) -> bool {
+        if !self.scene.remove(rid) {
+            return false;
+        }
         self.objects.remove(rid);
+        self.hidden_objects.remove(&rid);
+        self.object_media.remove(&rid);
+        self.light_tree_dirty = true;
+        true
+    }
+
+
+    /// Removes an object along with all of its descendants.
+    pub fn remove_subtree(&mut self, rid: Rid) {
+        for node in self.scene.subtree(rid).into_iter().rev() {
+            self.remove_object(node);
+        }
     }
 
 
     pub fn remove_material(&mut self, rid: Rid) {
         self.materials.remove(rid);
+        self.material_normals.remove(&rid);
+        self.light_tree_dirty = true;
     }
 
 
-    fn ray_color(&self, ray: &Ray, bounce_count: i64) -> Vec3 {
-        if bounce_count > self.max_light_bounce { // The light would not stop bouncing
-            return Vec3::ZERO;
+    /// Materials keep the textures they were created with, removing a texture only frees its `Rid`.
+    pub fn remove_texture(&mut self, rid: Rid) {
+        self.textures.remove(rid);
+    }
+
+
+    fn constant_texture(&self, color: Color) -> Arc<dyn Texture> {
+        Arc::new(ConstantTexture::new(color.get_raw_vec3f()))
+    }
+
+
+    fn scalar_texture(value: f64) -> Arc<dyn Texture> {
+        Arc::new(ConstantTexture::new(vec3!(value, value, value)))
+    }
+
+
+    /// Returns the texture of `rid`, or a constant grey texture if it does not exist
+    fn get_texture(&self, rid: Rid) -> Arc<dyn Texture> {
+        self.textures.get(rid)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(ConstantTexture::new(vec3!(0.5, 0.5, 0.5))))
+    }
+
+
+    /// Intersects an object, taking its transform into account
+    fn hit_object(&self, rid: &Rid, obj: &dyn Object, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        if self.hidden_objects.contains(rid) {
+            return None;
+        }
+
+        match self.scene.world_transform_at(*rid, ray.time) {
+            Some(object_to_world) => {
+                let local_ray = object_to_world.ray_to_object(ray);
+                if !Self::may_hit(obj, &local_ray, interval) {
+                    return None;
+                }
+                let hit = obj.hit(&local_ray, interval)?;
+                Some(object_to_world.hit_to_world(hit))
+            },
+            None => {
+                if !Self::may_hit(obj, ray, interval) {
+                    return None;
+                }
+                obj.hit(ray, interval)
+            }
+        }
+    }
+
+
+    /// Culls objects whose bounding box is missed by the ray
+    fn may_hit(obj: &dyn Object, ray: &Ray, interval: &Interval) -> bool {
+        let Some(bounds) = obj.bounding_box() else {
+            return true;
+        };
+        match bounds.hit_range(ray.origin, ray.direction) {
+            Some((t_min, t_max)) => t_max >= interval.start() && t_min <= interval.end(),
+            None => false
+        }
+    }
+
+
+    /// Material shading a hit on `obj_rid`
+    fn hit_material(&self, hit_info: &HitInfo, obj_rid: &Rid) -> Rid {
+        let obj_rid = hit_info.material_object.as_ref().unwrap_or(obj_rid);
+        self.object_materials.get(obj_rid)
+            .filter(|mat_rid| self.materials.get(**mat_rid).is_some())
+            .copied()
+            .unwrap_or(self.default_material)
+    }
+
+
+    /// Returns the material of the hit object, after having applied its normal perturbation to the hit
+    fn shade(&self, hit_info: &mut HitInfo, obj_rid: &Rid) -> &dyn Material {
+        let mat_rid = self.hit_material(hit_info, obj_rid);
+
+        if let Some(perturbation) = self.material_normals.get(&mat_rid) {
+            perturbation.apply(hit_info);
+        }
+
+        self.materials.get(mat_rid).unwrap().as_ref()
+    }
+
+
+    /// Medium a ray leaving the surface of `obj_rid` in direction `wi` travels through, coming from `medium`
+    fn medium_after(&self, hit_info: &HitInfo, obj_rid: &Rid, wi: Vec3, medium: Option<Rid>) -> Option<Rid> {
+        let Some(interior) = self.object_media.get(obj_rid) else {
+            return medium; // The object does not bound any medium
+        };
+
+        if wi.dot(hit_info.outward_normal()) < 0.0 {
+            Some(*interior)
+        } else {
+            self.global_medium
+        }
+    }
+
+
+    /// Poses the scene and the camera at `frame`.
+    ///
+    /// Objects and the camera move towards their pose of the next frame during the shutter interval, for motion blur.
+    pub fn apply_animation(&mut self, animation: &Animation, camera: &mut Camera, frame: f64) {
+        if let Some(position) = animation.camera_position.sample(frame) {
+            camera.position = position;
+            camera.end_position = animation.camera_position.sample(frame + 1.0).unwrap_or(position);
+        }
+        if let Some(rotation) = animation.camera_rotation.sample(frame) {
+            camera.rotation = rotation;
+            camera.end_rotation = animation.camera_rotation.sample(frame + 1.0);
+        }
+        if let Some(focal_length) = animation.camera_focal_length.sample(frame) {
+            camera.focal_length = focal_length;
+        }
+
+        for (rid, track) in animation.object_transforms() {
+            if let (Some(start), Some(end)) = (track.sample(frame), track.sample(frame + 1.0)) {
+                self.object_set_transform(*rid, start);
+                self.object_set_motion(*rid, Some(end));
+            }
+        }
+
+        for (rid, track) in animation.materials() {
+            if let Some(parameters) = track.sample(frame) {
+                self.material_set_principled(*rid, &parameters);
+            }
+        }
+    }
+
+
+    /// Renders the frames of an animation to numbered PPM files, without any terminal output.
+    ///
+    /// The run of `#` in `path_pattern` is replaced by the zero padded frame number, e.g. `out/frame_####.ppm`. The
+    /// frame number is appended to the file name if there is none.
+    pub fn render_sequence(
+        &mut self, animation: &Animation, camera: &mut Camera, frames: RangeInclusive<i64>,
+        width: usize, height: usize, path_pattern: &str
+    ) -> io::Result<()> {
+        for frame in frames {
+            self.apply_animation(animation, camera, frame as f64);
+            let film = self.render_film(camera, width, height);
+            film.write_ppm(Path::new(&Self::frame_path(path_pattern, frame)))?;
+        }
+        Ok(())
+    }
+
+
+    fn frame_path(path_pattern: &str, frame: i64) -> String {
+        let Some(start) = path_pattern.find('#') else {
+            return format!("{}{:04}.ppm", path_pattern, frame);
+        };
+        let width = path_pattern[start..].chars().take_while(|c| *c == '#').count();
+
+        format!("{}{:0width$}{}", &path_pattern[..start], frame, &path_pattern[start + width..], width = width)
+    }
+
+
+    /// Whether the selected integrator renders spectrally when `spectral` is set, the other ones rendering in RGB
+    pub fn spectral_supported(&self) -> bool {
+        matches!(self.integrator, Integrator::PathTracing | Integrator::Metropolis)
+    }
+
+
+    /// Renders the linear radiance of every pixel of a `width` x `height` image.
+    pub fn render_film(&mut self, camera: &Camera, width: usize, height: usize) -> Film {
+        self.update_light_tree();
+
+        if self.integrator == Integrator::Metropolis {
+            return self.render_metropolis(camera, width, height);
+        }
+
+        let aspect_ratio = width as f64 / height as f64;
+        let sample_weight = 1.0 / self.pixel_sample_count as f64;
+        let mut film = Film::new(width, height);
+
+        // Every pass takes one sample per pixel
+        for pass in 0..self.pixel_sample_count.max(0) as usize {
+            if self.integrator == Integrator::LightTracing {
+                self.trace_light_paths(camera, &film, sample_weight);
+                continue;
+            }
+
+            let photon_map = match self.integrator {
+                Integrator::PhotonMapping => Some(self.build_photon_map(pass)),
+                _ => None
+            };
+
+            for j in 0..height {
+                for i in 0..width {
+                    // Jittered within the pixel, which covers the same area of the image as the splats of the light
+                    // tracing and Metropolis integrators
+                    let (u, v) = ((i as f64 + random()) / width as f64, (j as f64 + random()) / height as f64);
+
+                    // Spread the samples over the shutter interval, the camera moving along
+                    let time = lerp(random(), camera.shutter_open, camera.shutter_close);
+                    let ray_direction = camera.direction(u, v, aspect_ratio, time);
+                    let ray = Ray::new(camera.position_at(time), ray_direction).with_time(time);
+
+                    let radiance = self.sample_radiance(&ray, photon_map.as_ref());
+                    film.add_sample(i, j, sample_weight * radiance);
+                }
+            }
+        }
+
+        film
+    }
+
+
+    /// Radiance estimate along a camera ray, using the selected integrator
+    fn sample_radiance(&self, ray: &Ray, photon_map: Option<&PhotonMap>) -> Vec3 {
+        match self.integrator {
+            Integrator::PathTracing => self.traced_radiance(ray),
+            Integrator::Bidirectional => self.bidirectional_radiance(ray),
+            Integrator::PhotonMapping => {
+                photon_map.map_or(Vec3::ZERO, |photon_map| self.photon_map_radiance(ray, photon_map))
+            },
+            Integrator::LightTracing | Integrator::Metropolis => Vec3::ZERO, // Paths are splatted instead
+            Integrator::Debug(view) => self.debug_radiance(ray, view)
+        }
+    }
+
+
+    /// Radiance along a camera ray, estimated by path tracing
+    fn traced_radiance(&self, ray: &Ray) -> Vec3 {
+        if self.spectral {
+            let wavelengths = Wavelengths::sample();
+            let radiance = self.ray_color(ray, 0, self.global_medium, Some(&wavelengths), None);
+            wavelengths.to_rgb(radiance)
+        } else {
+            self.ray_color(ray, 0, self.global_medium, None, None)
+        }
+    }
+
+
+    /// Random walk through the inside of the object `obj_rid`, filled with `interior`, starting with `ray` which has
+    /// just entered it. Returns the ray leaving the object with its weight and the radiance emitted on the way, the
+    /// weight being zero if the light was absorbed. `None` is only returned if the walk lost track of the object.
+    fn random_walk(&self, mut ray: Ray, obj_rid: &Rid, interior: &dyn Medium) -> Option<(Ray, Vec3, Vec3)> {
+        const MAX_WALK_STEPS: usize = 256;
+
+        let obj = self.objects.get(*obj_rid)?;
+        let interval = &Interval::new(0.0, f64::INFINITY);
+        let mut weight = vec3!(1.0, 1.0, 1.0);
+        let mut emitted = Vec3::ZERO;
+
+        for _ in 0..MAX_WALK_STEPS {
+            // A ray inside of a closed object always leaves it
+            let mut exit = self.hit_object(obj_rid, obj.as_ref(), &ray, interval)?;
+
+            match interior.sample(&ray, exit.distance) {
+                MediumEvent::Scatter { distance, weight: scatter_weight, emitted: scatter_emitted } => {
+                    emitted += mul_elem(weight, scatter_emitted);
+                    weight = mul_elem(weight, scatter_weight);
+                    ray = Ray::new(ray.at(distance), interior.phase().sample(ray.direction)).with_time(ray.time);
+                    continue;
+                },
+                MediumEvent::Pass { weight: pass_weight, emitted: pass_emitted } => {
+                    emitted += mul_elem(weight, pass_emitted);
+                    weight = mul_elem(weight, pass_weight);
+                },
+                MediumEvent::Absorb { emitted: absorb_emitted } => {
+                    return Some((ray, Vec3::ZERO, emitted + mul_elem(weight, absorb_emitted)));
+                }
+            }
+
+            // The walk reached the surface, where light either leaves or is reflected back inside
+            let mat = self.shade(&mut exit, obj_rid);
+            let Some(sample) = mat.sample(-ray.direction.normalized(), &exit) else {
+                return Some((ray, Vec3::ZERO, emitted));
+            };
+            weight = mul_elem(weight, sample.weight(exit.shading_normal));
+            ray = exit.spawn_ray(sample.wi).with_time(ray.time);
+
+            if sample.wi.dot(exit.outward_normal()) > 0.0 {
+                return Some((ray, weight, emitted));
+            }
+        }
+
+        Some((ray, Vec3::ZERO, emitted))
+    }
+
+
+    /// Estimate of the light reaching `point` at `time` directly from the lights. `spawn` creates the shadow ray
+    /// leaving the point towards a light along with the medium it travels through, and `scattering` weights the light
+    /// coming from a direction.
+    ///
+    /// Distant lights are all sampled, while a single light is picked from the light hierarchy. Emissive objects can
+    /// also be reached by scattered paths, so their light is weighted against `scattering_pdf`, the density with which
+    /// a direction is scattered. It is zero if scattered paths do not count the emission they find.
+    fn direct_lighting(
+        &self, point: Vec3, time: f64, spawn: impl Fn(Vec3) -> (Ray, Option<Rid>),
+        scattering: impl Fn(Vec3) -> Vec3, scattering_pdf: impl Fn(Vec3) -> f64
+    ) -> Vec3 {
+        let light_contribution = |sample: &LightSample| {
+            let f = scattering(sample.wi);
+            if f.length_sq() == 0.0 {
+                return Vec3::ZERO;
+            }
+
+            let (shadow_ray, medium) = spawn(sample.wi);
+            let transmittance = self.shadow_transmittance(&shadow_ray, sample.distance, medium);
+            mul_elem(mul_elem(f, sample.radiance), transmittance)
+        };
+
+        let mut radiance = Vec3::ZERO;
+        for light in self.light_tree.distant_lights() {
+            if let Some(sample) = light.sample(point) {
+                radiance += light_contribution(&sample);
+            }
+        }
+
+        match self.light_tree.sample(point) {
+            Some((LightSource::Light(light), probability)) => {
+                if let Some(sample) = light.sample(point) {
+                    radiance += light_contribution(&sample) / probability;
+                }
+            },
+            Some((LightSource::Area(area_light), probability)) => {
+                if let Some((sample, pdf)) = self.sample_area_light(area_light, point, time) {
+                    let weight = power_heuristic(probability * pdf, scattering_pdf(sample.wi));
+                    radiance += (weight / probability) * light_contribution(&sample);
+                }
+            },
+            None => ()
+        }
+
+        radiance
+    }
+
+
+    /// Picks a point on the surface of an emissive object, as seen from `point`. Returns the light arriving at `point`
+    /// divided by the density of the sample over solid angles, along with that density.
+    fn sample_area_light(&self, area_light: &AreaLight, point: Vec3, time: f64) -> Option<(LightSample, f64)> {
+        let (hit_info, material, pdf_area) = self.sample_area_light_surface(area_light, time)?;
+
+        let to_light = hit_info.position - point;
+        let distance = to_light.length();
+        let wi = to_light / distance;
+        let cos_light = -wi.dot(hit_info.normal);
+        if distance == 0.0 || cos_light <= 0.0 { // Only the outside of emissive surfaces emits light
+            return None;
+        }
+
+        let pdf = pdf_area * distance * distance / cos_light;
+        let radiance = material.emitted(-wi, &hit_info) / pdf;
+        Some((LightSample { wi: wi, distance: distance, radiance: radiance }, pdf))
+    }
+
+
+    /// Picks a point uniformly on the surface of the object of `area_light`. Returns the shaded point, its normal
+    /// pointing outside, with the material of the object and the density of the sample over the area of the surface.
+    fn sample_area_light_surface(&self, area_light: &AreaLight, time: f64) -> Option<(HitInfo, &dyn Material, f64)> {
+        let obj = self.objects.get(area_light.object)?;
+        let (position, normal) = obj.sample_surface(time)?;
+        let mut hit_info = surface_hit(obj.as_ref(), position, normal, time)?;
+        if let Some(object_to_world) = self.scene.world_transform_at(area_light.object, time) {
+            hit_info = object_to_world.hit_to_world(hit_info);
+        }
+
+        let pdf_area = self.surface_density(&hit_info, &area_light.object, time);
+        if pdf_area == 0.0 {
+            return None;
+        }
+        let material = self.shade(&mut hit_info, &area_light.object);
+        Some((hit_info, material, pdf_area))
+    }
+
+
+    /// Density over the area of the surface with which `sample_area_light_surface` picks the point `hit_info` of
+    /// the object `obj_rid`, zero for objects whose surface can not be sampled
+    fn surface_density(&self, hit_info: &HitInfo, obj_rid: &Rid, time: f64) -> f64 {
+        let Some(mut area) = self.objects.get(*obj_rid).and_then(|obj| obj.area()) else {
+            return 0.0;
+        };
+        if let Some(object_to_world) = self.scene.world_transform_at(*obj_rid, time) {
+            area *= object_to_world.area_scale(hit_info.normal);
+        }
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+
+    /// Density over solid angles with which `direct_lighting` samples the point `hit_info` of the object `obj_rid`,
+    /// as seen from `point`. Zero if the object is not sampled as a light.
+    fn area_light_pdf(&self, point: Vec3, hit_info: &HitInfo, obj_rid: &Rid, time: f64) -> f64 {
+        let probability = self.light_tree.area_light_probability(point, *obj_rid);
+        if probability == 0.0 {
+            return 0.0;
+        }
+
+        let to_light = hit_info.position - point;
+        let cos_light = to_light.normalized().dot(hit_info.normal).abs();
+        if cos_light == 0.0 {
+            return 0.0;
         }
+        probability * self.surface_density(hit_info, obj_rid, time) * to_light.length_sq() / cos_light
+    }
+
+
+    /// Fraction of light going along `ray` up to `distance`, starting in `medium`. The light goes through interfaces
+    /// and the media they bound, any other surface blocking it.
+    fn shadow_transmittance(&self, ray: &Ray, distance: f64, mut medium: Option<Rid>) -> Vec3 {
+        let mut transmittance = vec3!(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        let mut distance = distance;
+
+        loop {
+            let hit = self.closest_hit_within(&ray, &Interval::new(0.001, distance - 0.001));
+            let segment_end = hit.as_ref().map_or(distance, |(hit_info, _)| hit_info.distance);
+            if let Some(participating) = medium.and_then(|rid| self.media.get(rid)) {
+                transmittance = mul_elem(transmittance, participating.transmittance(&ray, segment_end));
+            }
+
+            let Some((hit_info, obj_rid)) = hit else {
+                return transmittance;
+            };
+            let is_interface = self.materials.get(self.hit_material(&hit_info, obj_rid))
+                .is_some_and(|mat| mat.is_interface());
+            if !is_interface {
+                return Vec3::ZERO;
+            }
 
-        let interval = &Interval::new(0.001, f64::INFINITY); // should be in rendering context or camera (far/near)
+            // The direction is kept as is so that ray parameters still measure the same distances
+            medium = self.medium_after(&hit_info, obj_rid, ray.direction, medium);
+            ray = hit_info.spawn_ray(ray.direction).with_time(ray.time);
+            distance -= hit_info.distance;
+        }
+    }
+
+
+    /// Closest surface hit by `ray`, with the object that was hit
+    fn closest_hit(&self, ray: &Ray) -> Option<(HitInfo, &Rid)> {
+        // should be in rendering context or camera (far/near)
+        self.closest_hit_within(ray, &Interval::new(0.001, f64::INFINITY))
+    }
+
+
+    /// Closest surface hit by `ray` within `interval`, with the object that was hit
+    fn closest_hit_within(&self, ray: &Ray, interval: &Interval) -> Option<(HitInfo, &Rid)> {
         let mut hit: Option<(HitInfo, &Rid)> = None;
 
-        // Process object hits
         for (rid, obj) in self.objects.rid_value_iter() {
-            if let Some(obj_hit) = (*obj).hit(ray, &interval) {
+            if let Some(obj_hit) = self.hit_object(rid, obj.as_ref(), ray, interval) {
                 if !interval.contains(obj_hit.distance) {
                     continue;
                 }
@@ -135,81 +1245,245 @@ impl CpuRenderingDevice {
                 }
             }
         }
-        
+
+        hit
+    }
+
+
+    /// Values carried by a path for an RGB quantity: the quantity itself, or its values at the path wavelengths when
+    /// rendering spectrally
+    fn path_values(rgb: Vec3, wavelengths: Option<&Wavelengths>) -> Vec3 {
+        match wavelengths {
+            Some(wavelengths) => wavelengths.upsample(rgb),
+            None => rgb
+        }
+    }
+
+
+    /// Radiance coming along `ray`, which travels through `medium`. When `wavelengths` are given, the radiance is
+    /// computed at each of them instead of for each RGB channel.
+    ///
+    /// `scattered` is the point the ray was scattered from with the density of its direction, used to weight the light
+    /// of the emissive objects it hits against `direct_lighting`. It is `None` for camera rays and specular bounces,
+    /// whose light can not be sampled from the lights.
+    fn ray_color(
+        &self, ray: &Ray, bounce_count: i64, medium: Option<Rid>, wavelengths: Option<&Wavelengths>,
+        scattered: Option<(Vec3, f64)>
+    ) -> Vec3 {
+        if bounce_count > self.max_light_bounce { // The light would not stop bouncing
+            return Vec3::ZERO;
+        }
+
+        let hit = self.closest_hit(ray);
+
+        // Sample free flight through the medium, before reaching the surface
+        let mut transmittance = vec3!(1.0, 1.0, 1.0);
+        let mut medium_emitted = Vec3::ZERO;
+        if let Some(participating) = medium.and_then(|rid| self.media.get(rid)) {
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |(hit_info, _)| hit_info.distance);
+
+            match participating.sample(ray, t_max) {
+                MediumEvent::Scatter { distance, weight, emitted } => {
+                    let point = ray.at(distance);
+                    let direction = ray.direction.normalized();
+                    let direct = self.direct_lighting(
+                        point,
+                        ray.time,
+                        |wi| (Ray::new(point, wi).with_time(ray.time), medium),
+                        |wi| participating.phase().eval(direction.dot(wi)) * vec3!(1.0, 1.0, 1.0),
+                        |wi| participating.phase().eval(direction.dot(wi))
+                    );
+
+                    let scatter_direction = participating.phase().sample(ray.direction);
+                    let scatter_ray = Ray::new(point, scatter_direction).with_time(ray.time);
+                    let scatter_pdf = participating.phase().eval(direction.dot(scatter_direction.normalized()));
+                    let in_scattered = self.ray_color(
+                        &scatter_ray, bounce_count + 1, medium, wavelengths, Some((point, scatter_pdf))
+                    );
+                    let incident = Self::path_values(direct, wavelengths) + in_scattered;
+                    return Self::path_values(emitted, wavelengths)
+                        + mul_elem(Self::path_values(weight, wavelengths), incident);
+                },
+                MediumEvent::Pass { weight, emitted } => {
+                    medium_emitted = Self::path_values(emitted, wavelengths);
+                    transmittance = Self::path_values(weight, wavelengths);
+                },
+                MediumEvent::Absorb { emitted } => return Self::path_values(emitted, wavelengths)
+            }
+        }
 
         // Process object material if there was a hit
-        if let Some((hit_info, obj_rid)) = hit {
-            let mat_rid = self.object_materials.get(obj_rid).unwrap();
-            let mat = self.materials.get(*mat_rid).or(self.materials.get(self.default_material)).unwrap();
-            let (attenuation, bounce_ray) = mat.scatter(ray, &hit_info);
+        if let Some((mut hit_info, obj_rid)) = hit {
+            hit_info.wavelength = wavelengths.map(Wavelengths::hero);
+            let mat = self.shade(&mut hit_info, obj_rid);
+            let wo = -ray.direction.normalized();
+            let mut emitted = Self::path_values(mat.emitted(wo, &hit_info), wavelengths);
+
+            // Emissive objects are also sampled by the direct lighting of the previous bounce
+            if let Some((origin, pdf)) = scattered.filter(|_| emitted.length_sq() > 0.0) {
+                let light_pdf = self.area_light_pdf(origin, &hit_info, obj_rid, ray.time);
+                emitted = power_heuristic(pdf, light_pdf) * emitted;
+            }
+
+            // Light arriving directly from the lights, which paths can not hit
+            if mat.flags(&hit_info).is_non_specular() {
+                let direct = self.direct_lighting(
+                    hit_info.position,
+                    ray.time,
+                    |wi| {
+                        let shadow_ray = hit_info.spawn_ray(wi).with_time(ray.time);
+                        (shadow_ray, self.medium_after(&hit_info, obj_rid, wi, medium))
+                    },
+                    |wi| wi.dot(hit_info.shading_normal).abs() * mat.eval(wi, wo, &hit_info),
+                    |wi| mat.pdf(wi, wo, &hit_info)
+                );
+                let mut direct = Self::path_values(direct, wavelengths);
+                if wavelengths.is_some() && mat.is_dispersive() {
+                    direct = Wavelengths::terminate_secondary(direct);
+                }
+                emitted += direct;
+            }
 
-            let env_contrib = self.ray_color(&bounce_ray, bounce_count + 1);
+            let Some(sample) = mat.sample(wo, &hit_info) else {
+                return medium_emitted + mul_elem(transmittance, emitted); // The path was absorbed
+            };
+            let mut attenuation = Self::path_values(sample.weight(hit_info.shading_normal), wavelengths);
+            let mut bounce_ray = hit_info.spawn_ray(sample.wi).with_time(ray.time);
+            let mut bounce_medium = self.medium_after(&hit_info, obj_rid, sample.wi, medium);
+            let mut bounce_scattered = if mat.is_interface() {
+                scattered // The ray goes on as if the interface was not there
+            } else if sample.is_specular() {
+                None
+            } else {
+                Some((hit_info.position, sample.pdf))
+            };
+
+            // The secondary wavelengths would have scattered in other directions
+            if wavelengths.is_some() && mat.is_dispersive() {
+                attenuation = Wavelengths::terminate_secondary(attenuation);
+            }
 
-            return vec3!(
-                attenuation.x * env_contrib.x,
-                attenuation.y * env_contrib.y,
-                attenuation.z * env_contrib.z
+            // Light refracted into a translucent object random walks inside of it
+            if let Some(interior) = mat.interior_medium().filter(|_| sample.wi.dot(hit_info.outward_normal()) < 0.0) {
+                let Some((exit_ray, walk_weight, walk_emitted)) = self.random_walk(bounce_ray, obj_rid, interior) else {
+                    return medium_emitted + mul_elem(transmittance, emitted);
+                };
+                emitted += mul_elem(attenuation, Self::path_values(walk_emitted, wavelengths));
+                if walk_weight.length_sq() == 0.0 { // Absorbed inside of the object
+                    return medium_emitted + mul_elem(transmittance, emitted);
+                }
+                attenuation = mul_elem(attenuation, Self::path_values(walk_weight, wavelengths));
+                bounce_ray = exit_ray;
+                bounce_medium = medium;
+                bounce_scattered = None;
+            }
+
+            let env_contrib = self.ray_color(
+                &bounce_ray, bounce_count + 1, bounce_medium, wavelengths, bounce_scattered
             );
+
+            return medium_emitted + mul_elem(transmittance, emitted + mul_elem(attenuation, env_contrib));
         }
-    
-        // Get sky color
+
+        medium_emitted + mul_elem(transmittance, Self::path_values(self.sky_color(ray), wavelengths))
+    }
+
+
+    /// Sets the colour temperature of the sun in the sky, in kelvins
+    pub fn set_sun_temperature(&mut self, temperature: f64) {
+        self.sun_temperature = temperature;
+        self.sun_color = blackbody_rgb(temperature);
+    }
+
+
+    pub fn sun_temperature(&self) -> f64 {
+        self.sun_temperature
+    }
+
+
+    fn sky_color(&self, ray: &Ray) -> Vec3 {
         let ray_dir = ray.direction.normalized();
         let sun_dir = vec3!(0.6, 0.6, 0.35).normalized();
 
         if ray_dir.dot(sun_dir) > (TAU/25.0).cos() {
-            return 1.8 * vec3!(0.95, 0.9, 0.6);
+            Self::SUN_LUMINANCE * self.sun_color
         } else {
             let a = 0.5 * (ray_dir.y + 1.0);
-            return 0.32 * (a * vec3!(0.5, 0.7, 1.0) + (1.0 - a) * vec3!(1.0, 1.0, 1.0));
+            0.32 * (a * vec3!(0.5, 0.7, 1.0) + (1.0 - a) * vec3!(1.0, 1.0, 1.0))
         }
     }
 }
 
 
 impl PTRenderer for CpuRenderingDevice {
-    fn render(&self, camera: &Camera, target: &mut Image) {
+    fn render(&mut self, camera: &Camera, target: &mut Image) {
         let size = target.size();
-        let aspect_ratio: f64 = size.x as f64 / size.y as f64;
-    
-        let mut viewport_size = vec2!(0.0, 2.0);
-        viewport_size.x = viewport_size.y * aspect_ratio;
-    
-        let viewport_u = vec3!(viewport_size.x, 0.0, 0.0);
-        let viewport_v = vec3!(0.0, -viewport_size.y, 0.0);
-    
-        let pixel_delta_u = viewport_u / size.x as f64;
-        let pixel_delta_v = viewport_v / size.y as f64;
-    
-        let pixel_top_left = camera.position
-            - vec3!(0.0, 0.0, camera.focal_length) - 0.5 * (viewport_u + viewport_v);
-    
-    
+        let film = self.render_film(camera, size.x as usize, size.y as usize);
+
         for j in 0..size.y {
             for i in 0..size.x {
-                // Get pixel ray
-                let pixel_source = pixel_top_left + (i as f64 * pixel_delta_u) + (j as f64 * pixel_delta_v);
-                let ray_direction = pixel_source - camera.position;
-    
-                let ray = Ray::new(camera.position, ray_direction);
+                let pixel_color = film.display_pixel(i as usize, j as usize);
+                target.point((i, j), Color::raw_vec3_rgb(pixel_color));
+            }
+        }
+    }
 
-                // Sample pixel color
-                let mut pixel_color = Vec3::ZERO;
 
-                for _sample in 0..self.pixel_sample_count {
-                    pixel_color += self.ray_color(&ray, 0);
-                }
+    fn set_integrator(&mut self, integrator: Integrator) {
+        self.integrator = integrator;
+    }
+}
 
-                pixel_color /= self.pixel_sample_count as f64;
 
-                // Apply gamma correction
-                pixel_color.x = pixel_color.x.sqrt().clamp(0.0, 1.0);
-                pixel_color.y = pixel_color.y.sqrt().clamp(0.0, 1.0);
-                pixel_color.z = pixel_color.z.sqrt().clamp(0.0, 1.0);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                // Write pixel
-                target.point((i, j), Color::raw_vec3_rgb(pixel_color));
+
+    #[test]
+    fn frame_path_pads_the_frame_number() {
+        assert_eq!(CpuRenderingDevice::frame_path("out/frame_####.ppm", 7), "out/frame_0007.ppm");
+        assert_eq!(CpuRenderingDevice::frame_path("out/##_frame.ppm", 5), "out/05_frame.ppm");
+        assert_eq!(CpuRenderingDevice::frame_path("out/frame_##.ppm", 123), "out/frame_123.ppm");
+    }
+
+
+    #[test]
+    fn frame_path_appends_the_frame_number() {
+        assert_eq!(CpuRenderingDevice::frame_path("out/frame_", 42), "out/frame_0042.ppm");
+    }
+
+
+    /// Mean radiance of a film
+    fn mean_radiance(film: &Film) -> Vec3 {
+        let mut sum = Vec3::ZERO;
+        for j in 0..film.height() {
+            for i in 0..film.width() {
+                sum += film.pixel(i, j);
             }
         }
+        (1.0 / (film.width() * film.height()) as f64) * sum
     }
-}
 
+
+    #[test]
+    fn light_tracing_matches_path_tracing() {
+        // Diffuse wall filling the view, lit by a point light in front of it
+        let mut device = CpuRenderingDevice::new(1, 64);
+        let wall = device.create_quad(vec3!(-5.0, -5.0, -3.0), vec3!(10.0, 0.0, 0.0), vec3!(0.0, 10.0, 0.0));
+        let diffuse = device.create_lambertial_material(Color::raw_rgb(0.5, 0.5, 0.5));
+        device.object_set_material(wall, diffuse);
+        device.create_point_light(vec3!(0.5, 0.5, -1.0), Color::raw_rgb(1.0, 1.0, 1.0), 200.0, 2.0);
+        let camera = Camera::new(Vec3::ZERO, 1.0);
+
+        device.set_integrator(Integrator::PathTracing);
+        let path_traced = luminance(mean_radiance(&device.render_film(&camera, 16, 16)));
+        device.set_integrator(Integrator::LightTracing);
+        let light_traced = luminance(mean_radiance(&device.render_film(&camera, 16, 16)));
+
+        assert!(
+            (light_traced - path_traced).abs() < 0.05 * path_traced,
+            "light tracing {}, path tracing {}", light_traced, path_traced
+        );
+    }
+}
@@ -16,36 +16,128 @@ OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
 */
 
 
-use simple_term_renderer::math::Vec3;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use simple_term_renderer::math::{Vec2, Vec3};
+use simple_term_renderer::vec2;
 
 use crate::path_tracer::{Ray, HitInfo};
 use crate::path_tracer::math::*;
+use crate::path_tracer::sampler::random;
+
+
+/// Bounds the number of crossings gathered by `Object::hit_all`
+const MAX_CROSSINGS: usize = 64;
+
+/// Relative distance skipped after a crossing, so that it is not found again
+const CROSSING_EPSILON: f64 = 1e-9;
+
+
+/// Thickness given to the bounding boxes of flat shapes
+const FLAT_PADDING: f64 = 1e-6;
+
+/// Distance from which `surface_hit` casts its ray towards the surface
+const PROBE_OFFSET: f64 = 1e-4;
 
 
 pub trait Object {
     fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo>;
+
+
+    /// Object space box containing the object over the whole frame, `None` if it is unbounded or unknown
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+
+    /// Object space area of the surface, `None` if points of the surface can not be sampled
+    fn area(&self) -> Option<f64> {
+        None
+    }
+
+
+    /// Point picked uniformly over the surface at `time`, with the normal pointing out of the surface there, in
+    /// object space. Implemented along with `area`, so that emissive objects can be sampled as lights.
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        None
+    }
+
+
+    /// Every crossing of the surface within `interval`, in increasing distance.
+    /// Used by CSG, which needs to know where the ray enters and leaves each operand.
+    fn hit_all(&self, ray: &Ray, interval: &Interval) -> Vec<HitInfo> {
+        let mut hits = Vec::new();
+        let mut start = interval.start();
+
+        while hits.len() < MAX_CROSSINGS {
+            let Some(hit) = self.hit(ray, &Interval::new(start, interval.end())) else {
+                break;
+            };
+            start = hit.distance + CROSSING_EPSILON * hit.distance.abs().max(1.0);
+            hits.push(hit);
+        }
+
+        hits
+    }
 }
 
 
 pub struct Sphere {
     position: Vec3,
+    /// Position at the end of the frame
+    end_position: Vec3,
     radius: f64
 }
 
 
 impl Sphere {
     pub fn new(position: Vec3, radius: f64) -> Self {
+        Self::moving(position, position, radius)
+    }
+
+
+    /// Creates a sphere moving from `start` to `end` during the frame
+    pub fn moving(start: Vec3, end: Vec3, radius: f64) -> Self {
         Self {
-            position,
+            position: start,
+            end_position: end,
             radius: radius
         }
     }
+
+
+    fn position_at(&self, time: f64) -> Vec3 {
+        lerp_vec(time, self.position, self.end_position)
+    }
+
+
+    /// Spherical coordinates of a point of the unit sphere, `u` going around the `y` axis and `v` from bottom to top
+    fn uv(point: Vec3) -> Vec2 {
+        let theta = (-point.y).clamp(-1.0, 1.0).acos();
+        let phi = (-point.z).atan2(point.x) + PI;
+        vec2!(phi / (2.0 * PI), theta / PI)
+    }
+
+
+    /// Partial derivatives of the surface with respect to `uv`, at the point of normal `normal`
+    fn tangents(&self, normal: Vec3) -> (Vec3, Vec3) {
+        let sin_theta = (1.0 - normal.y * normal.y).max(1e-8).sqrt();
+        let dpdu = 2.0 * PI * self.radius * Vec3::new(normal.z, 0.0, -normal.x);
+        let dpdv = PI * self.radius * Vec3::new(
+            -normal.x * normal.y / sin_theta,
+            sin_theta,
+            -normal.y * normal.z / sin_theta
+        );
+        (dpdu, dpdv)
+    }
 }
 
 
 impl Object for Sphere {
     fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
-        let vec_oc = self.position - ray.origin;
+        let position = self.position_at(ray.time);
+        let vec_oc = position - ray.origin;
 
         let a = ray.direction.length_sq();
         let h = ray.direction.dot(vec_oc);
@@ -67,27 +159,55 @@ impl Object for Sphere {
         }
 
 
-        let surface_normal = (ray.at(root) - self.position).normalized();
+        let surface_normal = (ray.at(root) - position).normalized();
+        let uv = Self::uv(surface_normal);
+        let (dpdu, dpdv) = self.tangents(surface_normal);
         if surface_normal.dot(ray.direction) < 0.0 { // The ray comes from outside the sphere
             Some(HitInfo::front_face(
                 root,
                 ray.at(root),
-                surface_normal
+                surface_normal,
+                uv,
+                dpdu,
+                dpdv
             ))
         } else {
             Some(HitInfo::back_face(
                 root,
                 ray.at(root),
-                surface_normal
+                surface_normal,
+                uv,
+                dpdu,
+                dpdv
             ))
         }
     }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        let start = Aabb::new(self.position - radius, self.position + radius);
+        let end = Aabb::new(self.end_position - radius, self.end_position + radius);
+        Some(start.union(&end))
+    }
+
+
+    fn area(&self) -> Option<f64> {
+        Some(4.0 * PI * self.radius * self.radius)
+    }
+
+
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        let normal = sample_uniform_sphere();
+        Some((self.position_at(time) + self.radius * normal, normal))
+    }
 }
 
 
 pub struct Plane {
     position: Vec3,
-    normal: Vec3
+    normal: Vec3,
+    frame: Frame
 }
 
 
@@ -96,9 +216,17 @@ impl Plane {
     pub fn new(position: Vec3, normal: Vec3) -> Self {
         Self {
             position: position,
-            normal: normal
+            normal: normal,
+            frame: Frame::from_normal(normal.normalized())
         }
     }
+
+
+    /// Coordinates of a point in the plane's tangent basis, one UV unit being one world unit
+    fn uv(&self, point: Vec3) -> Vec2 {
+        let local = self.frame.to_local(point - self.position);
+        vec2!(local.x, local.y)
+    }
 }
 
 
@@ -118,10 +246,452 @@ impl Object for Plane {
             return None;
         }
 
+        let position = ray.at(t);
+        let (dpdu, dpdv) = (self.frame.x, self.frame.y);
         if a < 0.0 { // The ray comes from above the plane
-            Some(HitInfo::front_face(t, ray.at(t), self.normal))
+            Some(HitInfo::front_face(t, position, self.normal, self.uv(position), dpdu, dpdv))
+        } else {
+            Some(HitInfo::back_face(t, position, self.normal, self.uv(position), dpdu, dpdv))
+        }
+    }
+}
+
+
+/// Hit of `object` at the point `position` of its surface, whose outward normal is `normal`, found by casting a ray
+/// towards it from just outside of the surface. Gives sampled points of a surface the UVs and tangents of its hits.
+pub fn surface_hit(object: &dyn Object, position: Vec3, normal: Vec3, time: f64) -> Option<HitInfo> {
+    let ray = Ray::new(position + PROBE_OFFSET * normal, -normal).with_time(time);
+    object.hit(&ray, &Interval::new(0.0, 2.0 * PROBE_OFFSET))
+}
+
+
+/// Builds the hit of a surface of outward normal `out_normal`, on the side facing the ray
+pub fn oriented_hit(ray: &Ray, t: f64, out_normal: Vec3, uv: Vec2, dpdu: Vec3, dpdv: Vec3) -> HitInfo {
+    if out_normal.dot(ray.direction) < 0.0 {
+        HitInfo::front_face(t, ray.at(t), out_normal, uv, dpdu, dpdv)
+    } else {
+        HitInfo::back_face(t, ray.at(t), out_normal, uv, dpdu, dpdv)
+    }
+}
+
+
+/// Parallelogram spanned by `u` and `v` from `corner`, facing towards `u x v`
+pub struct Quad {
+    corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    w: Vec3
+}
+
+
+impl Quad {
+
+    pub fn new(corner: Vec3, u: Vec3, v: Vec3) -> Self {
+        let n = u.cross(v);
+        Self {
+            corner: corner,
+            u: u,
+            v: v,
+            normal: n.normalized(),
+            w: n / n.length_sq()
+        }
+    }
+}
+
+
+impl Object for Quad {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let denom = self.normal.dot(ray.direction);
+        if denom.abs() < 1e-12 {
+            return None; // The ray is parallel to the quad
+        }
+
+        let t = self.normal.dot(self.corner - ray.origin) / denom;
+        if !interval.surrounds(t) {
+            return None;
+        }
+
+        // Coordinates of the hit point in the (u, v) basis
+        let planar = ray.at(t) - self.corner;
+        let alpha = self.w.dot(planar.cross(self.v));
+        let beta = self.w.dot(self.u.cross(planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        Some(oriented_hit(ray, t, self.normal, vec2!(alpha, beta), self.u, self.v))
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let corners = [self.corner, self.corner + self.u, self.corner + self.v, self.corner + self.u + self.v];
+        Some(Aabb::from_points(&corners).padded(FLAT_PADDING))
+    }
+
+
+    fn area(&self) -> Option<f64> {
+        Some(self.u.cross(self.v).length())
+    }
+
+
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        Some((self.corner + random() * self.u + random() * self.v, self.normal))
+    }
+}
+
+
+/// Disk of center `center` facing towards `normal`
+pub struct Disk {
+    center: Vec3,
+    radius: f64,
+    frame: Frame
+}
+
+
+impl Disk {
+
+    pub fn new(center: Vec3, normal: Vec3, radius: f64) -> Self {
+        Self {
+            center: center,
+            radius: radius,
+            frame: Frame::from_normal(normal.normalized())
+        }
+    }
+}
+
+
+impl Object for Disk {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let normal = self.frame.z;
+        let denom = normal.dot(ray.direction);
+        if denom.abs() < 1e-12 {
+            return None; // The ray is parallel to the disk
+        }
+
+        let t = normal.dot(self.center - ray.origin) / denom;
+        if !interval.surrounds(t) {
+            return None;
+        }
+
+        let local = self.frame.to_local(ray.at(t) - self.center);
+        let distance = (local.x * local.x + local.y * local.y).sqrt();
+        if distance > self.radius {
+            return None;
+        }
+
+        // Polar coordinates, `v` going from the rim to the center
+        let phi = local.y.atan2(local.x).rem_euclid(2.0 * PI);
+        let uv = vec2!(phi / (2.0 * PI), 1.0 - distance / self.radius);
+
+        let dpdu = 2.0 * PI * (-local.y * self.frame.x + local.x * self.frame.y);
+        let dpdv = -self.radius * (phi.cos() * self.frame.x + phi.sin() * self.frame.y);
+
+        Some(oriented_hit(ray, t, normal, uv, dpdu, dpdv))
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let n = self.frame.z;
+        let extent = self.radius * Vec3::new(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt()
+        );
+        Some(Aabb::new(self.center - extent, self.center + extent).padded(FLAT_PADDING))
+    }
+
+
+    fn area(&self) -> Option<f64> {
+        Some(PI * self.radius * self.radius)
+    }
+
+
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        let (x, y) = sample_uniform_disk_polar((random(), random()));
+        let position = self.center + self.radius * (x * self.frame.x + y * self.frame.y);
+        Some((position, self.frame.z))
+    }
+}
+
+
+/// Box whose faces are aligned with the axes of its object space. Each face is mapped to the whole UV square.
+///
+/// Flat boxes are allowed, their null extents being clamped when dividing by them.
+pub struct AxisAlignedBox {
+    bounds: Aabb
+}
+
+
+impl AxisAlignedBox {
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self {
+            bounds: Aabb::new(min, max)
+        }
+    }
+}
+
+
+impl Object for AxisAlignedBox {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let (t_near, t_far) = self.bounds.hit_range(ray.origin, ray.direction)?;
+
+        let t = if interval.surrounds(t_near) {
+            t_near
+        } else if interval.surrounds(t_far) {
+            t_far // The ray starts inside the box
         } else {
-            Some(HitInfo::back_face(t, ray.at(t), self.normal))
+            return None;
+        };
+
+        // Find the face that was hit: the one the point is the closest to
+        let position = ray.at(t);
+        let extent = self.bounds.extent();
+        let mut face = (0, 1.0);
+        let mut closest = f64::INFINITY;
+        for axis in 0..3 {
+            let size = axis_component(extent, axis).max(f64::EPSILON);
+            let to_min = (axis_component(position, axis) - axis_component(self.bounds.min, axis)).abs() / size;
+            let to_max = (axis_component(self.bounds.max, axis) - axis_component(position, axis)).abs() / size;
+            if to_min < closest {
+                closest = to_min;
+                face = (axis, -1.0);
+            }
+            if to_max < closest {
+                closest = to_max;
+                face = (axis, 1.0);
+            }
+        }
+
+        // The tangent axes are chosen so that `dpdu x dpdv` is the outward normal
+        let (axis, sign) = face;
+        let (axis_u, axis_v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let size_u = axis_component(extent, axis_u).max(f64::EPSILON);
+        let size_v = axis_component(extent, axis_v).max(f64::EPSILON);
+
+        let local = position - self.bounds.min;
+        let mut u = axis_component(local, axis_u) / size_u;
+        let v = axis_component(local, axis_v) / size_v;
+        let mut dpdu = size_u * axis_vec(axis_u);
+        if sign < 0.0 {
+            u = 1.0 - u;
+            dpdu = -dpdu;
+        }
+        let dpdv = size_v * axis_vec(axis_v);
+
+        Some(oriented_hit(ray, t, sign * axis_vec(axis), vec2!(u, v), dpdu, dpdv))
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+
+/// Triangle facing towards `(b - a) x (c - a)`
+pub struct Triangle {
+    vertices: [Vec3; 3],
+    uvs: [Vec2; 3]
+}
+
+
+impl Triangle {
+
+    /// Creates a triangle whose vertices are mapped to the UV coordinates (0, 0), (1, 0) and (1, 1)
+    pub fn new(a: Vec3, b: Vec3, c: Vec3) -> Self {
+        Self::with_uvs([a, b, c], [vec2!(0.0, 0.0), vec2!(1.0, 0.0), vec2!(1.0, 1.0)])
+    }
+
+
+    pub fn with_uvs(vertices: [Vec3; 3], uvs: [Vec2; 3]) -> Self {
+        Self {
+            vertices: vertices,
+            uvs: uvs
+        }
+    }
+
+
+    /// Partial derivatives of the position with respect to the UV coordinates
+    fn tangents(&self, normal: Vec3) -> (Vec3, Vec3) {
+        let [p0, p1, p2] = self.vertices;
+        let [uv0, uv1, uv2] = &self.uvs;
+
+        let (du02, dv02) = (uv0.x - uv2.x, uv0.y - uv2.y);
+        let (du12, dv12) = (uv1.x - uv2.x, uv1.y - uv2.y);
+        let (dp02, dp12) = (p0 - p2, p1 - p2);
+
+        let determinant = du02 * dv12 - dv02 * du12;
+        if determinant.abs() < 1e-12 { // Degenerate UVs, use any tangent frame
+            let frame = Frame::from_normal(normal);
+            return (frame.x, frame.y);
+        }
+
+        let inv_det = 1.0 / determinant;
+        (inv_det * (dv12 * dp02 - dv02 * dp12), inv_det * (du02 * dp12 - du12 * dp02))
+    }
+}
+
+
+impl Object for Triangle {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        // Möller-Trumbore intersection
+        let [p0, p1, p2] = self.vertices;
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+
+        let p = ray.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < 1e-12 {
+            return None; // The ray is parallel to the triangle
+        }
+        let inv_det = 1.0 / determinant;
+
+        let s = ray.origin - p0;
+        let b1 = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let b2 = ray.direction.dot(q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(q) * inv_det;
+        if !interval.surrounds(t) {
+            return None;
+        }
+
+        let b0 = 1.0 - b1 - b2;
+        let [uv0, uv1, uv2] = &self.uvs;
+        let uv = vec2!(
+            b0 * uv0.x + b1 * uv1.x + b2 * uv2.x,
+            b0 * uv0.y + b1 * uv1.y + b2 * uv2.y
+        );
+
+        let normal = edge1.cross(edge2).normalized();
+        let (dpdu, dpdv) = self.tangents(normal);
+        let mut hit = oriented_hit(ray, t, normal, uv, dpdu, dpdv);
+        hit.barycentrics = Some(Vec3::new(b0, b1, b2));
+        Some(hit)
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&self.vertices).padded(FLAT_PADDING))
+    }
+
+
+    fn area(&self) -> Option<f64> {
+        let [p0, p1, p2] = self.vertices;
+        Some(0.5 * (p1 - p0).cross(p2 - p0).length())
+    }
+
+
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        let [p0, p1, p2] = self.vertices;
+        let (sqrt_u, v) = (random().sqrt(), random());
+        let (b1, b2) = (sqrt_u * (1.0 - v), sqrt_u * v);
+        let position = p0 + b1 * (p1 - p0) + b2 * (p2 - p0);
+        Some((position, (p1 - p0).cross(p2 - p0).normalized()))
+    }
+}
+
+
+/// Object sharing the geometry of another one, usually placed with its own transform
+pub struct Instance {
+    geometry: Arc<dyn Object>
+}
+
+
+impl Instance {
+
+    pub fn new(geometry: Arc<dyn Object>) -> Self {
+        Self {
+            geometry: geometry
+        }
+    }
+}
+
+
+impl Object for Instance {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        self.geometry.hit(ray, interval)
+    }
+
+
+    fn hit_all(&self, ray: &Ray, interval: &Interval) -> Vec<HitInfo> {
+        self.geometry.hit_all(ray, interval)
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.geometry.bounding_box()
+    }
+
+
+    fn area(&self) -> Option<f64> {
+        self.geometry.area()
+    }
+
+
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        self.geometry.sample_surface(time)
+    }
+}
+
+
+
+/// Scene graph node without geometry, only used to transform its children
+pub struct Group;
+
+
+impl Object for Group {
+    fn hit(&self, _ray: &Ray, _interval: &Interval) -> Option<HitInfo> {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn surface_samples_are_hit() {
+        let objects: Vec<Box<dyn Object>> = vec![
+            Box::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.5)),
+            Box::new(Quad::new(Vec3::ZERO, Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0))),
+            Box::new(Disk::new(Vec3::ZERO, Vec3::new(0.0, 1.0, 1.0).normalized(), 1.5)),
+            Box::new(Triangle::new(Vec3::ZERO, Vec3::new(3.0, 0.0, 0.0), Vec3::new(0.5, 2.0, -1.0)))
+        ];
+
+        for object in &objects {
+            for _ in 0..1000 {
+                let (position, normal) = object.sample_surface(0.0).unwrap();
+                let hit = surface_hit(object.as_ref(), position, normal, 0.0);
+                assert!(hit.is_some_and(|hit| hit.front_face), "sample {:?} missed its surface", position);
+            }
+        }
+    }
+
+
+    #[test]
+    fn triangle_samples_are_uniform() {
+        let (a, b, c) = (Vec3::ZERO, Vec3::new(3.0, 0.0, 0.0), Vec3::new(0.5, 2.0, -1.0));
+        let triangle = Triangle::new(a, b, c);
+
+        // The mean of uniform samples is the centroid
+        let count = 20000;
+        let mut sum = Vec3::ZERO;
+        for _ in 0..count {
+            sum += triangle.sample_surface(0.0).unwrap().0;
         }
+        let error = (1.0 / count as f64) * sum - (1.0 / 3.0) * (a + b + c);
+        assert!(error.length() < 0.03, "mean off the centroid by {:?}", error);
     }
 }
@@ -0,0 +1,101 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use simple_term_renderer::math::Vec3;
+
+use crate::{Camera, HitInfo, Ray};
+use crate::path_tracer::sampler::random;
+
+use super::{lerp, mul_elem, CpuRenderingDevice, Film};
+use super::mat::Material;
+
+
+impl CpuRenderingDevice {
+
+    /// Shoots as many paths from the lights and the sky as `film` has pixels, splatting their contributions weighted
+    /// by `weight`.
+    ///
+    /// Paths are connected to the camera at every diffuse or glossy surface they reach, so only light having reached
+    /// a surface is seen: lights, emissive surfaces and the sky do not appear directly. Participating media are
+    /// ignored.
+    pub(super) fn trace_light_paths(&self, camera: &Camera, film: &Film, weight: f64) {
+        let scene_bounds = self.scene_bounding_sphere();
+
+        for _ in 0..film.width() * film.height() {
+            let time = lerp(random(), camera.shutter_open, camera.shutter_close);
+            let Some((ray, power, _)) = self.emit_photon(scene_bounds, time) else {
+                continue;
+            };
+            self.trace_light_path(ray, weight * power, camera, film);
+        }
+    }
+
+
+    fn trace_light_path(&self, mut ray: Ray, mut power: Vec3, camera: &Camera, film: &Film) {
+        for _ in 0..=self.max_light_bounce {
+            let Some((mut hit, obj_rid)) = self.closest_hit(&ray) else {
+                return;
+            };
+
+            let material = self.shade(&mut hit, obj_rid);
+            if material.flags(&hit).is_non_specular() {
+                self.splat_to_camera(&hit, material, &ray, power, camera, film);
+            }
+
+            let Some(sample) = material.sample(-ray.direction.normalized(), &hit) else {
+                return;
+            };
+            power = mul_elem(power, sample.weight(hit.shading_normal));
+            if power.length_sq() == 0.0 {
+                return;
+            }
+            ray = hit.spawn_ray(sample.wi).with_time(ray.time);
+        }
+    }
+
+
+    /// Splats the light scattered towards the camera by a surface, lit by `power` arriving along `ray`
+    fn splat_to_camera(
+        &self, hit: &HitInfo, material: &dyn Material, ray: &Ray, power: Vec3, camera: &Camera, film: &Film
+    ) {
+        let (wi, time) = (-ray.direction.normalized(), ray.time);
+        let aspect_ratio = film.width() as f64 / film.height() as f64;
+        let Some((u, v)) = camera.project(hit.position, aspect_ratio, time) else {
+            return;
+        };
+
+        let offset = camera.position_at(time) - hit.position;
+        let distance = offset.length();
+        let wo = offset / distance;
+
+        let f = wo.dot(hit.shading_normal).abs() * material.eval(wi, wo, hit);
+        if f.length_sq() == 0.0 {
+            return;
+        }
+        let shadow_ray = hit.spawn_ray(wo).with_time(time);
+        let transmittance = self.shadow_transmittance(&shadow_ray, distance, None);
+
+        // The importance of a pixel is the one of the camera scaled by the number of pixels, as it measures the
+        // average radiance over a fraction of the image. Shooting one path per pixel divides it back.
+        let cos_camera = (-wo).dot(camera.forward(time));
+        let importance = camera.importance(-wo, aspect_ratio, time) * cos_camera / (distance * distance);
+
+        let (x, y) = ((u * film.width() as f64) as usize, (v * film.height() as f64) as usize);
+        film.add_splat(x, y, importance * mul_elem(mul_elem(f, power), transmittance));
+    }
+}
@@ -0,0 +1,416 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::f64::consts::PI;
+
+use simple_term_renderer::math::Vec3;
+use simple_term_renderer::vec3;
+
+use crate::Ray;
+use crate::path_tracer::sampler::random;
+
+use super::{luminance, mul_elem, sample_cosine_hemisphere, sample_uniform_disk_polar, sample_uniform_sphere};
+use super::{Aabb, CpuRenderingDevice, Frame};
+use super::light::{Light, LightSource};
+
+
+/// Light flux stored where it landed on a surface
+struct Photon {
+    position: Vec3,
+    /// Direction the photon came from
+    wi: Vec3,
+    power: Vec3
+}
+
+
+/// Photons balanced in a kd-tree, for density estimation within a fixed radius
+pub struct PhotonMap {
+    /// Implicit tree: the node of a range is its middle photon, its children the ranges on each side
+    photons: Vec<Photon>,
+    /// Splitting axis of each node
+    axes: Vec<u8>,
+    radius: f64
+}
+
+
+impl PhotonMap {
+
+    fn new(mut photons: Vec<Photon>, radius: f64) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+
+        Self {
+            photons: photons,
+            axes: axes,
+            radius: radius
+        }
+    }
+
+
+    /// Splits the range around the median along its widest axis, then the two sides
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.len() <= 1 {
+            return;
+        }
+
+        let positions: Vec<Vec3> = photons.iter().map(|photon| photon.position).collect();
+        let extent = Aabb::from_points(&positions).extent();
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let middle = photons.len() / 2;
+        photons.select_nth_unstable_by(middle, |a, b| {
+            coordinate(a.position, axis).total_cmp(&coordinate(b.position, axis))
+        });
+        axes[middle] = axis;
+
+        let (photons_left, photons_right) = photons.split_at_mut(middle);
+        let (axes_left, axes_right) = axes.split_at_mut(middle);
+        Self::build(photons_left, axes_left);
+        Self::build(&mut photons_right[1..], &mut axes_right[1..]);
+    }
+
+
+    /// Calls `f` on every photon within the gather radius of `point`
+    fn for_each_near(&self, point: Vec3, f: &mut impl FnMut(&Photon)) {
+        self.visit(0, self.photons.len(), point, f);
+    }
+
+
+    fn visit(&self, start: usize, end: usize, point: Vec3, f: &mut impl FnMut(&Photon)) {
+        if start >= end {
+            return;
+        }
+
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        if (photon.position - point).length_sq() <= self.radius * self.radius {
+            f(photon);
+        }
+
+        let axis = self.axes[middle];
+        let offset = coordinate(point, axis) - coordinate(photon.position, axis);
+        let (near, far) = if offset < 0.0 {
+            ((start, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (start, middle))
+        };
+
+        self.visit(near.0, near.1, point, f);
+        if offset * offset <= self.radius * self.radius {
+            self.visit(far.0, far.1, point, f);
+        }
+    }
+}
+
+
+fn coordinate(vec: Vec3, axis: u8) -> f64 {
+    match axis {
+        0 => vec.x,
+        1 => vec.y,
+        _ => vec.z
+    }
+}
+
+
+impl CpuRenderingDevice {
+
+    /// Shoots `photon_count` photons from the lights and the sky, storing them where they land on diffuse or glossy
+    /// surfaces. Photons of the lights are only stored after at least one bounce, their direct lighting being left to
+    /// next event estimation, which does not sample the sky.
+    ///
+    /// The gather radius shrinks with every `pass` (Knaus and Zwicker, "Progressive Photon Mapping: A Probabilistic
+    /// Approach"), so that averaging the passes converges to the exact solution.
+    pub(super) fn build_photon_map(&self, pass: usize) -> PhotonMap {
+        const ALPHA: f64 = 2.0 / 3.0;
+        // The gathered flux is divided by the area of the disk of the radius, which must not be zero
+        const MIN_RADIUS: f64 = 1e-6;
+
+        let radius = self.photon_radius.max(MIN_RADIUS);
+        let mut radius_sq = radius * radius;
+        for i in 1..=pass {
+            radius_sq *= (i as f64 + ALPHA) / (i as f64 + 1.0);
+        }
+
+        let scene_bounds = self.scene_bounding_sphere();
+        let mut photons = Vec::new();
+
+        for _ in 0..self.photon_count {
+            let Some((ray, power, sampled_directly)) = self.emit_photon(scene_bounds, random()) else {
+                continue;
+            };
+            let power = (1.0 / self.photon_count as f64) * power;
+            self.trace_photon(ray, power, !sampled_directly, &mut photons);
+        }
+
+        PhotonMap::new(photons, radius_sq.sqrt())
+    }
+
+
+    /// Center and radius of a sphere containing the bounded objects, used to shoot photons from distant lights
+    /// and the sky
+    pub(super) fn scene_bounding_sphere(&self) -> Option<(Vec3, f64)> {
+        let bounds = self.objects.rid_value_iter()
+            .filter(|(rid, _)| !self.hidden_objects.contains(*rid))
+            .filter_map(|(rid, obj)| {
+                let bounds = obj.bounding_box()?;
+                match self.scene.world_transform_at(*rid, 0.0) {
+                    Some(object_to_world) => Some(object_to_world.box_to_world(&bounds)),
+                    None => Some(bounds)
+                }
+            })
+            .reduce(|a, b| a.union(&b))?;
+
+        Some((bounds.center(), 0.5 * bounds.extent().length()))
+    }
+
+
+    /// Samples the ray of a photon leaving a light or the sky at `time`, with its power and whether `direct_lighting`
+    /// samples where it comes from. Lights and the sky are picked proportionally to their power.
+    pub(super) fn emit_photon(&self, scene_bounds: Option<(Vec3, f64)>, time: f64) -> Option<(Ray, Vec3, bool)> {
+        // Power of the distant lights and the sky over the scene
+        let distant_power = |light: &Light| scene_bounds.map_or(0.0, |(_, radius)| radius * radius * light.power());
+        let sky_power = scene_bounds.map_or(0.0, |(_, radius)| self.sky_power(radius));
+        let total_power = self.light_tree.power()
+            + self.light_tree.distant_lights().iter().map(distant_power).sum::<f64>()
+            + sky_power;
+        if total_power <= 0.0 {
+            return None;
+        }
+
+        let mut u = random() * total_power;
+        for light in self.light_tree.distant_lights() {
+            let power = distant_power(light);
+            if u >= power {
+                u -= power;
+                continue;
+            }
+
+            let (Light::Directional { direction, irradiance }, Some((center, radius))) = (light, scene_bounds) else {
+                continue;
+            };
+            // Leaving a disk facing the light, in front of the scene
+            let frame = Frame::from_normal(*direction);
+            let (x, y) = sample_uniform_disk_polar((random(), random()));
+            let origin = center + radius * (x * frame.x + y * frame.y) - radius * *direction;
+
+            let area = PI * radius * radius;
+            let ray = Ray::new(origin, *direction).with_time(time);
+            return Some((ray, (area * total_power / power) * *irradiance, true));
+        }
+
+        if u < sky_power {
+            let (center, radius) = scene_bounds?;
+            // Coming from a uniform direction of the sky, through a disk facing it in front of the scene
+            let to_sky = sample_uniform_sphere();
+            let frame = Frame::from_normal(to_sky);
+            let (x, y) = sample_uniform_disk_polar((random(), random()));
+            let origin = center + radius * (x * frame.x + y * frame.y) + radius * to_sky;
+
+            let area = PI * radius * radius;
+            let radiance = self.sky_color(&Ray::new(origin, to_sky));
+            let ray = Ray::new(origin, -to_sky).with_time(time);
+            return Some((ray, (4.0 * PI * area * total_power / sky_power) * radiance, false));
+        }
+
+        let (source, probability) = self.light_tree.sample_power()?;
+        let probability = probability * self.light_tree.power() / total_power;
+        match source {
+            LightSource::Light(light) => {
+                let (direction, pdf_direction) = light.sample_emission()?;
+                let ray = Ray::new(light.position()?, direction).with_time(time);
+                Some((ray, (1.0 / (probability * pdf_direction)) * light.intensity(direction), true))
+            },
+            LightSource::Area(area_light) => {
+                let (hit, material, pdf_area) = self.sample_area_light_surface(area_light, time)?;
+                let direction = Frame::from_normal(hit.normal).from_local(sample_cosine_hemisphere());
+
+                // The cosine of the emitted light cancels with the density of the direction
+                let power = (PI / (probability * pdf_area)) * material.emitted(direction, &hit);
+                Some((hit.spawn_ray(direction).with_time(time), power, true))
+            }
+        }
+    }
+
+
+    /// Rough power of the sky through a disk of radius `radius`, from its mean luminance along the axes
+    fn sky_power(&self, radius: f64) -> f64 {
+        let axes = [Vec3::UNIT_X, Vec3::UNIT_Y, Vec3::UNIT_Z];
+        let mean_luminance = axes.iter()
+            .flat_map(|axis| [*axis, -*axis])
+            .map(|direction| luminance(self.sky_color(&Ray::new(Vec3::ZERO, direction))))
+            .sum::<f64>() / 6.0;
+        4.0 * PI * mean_luminance * PI * radius * radius
+    }
+
+
+    /// Follows a photon, storing it where it lands after the first bounce, or from the start if `store_direct` is set
+    fn trace_photon(&self, mut ray: Ray, mut power: Vec3, store_direct: bool, photons: &mut Vec<Photon>) {
+        for bounce in 0..=self.max_light_bounce {
+            let Some((mut hit, obj_rid)) = self.closest_hit(&ray) else {
+                return;
+            };
+
+            let material = self.shade(&mut hit, obj_rid);
+            let wi = -ray.direction.normalized();
+            if (bounce > 0 || store_direct) && material.flags(&hit).is_non_specular() {
+                photons.push(Photon { position: hit.position, wi: wi, power: power });
+            }
+
+            // Photons are scattered as light would be towards them, BSDFs being mostly symmetric
+            let Some(sample) = material.sample(wi, &hit) else {
+                return;
+            };
+            power = mul_elem(power, sample.weight(hit.shading_normal));
+            if power.length_sq() == 0.0 {
+                return;
+            }
+            ray = hit.spawn_ray(sample.wi).with_time(ray.time);
+        }
+    }
+
+
+    /// Radiance arriving along the camera `ray`, estimated with photon mapping.
+    ///
+    /// The path is followed through specular surfaces. Where it first reaches a diffuse or glossy surface, direct
+    /// lighting is sampled from the lights and the rest of the lighting, sky and caustics included, is estimated from
+    /// the density of the photons around. Emissive surfaces and the sky are only seen directly or through specular
+    /// surfaces, and participating media are ignored.
+    pub(super) fn photon_map_radiance(&self, ray: &Ray, photon_map: &PhotonMap) -> Vec3 {
+        let mut ray = *ray;
+        let mut beta = vec3!(1.0, 1.0, 1.0);
+        let mut radiance = Vec3::ZERO;
+
+        for _ in 0..=self.max_light_bounce {
+            let Some((mut hit, obj_rid)) = self.closest_hit(&ray) else {
+                return radiance + mul_elem(beta, self.sky_color(&ray));
+            };
+
+            let material = self.shade(&mut hit, obj_rid);
+            let wo = -ray.direction.normalized();
+            radiance += mul_elem(beta, material.emitted(wo, &hit));
+
+            if material.flags(&hit).is_non_specular() {
+                // Emission is only counted after specular bounces, which direct lighting can not sample
+                let direct = self.direct_lighting(
+                    hit.position,
+                    ray.time,
+                    |wi| (hit.spawn_ray(wi).with_time(ray.time), None),
+                    |wi| wi.dot(hit.shading_normal).abs() * material.eval(wi, wo, &hit),
+                    |_| 0.0
+                );
+
+                let mut flux = Vec3::ZERO;
+                photon_map.for_each_near(hit.position, &mut |photon: &Photon| {
+                    flux += mul_elem(material.eval(photon.wi, wo, &hit), photon.power);
+                });
+                let indirect = (1.0 / (PI * photon_map.radius * photon_map.radius)) * flux;
+
+                radiance += mul_elem(beta, direct + indirect);
+            }
+
+            // Only specular lobes are followed, the others being accounted for by the photons
+            let Some(sample) = material.sample(wo, &hit).filter(|sample| sample.is_specular()) else {
+                return radiance;
+            };
+            beta = mul_elem(beta, sample.weight(hit.shading_normal));
+            ray = hit.spawn_ray(sample.wi).with_time(ray.time);
+        }
+
+        radiance
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn photon_at(position: Vec3) -> Photon {
+        Photon { position: position, wi: Vec3::UNIT_Z, power: Vec3::ZERO }
+    }
+
+
+    /// Positions found by `for_each_near` and by checking every photon, sorted to be compared
+    fn near_positions(photon_map: &PhotonMap, positions: &[Vec3], point: Vec3) -> (Vec<[f64; 3]>, Vec<[f64; 3]>) {
+        let mut found = Vec::new();
+        photon_map.for_each_near(point, &mut |photon: &Photon| {
+            found.push([photon.position.x, photon.position.y, photon.position.z]);
+        });
+
+        let radius_sq = photon_map.radius * photon_map.radius;
+        let mut expected: Vec<[f64; 3]> = positions.iter()
+            .filter(|position| (**position - point).length_sq() <= radius_sq)
+            .map(|position| [position.x, position.y, position.z])
+            .collect();
+
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        (found, expected)
+    }
+
+
+    #[test]
+    fn for_each_near_matches_brute_force() {
+        let positions: Vec<Vec3> = (0..1000).map(|_| Vec3::new(random(), 0.5 * random(), 2.0 * random())).collect();
+        let photon_map = PhotonMap::new(positions.iter().copied().map(photon_at).collect(), 0.15);
+
+        for _ in 0..200 {
+            let point = Vec3::new(1.2 * random() - 0.1, 0.7 * random() - 0.1, 2.2 * random() - 0.1);
+            let (found, expected) = near_positions(&photon_map, &positions, point);
+            assert_eq!(found, expected);
+        }
+    }
+
+
+    #[test]
+    fn for_each_near_finds_photons_sharing_coordinates() {
+        // Photons on a grid, several of them at each point, so that many of them lie on the splitting planes
+        let mut positions = Vec::new();
+        for i in 0..6 {
+            for j in 0..6 {
+                for k in 0..3 {
+                    positions.push(Vec3::new(0.1 * i as f64, 0.1 * j as f64, 0.0));
+                    positions.push(Vec3::new(0.1 * i as f64, 0.0, 0.1 * k as f64));
+                }
+            }
+        }
+        let photon_map = PhotonMap::new(positions.iter().copied().map(photon_at).collect(), 0.1);
+
+        for position in &positions {
+            let (found, expected) = near_positions(&photon_map, &positions, *position);
+            assert!(!found.is_empty());
+            assert_eq!(found, expected);
+        }
+    }
+
+
+    #[test]
+    fn empty_photon_map_finds_nothing() {
+        let photon_map = PhotonMap::new(Vec::new(), 1.0);
+        let mut count = 0;
+        photon_map.for_each_near(Vec3::ZERO, &mut |_: &Photon| count += 1);
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,437 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::f64::consts::PI;
+
+use simple_term_renderer::math::{Vec2, Vec3};
+use simple_term_renderer::vec2;
+
+use crate::path_tracer::{Ray, HitInfo};
+use crate::path_tracer::math::*;
+
+use super::obj::{oriented_hit, Object};
+
+
+// Every shape of this module is a surface of revolution around the `y` axis going through `center`.
+
+
+/// Angle around the `y` axis, mapped to [0; 1] the same way as the `u` coordinate of spheres
+fn revolution_u(local: Vec3) -> f64 {
+    ((-local.z).atan2(local.x) + PI) / (2.0 * PI)
+}
+
+
+/// Derivative of the position with respect to `u` for a surface of revolution
+fn revolution_dpdu(local: Vec3) -> Vec3 {
+    2.0 * PI * Vec3::new(local.z, 0.0, -local.x)
+}
+
+
+/// Derivative along the meridian of length `length`, oriented so that `dpdu x dpdv` follows the normal
+fn meridian_dpdv(normal: Vec3, dpdu: Vec3, length: f64) -> Vec3 {
+    let tangent = normal.cross(dpdu);
+    if tangent.length_sq() == 0.0 { // On the axis
+        return Vec3::ZERO;
+    }
+    length * tangent.normalized()
+}
+
+
+/// Hit of a flat cap of radius `radius`, at height `height` in local space, facing up or down
+fn cap_hit(ray: &Ray, local_origin: Vec3, interval: &Interval, radius: f64, height: f64, up: bool) -> Option<HitInfo> {
+    if ray.direction.y == 0.0 {
+        return None;
+    }
+
+    let t = (height - local_origin.y) / ray.direction.y;
+    if !interval.surrounds(t) {
+        return None;
+    }
+
+    let local = local_origin + t * ray.direction;
+    if local.x * local.x + local.z * local.z > radius * radius {
+        return None;
+    }
+
+    // Planar mapping, oriented so that `dpdu x dpdv` is the outward normal
+    let u = 0.5 * (local.x / radius + 1.0);
+    let (v, dpdv, normal) = if up {
+        (0.5 * (1.0 - local.z / radius), -2.0 * radius * Vec3::UNIT_Z, Vec3::UNIT_Y)
+    } else {
+        (0.5 * (local.z / radius + 1.0), 2.0 * radius * Vec3::UNIT_Z, -Vec3::UNIT_Y)
+    };
+
+    Some(oriented_hit(ray, t, normal, vec2!(u, v), 2.0 * radius * Vec3::UNIT_X, dpdv))
+}
+
+
+/// Closest hit of an open tube of radius `radius` spanning `half_height` around the origin in local space.
+/// Returns the distance, the local position and the outward normal.
+fn tube_hit(
+    ray: &Ray, local_origin: Vec3, interval: &Interval, radius: f64, half_height: f64
+) -> Option<(f64, Vec3, Vec3)> {
+    let (o, d) = (local_origin, ray.direction);
+    let a = d.x * d.x + d.z * d.z;
+    let b = 2.0 * (o.x * d.x + o.z * d.z);
+    let c = o.x * o.x + o.z * o.z - radius * radius;
+
+    let (t0, t1) = solve_quadratic(a, b, c)?;
+    for t in [t0, t1] {
+        if !interval.surrounds(t) {
+            continue;
+        }
+
+        let local = o + t * d;
+        if local.y.abs() > half_height {
+            continue;
+        }
+
+        return Some((t, local, Vec3::new(local.x, 0.0, local.z) / radius));
+    }
+
+    None
+}
+
+
+/// Keeps the closest of two hits
+fn closest(a: Option<HitInfo>, b: Option<HitInfo>) -> Option<HitInfo> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.distance <= b.distance { a } else { b }),
+        (a, None) => a,
+        (None, b) => b
+    }
+}
+
+
+/// Cylinder of axis `y`, spanning `height` around its center
+pub struct Cylinder {
+    center: Vec3,
+    radius: f64,
+    half_height: f64,
+    capped: bool
+}
+
+
+impl Cylinder {
+
+    pub fn new(center: Vec3, radius: f64, height: f64, capped: bool) -> Self {
+        Self {
+            center: center,
+            radius: radius,
+            half_height: 0.5 * height,
+            capped: capped
+        }
+    }
+
+
+    fn side_hit(&self, ray: &Ray, local_origin: Vec3, interval: &Interval) -> Option<HitInfo> {
+        let (t, local, normal) = tube_hit(ray, local_origin, interval, self.radius, self.half_height)?;
+        let uv = vec2!(revolution_u(local), (local.y + self.half_height) / (2.0 * self.half_height));
+        let dpdv = 2.0 * self.half_height * Vec3::UNIT_Y;
+        Some(oriented_hit(ray, t, normal, uv, revolution_dpdu(local), dpdv))
+    }
+}
+
+
+impl Object for Cylinder {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let local_origin = ray.origin - self.center;
+        let mut hit = self.side_hit(ray, local_origin, interval);
+        if self.capped {
+            let top = cap_hit(ray, local_origin, interval, self.radius, self.half_height, true);
+            let bottom = cap_hit(ray, local_origin, interval, self.radius, -self.half_height, false);
+            hit = closest(hit, closest(top, bottom));
+        }
+
+        hit
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(self.radius, self.half_height, self.radius);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
+
+
+/// Cone of axis `y` whose base of radius `radius` is at `base_center`, the apex being `height` above it
+pub struct Cone {
+    base_center: Vec3,
+    radius: f64,
+    height: f64,
+    capped: bool
+}
+
+
+impl Cone {
+
+    pub fn new(base_center: Vec3, radius: f64, height: f64, capped: bool) -> Self {
+        Self {
+            base_center: base_center,
+            radius: radius,
+            height: height,
+            capped: capped
+        }
+    }
+
+
+    fn side_hit(&self, ray: &Ray, local_origin: Vec3, interval: &Interval) -> Option<HitInfo> {
+        // x² + z² = k² (h - y)²
+        let k2 = (self.radius / self.height).powi(2);
+        let (o, d) = (local_origin, ray.direction);
+        let oy = self.height - o.y;
+        let dy = -d.y;
+
+        let a = d.x * d.x + d.z * d.z - k2 * dy * dy;
+        let b = 2.0 * (o.x * d.x + o.z * d.z - k2 * oy * dy);
+        let c = o.x * o.x + o.z * o.z - k2 * oy * oy;
+
+        let (t0, t1) = solve_quadratic(a, b, c)?;
+        for t in [t0, t1] {
+            if !interval.surrounds(t) {
+                continue;
+            }
+
+            let local = o + t * d;
+            if local.y < 0.0 || local.y > self.height {
+                continue; // Outside of the cone, or on its mirrored nappe
+            }
+
+            let normal = Vec3::new(local.x, k2 * (self.height - local.y), local.z).normalized();
+            let v = local.y / self.height;
+            let uv = vec2!(revolution_u(local), v);
+
+            // Derivative of ((1 - v) r cos, v h, -(1 - v) r sin) with respect to v
+            let ring_radius = (local.x * local.x + local.z * local.z).sqrt().max(1e-12);
+            let dpdv = Vec3::new(
+                -self.radius / ring_radius * local.x,
+                self.height,
+                -self.radius / ring_radius * local.z
+            );
+
+            return Some(oriented_hit(ray, t, normal, uv, revolution_dpdu(local), dpdv));
+        }
+
+        None
+    }
+}
+
+
+impl Object for Cone {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let local_origin = ray.origin - self.base_center;
+        let mut hit = self.side_hit(ray, local_origin, interval);
+
+        if self.capped {
+            hit = closest(hit, cap_hit(ray, local_origin, interval, self.radius, 0.0, false));
+        }
+
+        hit
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(
+            self.base_center - Vec3::new(self.radius, 0.0, self.radius),
+            self.base_center + Vec3::new(self.radius, self.height, self.radius)
+        ))
+    }
+}
+
+
+/// Cylinder of axis `y` closed by two hemispheres. `v` follows the arc length of the meridian.
+pub struct Capsule {
+    center: Vec3,
+    radius: f64,
+    half_height: f64
+}
+
+
+impl Capsule {
+
+    /// `height` is the length of the cylindrical part
+    pub fn new(center: Vec3, radius: f64, height: f64) -> Self {
+        Self {
+            center: center,
+            radius: radius,
+            half_height: 0.5 * height
+        }
+    }
+
+
+    fn meridian_length(&self) -> f64 {
+        PI * self.radius + 2.0 * self.half_height
+    }
+
+
+    /// Arc length from the bottom pole to the point
+    fn arc_length(&self, local: Vec3) -> f64 {
+        let (r, h) = (self.radius, self.half_height);
+        if local.y < -h {
+            r * (-(local.y + h) / r).clamp(-1.0, 1.0).acos()
+        } else if local.y > h {
+            0.5 * PI * r + 2.0 * h + r * ((local.y - h) / r).clamp(-1.0, 1.0).asin()
+        } else {
+            0.5 * PI * r + local.y + h
+        }
+    }
+
+
+    fn surface_hit(&self, ray: &Ray, t: f64, local: Vec3, normal: Vec3) -> HitInfo {
+        let length = self.meridian_length();
+        let dpdu = revolution_dpdu(local);
+        let uv = vec2!(revolution_u(local), self.arc_length(local) / length);
+        oriented_hit(ray, t, normal, uv, dpdu, meridian_dpdv(normal, dpdu, length))
+    }
+
+
+    fn hemisphere_hit(&self, ray: &Ray, local_origin: Vec3, interval: &Interval, top: bool) -> Option<HitInfo> {
+        let sphere_center = if top { self.half_height } else { -self.half_height } * Vec3::UNIT_Y;
+        let oc = local_origin - sphere_center;
+        let d = ray.direction;
+
+        let (t0, t1) = solve_quadratic(d.length_sq(), 2.0 * oc.dot(d), oc.length_sq() - self.radius * self.radius)?;
+        for t in [t0, t1] {
+            if !interval.surrounds(t) {
+                continue;
+            }
+
+            let local = local_origin + t * d;
+            if (top && local.y < self.half_height) || (!top && local.y > -self.half_height) {
+                continue; // Inside of the cylindrical part
+            }
+
+            let normal = (local - sphere_center) / self.radius;
+            return Some(self.surface_hit(ray, t, local, normal));
+        }
+
+        None
+    }
+
+
+    fn side_hit(&self, ray: &Ray, local_origin: Vec3, interval: &Interval) -> Option<HitInfo> {
+        let (t, local, normal) = tube_hit(ray, local_origin, interval, self.radius, self.half_height)?;
+        Some(self.surface_hit(ray, t, local, normal))
+    }
+}
+
+
+impl Object for Capsule {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let local_origin = ray.origin - self.center;
+
+        closest(
+            self.side_hit(ray, local_origin, interval),
+            closest(
+                self.hemisphere_hit(ray, local_origin, interval, true),
+                self.hemisphere_hit(ray, local_origin, interval, false)
+            )
+        )
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(self.radius, self.half_height + self.radius, self.radius);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
+
+
+/// Torus lying in the `xz` plane. `u` goes around the `y` axis and `v` around the tube.
+pub struct Torus {
+    center: Vec3,
+    major_radius: f64,
+    minor_radius: f64
+}
+
+
+impl Torus {
+
+    pub fn new(center: Vec3, major_radius: f64, minor_radius: f64) -> Self {
+        Self {
+            center: center,
+            major_radius: major_radius,
+            minor_radius: minor_radius
+        }
+    }
+}
+
+
+impl Object for Torus {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let (major, minor) = (self.major_radius, self.minor_radius);
+
+        // Work with a normalized direction starting close to the torus, so that the quartic is well conditioned
+        let direction_length = ray.direction.length();
+        let direction = ray.direction / direction_length;
+        let local_origin = ray.origin - self.center;
+
+        let bounding_radius = major + minor;
+        let (enter, _) = solve_quadratic(
+            1.0,
+            2.0 * local_origin.dot(direction),
+            local_origin.length_sq() - bounding_radius * bounding_radius
+        )?;
+        let start = enter.max(0.0);
+        let o = local_origin + start * direction;
+        let d = direction;
+
+        // (|p|² + R² - r²)² = 4 R² (x² + z²)
+        let b = 2.0 * o.dot(d);
+        let c = o.length_sq() + major * major - minor * minor;
+        let four_r2 = 4.0 * major * major;
+
+        let roots = solve_quartic(
+            2.0 * b,
+            b * b + 2.0 * c - four_r2 * (d.x * d.x + d.z * d.z),
+            2.0 * b * c - four_r2 * 2.0 * (o.x * d.x + o.z * d.z),
+            c * c - four_r2 * (o.x * o.x + o.z * o.z)
+        );
+
+        for root in roots {
+            let t = (root + start) / direction_length;
+            if !interval.surrounds(t) {
+                continue;
+            }
+
+            let local = local_origin + (root + start) * direction;
+            let ring = Vec3::new(local.x, 0.0, local.z);
+            if ring.length_sq() == 0.0 {
+                continue;
+            }
+            let ring_point = major * ring.normalized();
+            let normal = (local - ring_point).normalized();
+
+            let tube_angle = local.y.atan2(ring.length() - major).rem_euclid(2.0 * PI);
+            let uv = vec2!(revolution_u(local), tube_angle / (2.0 * PI));
+            let dpdu = revolution_dpdu(local);
+            let dpdv = meridian_dpdv(normal, dpdu, 2.0 * PI * minor);
+
+            return Some(oriented_hit(ray, t, normal, uv, dpdu, dpdv));
+        }
+
+        None
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let outer = self.major_radius + self.minor_radius;
+        let extent = Vec3::new(outer, self.minor_radius, outer);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
@@ -0,0 +1,302 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::collections::HashMap;
+
+use crate::rid::Rid;
+use crate::transform::{Affine, ObjectToWorld, Transform};
+
+
+struct SceneNode {
+    local: Transform,
+    /// Local transform at the end of the frame, for motion blur
+    local_end: Option<Transform>,
+    /// Whether the node or one of its ancestors moves during the frame
+    moving: bool,
+    parent: Option<Rid>,
+    children: Vec<Rid>,
+    world: Affine,
+    object_to_world: ObjectToWorld
+}
+
+
+impl SceneNode {
+
+    fn new() -> Self {
+        Self {
+            local: Transform::identity(),
+            local_end: None,
+            moving: false,
+            parent: None,
+            children: Vec::new(),
+            world: Affine::identity(),
+            object_to_world: ObjectToWorld::new(Affine::identity())
+        }
+    }
+}
+
+
+/// Hierarchy of objects, each node having a transform relative to its parent.
+///
+/// World transforms are resolved as soon as the hierarchy changes, so that rendering only reads them.
+pub struct SceneGraph {
+    nodes: HashMap<Rid, SceneNode>
+}
+
+
+impl SceneGraph {
+
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::new()
+        }
+    }
+
+
+    fn node_mut(&mut self, rid: Rid) -> &mut SceneNode {
+        self.nodes.entry(rid).or_insert_with(SceneNode::new)
+    }
+
+
+    pub fn local_transform(&self, rid: Rid) -> Transform {
+        self.nodes.get(&rid)
+            .map(|node| node.local)
+            .unwrap_or_else(Transform::identity)
+    }
+
+
+    pub fn set_local_transform(&mut self, rid: Rid, transform: Transform) {
+        self.node_mut(rid).local = transform;
+        self.resolve(rid);
+    }
+
+
+    /// Sets the local transform at the end of the frame, the node moving from its local transform to it.
+    /// `None` makes the node static.
+    pub fn set_local_motion(&mut self, rid: Rid, end: Option<Transform>) {
+        self.node_mut(rid).local_end = end;
+        self.resolve(rid);
+    }
+
+
+    pub fn local_motion(&self, rid: Rid) -> Option<Transform> {
+        self.nodes.get(&rid).and_then(|node| node.local_end)
+    }
+
+
+    /// World transform of a node, `None` if it was never placed in the graph (identity)
+    pub fn world_transform(&self, rid: Rid) -> Option<&ObjectToWorld> {
+        self.nodes.get(&rid).map(|node| &node.object_to_world)
+    }
+
+
+    /// World transform of a node at `time`, interpolating the transforms of moving nodes
+    pub fn world_transform_at(&self, rid: Rid, time: f64) -> Option<ObjectToWorld> {
+        let node = self.nodes.get(&rid)?;
+        if !node.moving {
+            return Some(node.object_to_world);
+        }
+        Some(ObjectToWorld::new(self.world_affine_at(rid, time)))
+    }
+
+
+    fn world_affine_at(&self, rid: Rid, time: f64) -> Affine {
+        let Some(node) = self.nodes.get(&rid) else {
+            return Affine::identity();
+        };
+        if !node.moving {
+            return node.world;
+        }
+
+        let local = match &node.local_end {
+            Some(end) => Transform::lerp(time, &node.local, end),
+            None => node.local
+        };
+        let parent_world = node.parent
+            .map(|parent| self.world_affine_at(parent, time))
+            .unwrap_or_else(Affine::identity);
+
+        parent_world * local.to_affine()
+    }
+
+
+    pub fn parent(&self, rid: Rid) -> Option<Rid> {
+        self.nodes.get(&rid).and_then(|node| node.parent)
+    }
+
+
+    pub fn children(&self, rid: Rid) -> &[Rid] {
+        self.nodes.get(&rid).map(|node| node.children.as_slice()).unwrap_or(&[])
+    }
+
+
+    /// Returns `rid` and all of its descendants
+    pub fn subtree(&self, rid: Rid) -> Vec<Rid> {
+        let mut subtree = vec![rid];
+        let mut index = 0;
+        while index < subtree.len() {
+            subtree.extend_from_slice(self.children(subtree[index]));
+            index += 1;
+        }
+        subtree
+    }
+
+
+    /// Moves `child` under `parent`, keeping its local transform. Returns `false` if it would create a cycle.
+    pub fn set_parent(&mut self, child: Rid, parent: Option<Rid>) -> bool {
+        // Check that the child is not an ancestor of the new parent
+        let mut ancestor = parent;
+        while let Some(rid) = ancestor {
+            if rid == child {
+                return false;
+            }
+            ancestor = self.parent(rid);
+        }
+
+        self.detach(child);
+        self.node_mut(child).parent = parent;
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(child);
+        }
+
+        self.resolve(child);
+        true
+    }
+
+
+    /// Removes a node, its children are moved to its parent. The transform of the node is folded into the local
+    /// transforms of its children, so that they keep their place in world space. Returns `false`, leaving the node in
+    /// place, if a transform can not be folded exactly: see `Transform::compose`.
+    pub fn remove(&mut self, rid: Rid) -> bool {
+        let Some(node) = self.nodes.get(&rid) else {
+            return true;
+        };
+        let parent = node.parent;
+        let children = node.children.clone();
+        let (local, local_end) = (node.local, node.local_end);
+
+        let exact = children.iter().filter_map(|child| self.nodes.get(child)).all(|child_node| {
+            Transform::composes_exactly(&local, &child_node.local)
+                && Transform::composes_exactly(
+                    &local_end.unwrap_or(local),
+                    &child_node.local_end.unwrap_or(child_node.local)
+                )
+        });
+        if !exact {
+            return false;
+        }
+
+        for child in children {
+            let child_node = self.node_mut(child);
+            let child_end = match (local_end, child_node.local_end) {
+                (None, None) => None,
+                (end, child_end) => Some(Transform::compose(
+                    &end.unwrap_or(local),
+                    &child_end.unwrap_or(child_node.local)
+                ))
+            };
+            child_node.local = Transform::compose(&local, &child_node.local);
+            child_node.local_end = child_end;
+
+            self.set_parent(child, parent);
+        }
+
+        self.detach(rid);
+        self.nodes.remove(&rid);
+        true
+    }
+
+
+    /// Removes a node from the children of its parent
+    fn detach(&mut self, rid: Rid) {
+        if let Some(parent) = self.parent(rid) {
+            self.node_mut(parent).children.retain(|child| *child != rid);
+        }
+        if let Some(node) = self.nodes.get_mut(&rid) {
+            node.parent = None;
+        }
+    }
+
+
+    /// Recomputes the world transforms of a subtree
+    fn resolve(&mut self, rid: Rid) {
+        let (parent_world, parent_moving) = self.parent(rid)
+            .and_then(|parent| self.nodes.get(&parent))
+            .map(|parent| (parent.world, parent.moving))
+            .unwrap_or_else(|| (Affine::identity(), false));
+
+        let node = self.node_mut(rid);
+        node.world = parent_world * node.local.to_affine();
+        node.moving = parent_moving || node.local_end.is_some();
+        node.object_to_world = ObjectToWorld::new(node.world);
+
+        for child in node.children.clone() {
+            self.resolve(child);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    use simple_term_renderer::math::Vec3;
+
+    use crate::rid::RidOwner;
+    use crate::transform::Quat;
+
+
+    #[test]
+    fn removed_nodes_keep_their_children_in_place() {
+        let mut rids = RidOwner::new();
+        let (group, child) = (rids.add(()), rids.add(()));
+        let mut scene = SceneGraph::new();
+
+        let rotation = Quat::from_axis_angle(Vec3::UNIT_Y, FRAC_PI_2);
+        scene.set_local_transform(group, Transform::new(Vec3::new(1.0, 2.0, 3.0), rotation, Vec3::new(2.0, 2.0, 2.0)));
+        scene.set_local_transform(child, Transform::new(Vec3::new(0.5, 0.0, 0.0), rotation, Vec3::new(1.0, 3.0, 1.0)));
+        scene.set_parent(child, Some(group));
+
+        let point = Vec3::new(0.3, -0.7, 1.1);
+        let before = scene.nodes[&child].world.point(point);
+        assert!(scene.remove(group));
+        let after = scene.nodes[&child].world.point(point);
+
+        assert!((after - before).length() < 1e-9, "moved from {:?} to {:?}", before, after);
+        assert!(scene.parent(child).is_none());
+    }
+
+
+    #[test]
+    fn nodes_whose_transform_can_not_be_folded_are_kept() {
+        let mut rids = RidOwner::new();
+        let (group, child) = (rids.add(()), rids.add(()));
+        let mut scene = SceneGraph::new();
+
+        let stretch = Transform::new(Vec3::ZERO, Quat::IDENTITY, Vec3::new(1.0, 4.0, 1.0));
+        let rotation = Quat::from_axis_angle(Vec3::UNIT_Z, 0.3);
+        scene.set_local_transform(group, stretch);
+        scene.set_local_transform(child, Transform::new(Vec3::ZERO, rotation, Vec3::new(1.0, 1.0, 1.0)));
+        scene.set_parent(child, Some(group));
+
+        assert!(!scene.remove(group));
+        assert!(scene.parent(child) == Some(group));
+    }
+}
@@ -0,0 +1,508 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use simple_term_renderer::img::Color;
+use simple_term_renderer::math::Vec3;
+
+use crate::Camera;
+use crate::rid::Rid;
+use crate::transform::{Quat, Transform};
+
+use super::{Animation, ComplexIor, CpuRenderingDevice, Dispersion, Interpolation, PrincipledParameters};
+
+
+/// Scene loaded from a file, along with the names given to its objects, materials and lights
+pub struct SceneDescription {
+    pub camera: Camera,
+    pub animation: Animation,
+    pub objects: HashMap<String, Rid>,
+    pub materials: HashMap<String, Rid>,
+    pub lights: HashMap<String, Rid>
+}
+
+
+fn invalid_data(line: usize, message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, message))
+}
+
+
+/// Line of a scene file, split into its positional words and its options
+struct Directive<'a> {
+    line: usize,
+    words: Vec<&'a str>,
+    options: HashMap<&'a str, &'a str>
+}
+
+
+impl<'a> Directive<'a> {
+
+    fn parse(line: usize, text: &'a str) -> Self {
+        let text = text.split('#').next().unwrap_or("");
+        let mut words = Vec::new();
+        let mut options = HashMap::new();
+
+        for word in text.split_whitespace() {
+            match word.split_once('=') {
+                Some((name, value)) => { options.insert(name, value); },
+                None => words.push(word)
+            }
+        }
+
+        Self {
+            line: line,
+            words: words,
+            options: options
+        }
+    }
+
+
+    fn error(&self, message: impl Into<String>) -> io::Error {
+        invalid_data(self.line, message.into())
+    }
+
+
+    fn word(&self, index: usize, what: &str) -> io::Result<&'a str> {
+        self.words.get(index).copied().ok_or_else(|| self.error(format!("missing {}", what)))
+    }
+
+
+    fn numbers(&self, name: &str, count: usize) -> io::Result<Option<Vec<f64>>> {
+        let Some(value) = self.options.get(name) else {
+            return Ok(None);
+        };
+
+        let numbers = value.split(',')
+            .map(|number| number.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| self.error(format!("invalid number in `{}`", name)))?;
+        if numbers.len() != count {
+            return Err(self.error(format!("`{}` expects {} numbers", name, count)));
+        }
+        Ok(Some(numbers))
+    }
+
+
+    fn number(&self, name: &str) -> io::Result<Option<f64>> {
+        Ok(self.numbers(name, 1)?.map(|numbers| numbers[0]))
+    }
+
+
+    fn vec3(&self, name: &str) -> io::Result<Option<Vec3>> {
+        Ok(self.numbers(name, 3)?.map(|v| Vec3::new(v[0], v[1], v[2])))
+    }
+
+
+    /// Rotation given as an axis and an angle in degrees
+    fn rotation(&self, name: &str) -> io::Result<Option<Quat>> {
+        Ok(self.numbers(name, 4)?.map(|v| Quat::from_axis_angle(Vec3::new(v[0], v[1], v[2]), v[3].to_radians())))
+    }
+
+
+    fn required<T>(&self, name: &str, value: io::Result<Option<T>>) -> io::Result<T> {
+        value?.ok_or_else(|| self.error(format!("missing `{}`", name)))
+    }
+
+
+    fn required_number(&self, name: &str) -> io::Result<f64> {
+        self.required(name, self.number(name))
+    }
+
+
+    fn required_vec3(&self, name: &str) -> io::Result<Vec3> {
+        self.required(name, self.vec3(name))
+    }
+
+
+    fn color(&self, name: &str) -> io::Result<Option<Color>> {
+        Ok(self.vec3(name)?.map(|color| Color::raw_vec3_rgb(color)))
+    }
+
+
+    fn boolean(&self, name: &str) -> io::Result<Option<bool>> {
+        match self.options.get(name) {
+            None => Ok(None),
+            Some(&"true") => Ok(Some(true)),
+            Some(&"false") => Ok(Some(false)),
+            Some(_) => Err(self.error(format!("`{}` expects true or false", name)))
+        }
+    }
+
+
+    fn interpolation(&self) -> io::Result<Interpolation> {
+        match self.options.get("interpolation").copied() {
+            None | Some("linear") => Ok(Interpolation::Linear),
+            Some("ease") => Ok(Interpolation::EASE_IN_OUT),
+            Some(value) => {
+                let points = value.strip_prefix("bezier:")
+                    .map(|points| points.split(',').map(str::parse::<f64>).collect::<Result<Vec<_>, _>>())
+                    .and_then(Result::ok)
+                    .filter(|points| points.len() == 4)
+                    .ok_or_else(|| self.error(format!("invalid interpolation `{}`", value)))?;
+                Ok(Interpolation::Bezier(points[0], points[1], points[2], points[3]))
+            }
+        }
+    }
+
+
+    fn dispersion(&self) -> io::Result<Option<Dispersion>> {
+        match self.options.get("dispersion").copied() {
+            None => Ok(None),
+            Some("bk7") => Ok(Some(Dispersion::BK7)),
+            Some("sf11") => Ok(Some(Dispersion::SF11)),
+            Some("water") => Ok(Some(Dispersion::WATER)),
+            Some(glass) => Err(self.error(format!("unknown dispersion `{}`", glass)))
+        }
+    }
+
+
+    /// Applies the transform options of the directive to `transform`
+    fn transform(&self, transform: Transform) -> io::Result<Transform> {
+        Ok(Transform::new(
+            self.vec3("translation")?.unwrap_or(transform.translation),
+            self.rotation("rotation")?.unwrap_or(transform.rotation),
+            self.vec3("scale")?.unwrap_or(transform.scale)
+        ))
+    }
+
+
+    /// Applies the principled material options of the directive to `parameters`
+    fn principled(&self, parameters: PrincipledParameters) -> io::Result<PrincipledParameters> {
+        let number = |name: &str, value: f64| -> io::Result<f64> {
+            Ok(self.number(name)?.unwrap_or(value))
+        };
+
+        Ok(PrincipledParameters {
+            base_color: self.color("color")?.unwrap_or(parameters.base_color),
+            metallic: number("metallic", parameters.metallic)?,
+            roughness: number("roughness", parameters.roughness)?,
+            specular: number("specular", parameters.specular)?,
+            transmission: number("transmission", parameters.transmission)?,
+            ior: number("ior", parameters.ior)?,
+            dispersion: self.dispersion()?.or(parameters.dispersion),
+            clearcoat: number("clearcoat", parameters.clearcoat)?,
+            clearcoat_roughness: number("clearcoat_roughness", parameters.clearcoat_roughness)?,
+            sheen: number("sheen", parameters.sheen)?,
+            sheen_tint: number("sheen_tint", parameters.sheen_tint)?,
+            ..parameters
+        })
+    }
+}
+
+
+/// State of the loading of a scene file
+struct SceneLoader<'a> {
+    device: &'a mut CpuRenderingDevice,
+    scene: SceneDescription,
+    /// Last transform given to each object, completed by the next keyframes
+    transforms: HashMap<Rid, Transform>,
+    /// Last parameters given to each principled material, completed by the next keyframes
+    principled: HashMap<Rid, PrincipledParameters>
+}
+
+
+impl SceneLoader<'_> {
+
+    fn object(&self, directive: &Directive, name: &str) -> io::Result<Rid> {
+        self.scene.objects.get(name).copied().ok_or_else(|| directive.error(format!("unknown object `{}`", name)))
+    }
+
+
+    fn material(&self, directive: &Directive, name: &str) -> io::Result<Rid> {
+        self.scene.materials.get(name).copied().ok_or_else(|| directive.error(format!("unknown material `{}`", name)))
+    }
+
+
+    fn load_directive(&mut self, directive: &Directive) -> io::Result<()> {
+        let Some(keyword) = directive.words.first().copied() else {
+            return Ok(()); // Empty line
+        };
+
+        match keyword {
+            "material" => self.load_material(directive),
+            "light" => self.load_light(directive),
+            "camera" => self.load_camera(directive),
+            "key" => self.load_keyframe(directive),
+            _ => self.load_object(directive, keyword)
+        }
+    }
+
+
+    fn load_material(&mut self, directive: &Directive) -> io::Result<()> {
+        let name = directive.word(1, "material name")?;
+        let kind = directive.word(2, "material kind")?;
+        let color = directive.color("color")?.unwrap_or(Color::raw_rgb(0.8, 0.8, 0.8));
+        let device = &mut *self.device;
+
+        let rid = match kind {
+            "lambertian" => device.create_lambertial_material(color),
+            "metal" => device.create_metal_material(color, directive.number("fuzz")?.unwrap_or(0.0)),
+            "conductor" => {
+                let ior = match directive.options.get("metal").copied() {
+                    Some("gold") => ComplexIor::gold(),
+                    Some("copper") => ComplexIor::copper(),
+                    Some("aluminium") | None => ComplexIor::aluminium(),
+                    Some("silver") => ComplexIor::silver(),
+                    Some(metal) => return Err(directive.error(format!("unknown metal `{}`", metal)))
+                };
+                device.create_conductor_material(ior, directive.number("roughness")?.unwrap_or(0.0))
+            },
+            "dielectric" => {
+                let roughness = directive.number("roughness")?.unwrap_or(0.0);
+                match directive.dispersion()? {
+                    Some(dispersion) => device.create_dispersive_dielectric_material(dispersion, roughness),
+                    None => device.create_dielectric_material(directive.number("ior")?.unwrap_or(1.5), roughness)
+                }
+            },
+            "principled" => {
+                let parameters = directive.principled(PrincipledParameters::default())?;
+                let rid = device.create_principled_material(&parameters);
+                self.principled.insert(rid, parameters);
+                rid
+            },
+            "emissive" => device.create_emissive_material(color, directive.number("strength")?.unwrap_or(1.0)),
+            _ => return Err(directive.error(format!("unknown material kind `{}`", kind)))
+        };
+
+        self.scene.materials.insert(name.to_string(), rid);
+        Ok(())
+    }
+
+
+    fn load_light(&mut self, directive: &Directive) -> io::Result<()> {
+        let name = directive.word(1, "light name")?;
+        let kind = directive.word(2, "light kind")?;
+        let color = directive.color("color")?.unwrap_or(Color::raw_rgb(1.0, 1.0, 1.0));
+        let device = &mut *self.device;
+
+        let rid = match kind {
+            "point" => device.create_point_light(
+                directive.required_vec3("position")?,
+                color,
+                directive.number("intensity")?.unwrap_or(1.0),
+                directive.number("falloff")?.unwrap_or(2.0)
+            ),
+            "spot" => device.create_spot_light(
+                directive.required_vec3("position")?,
+                directive.required_vec3("direction")?,
+                color,
+                directive.number("intensity")?.unwrap_or(1.0),
+                directive.required_number("inner_angle")?.to_radians(),
+                directive.required_number("outer_angle")?.to_radians()
+            ),
+            "directional" => device.create_directional_light(
+                directive.required_vec3("direction")?,
+                color,
+                directive.number("irradiance")?.unwrap_or(1.0)
+            ),
+            _ => return Err(directive.error(format!("unknown light kind `{}`", kind)))
+        };
+
+        self.scene.lights.insert(name.to_string(), rid);
+        Ok(())
+    }
+
+
+    fn load_object(&mut self, directive: &Directive, kind: &str) -> io::Result<()> {
+        let name = directive.word(1, "object name")?;
+        let d = directive;
+        let device = &mut *self.device;
+
+        let rid = match kind {
+            "sphere" => device.create_sphere(d.required_vec3("center")?, d.required_number("radius")?),
+            "plane" => device.create_plane(d.required_vec3("point")?, d.required_vec3("normal")?),
+            "quad" => device.create_quad(d.required_vec3("corner")?, d.required_vec3("u")?, d.required_vec3("v")?),
+            "disk" => device.create_disk(
+                d.required_vec3("center")?, d.required_vec3("normal")?, d.required_number("radius")?
+            ),
+            "box" => device.create_box(d.required_vec3("min")?, d.required_vec3("max")?),
+            "triangle" => device.create_triangle(d.required_vec3("a")?, d.required_vec3("b")?, d.required_vec3("c")?),
+            "cylinder" | "cone" => {
+                let (center, radius) = (d.required_vec3("center")?, d.required_number("radius")?);
+                let (height, capped) = (d.required_number("height")?, d.boolean("capped")?.unwrap_or(true));
+                if kind == "cylinder" {
+                    device.create_cylinder(center, radius, height, capped)
+                } else {
+                    device.create_cone(center, radius, height, capped)
+                }
+            },
+            "capsule" => device.create_capsule(
+                d.required_vec3("center")?, d.required_number("radius")?, d.required_number("height")?
+            ),
+            "torus" => device.create_torus(
+                d.required_vec3("center")?, d.required_number("major_radius")?, d.required_number("minor_radius")?
+            ),
+            "group" => device.create_group(),
+            _ => return Err(directive.error(format!("unknown directive `{}`", kind)))
+        };
+        self.scene.objects.insert(name.to_string(), rid);
+
+        if let Some(material) = directive.options.get("material") {
+            let material = self.material(directive, material)?;
+            self.device.object_set_material(rid, material);
+        }
+        if let Some(parent) = directive.options.get("parent") {
+            let parent = self.object(directive, parent)?;
+            self.device.object_set_parent(rid, Some(parent));
+        }
+
+        let transform = directive.transform(Transform::identity())?;
+        self.device.object_set_transform(rid, transform);
+        self.transforms.insert(rid, transform);
+        Ok(())
+    }
+
+
+    fn load_camera(&mut self, directive: &Directive) -> io::Result<()> {
+        let camera = &mut self.scene.camera;
+        if let Some(position) = directive.vec3("position")? {
+            camera.position = position;
+            camera.end_position = position;
+        }
+        if let Some(rotation) = directive.rotation("rotation")? {
+            camera.rotation = rotation;
+        }
+        if let Some(focal_length) = directive.number("focal_length")? {
+            camera.focal_length = focal_length;
+        }
+        if let Some(shutter) = directive.numbers("shutter", 2)? {
+            (camera.shutter_open, camera.shutter_close) = (shutter[0], shutter[1]);
+        }
+        Ok(())
+    }
+
+
+    fn load_keyframe(&mut self, directive: &Directive) -> io::Result<()> {
+        let target = directive.word(1, "keyframe target")?;
+        let frame_index = if target == "camera" { 2 } else { 3 };
+        let frame = directive.word(frame_index, "keyframe frame")?;
+        let frame = frame.parse::<f64>().map_err(|_| directive.error(format!("invalid frame `{}`", frame)))?;
+        let interpolation = directive.interpolation()?;
+        let animation = &mut self.scene.animation;
+
+        match target {
+            "camera" => {
+                if let Some(position) = directive.vec3("position")? {
+                    animation.camera_position.add(frame, position, interpolation);
+                }
+                if let Some(rotation) = directive.rotation("rotation")? {
+                    animation.camera_rotation.add(frame, rotation, interpolation);
+                }
+                if let Some(focal_length) = directive.number("focal_length")? {
+                    animation.camera_focal_length.add(frame, focal_length, interpolation);
+                }
+            },
+            "object" => {
+                let rid = self.object(directive, directive.word(2, "object name")?)?;
+                let transform = directive.transform(self.transforms[&rid])?;
+                self.transforms.insert(rid, transform);
+                self.scene.animation.object_transform(rid).add(frame, transform, interpolation);
+            },
+            "material" => {
+                let name = directive.word(2, "material name")?;
+                let rid = self.material(directive, name)?;
+                let previous = self.principled.get(&rid)
+                    .copied()
+                    .ok_or_else(|| directive.error(format!("material `{}` is not principled", name)))?;
+
+                let parameters = directive.principled(previous)?;
+                self.principled.insert(rid, parameters);
+                self.scene.animation.material(rid).add(frame, parameters, interpolation);
+            },
+            _ => return Err(directive.error(format!("unknown keyframe target `{}`", target)))
+        }
+        Ok(())
+    }
+}
+
+
+impl CpuRenderingDevice {
+
+    /// Creates the objects, materials and lights described by a scene file. Returns its camera, its animation and
+    /// the `Rid`s of its named objects, materials and lights.
+    ///
+    /// Every line is a directive made of words separated by whitespaces, `#` starting a comment. Words of the form
+    /// `name=value` are options, vectors being given as comma separated numbers and rotations as an axis followed by an
+    /// angle in degrees, e.g. `rotation=0,1,0,90`.
+    ///
+    /// ```text
+    /// material <name> lambertian color=r,g,b
+    /// material <name> metal color=r,g,b fuzz=f
+    /// material <name> conductor metal=gold|copper|aluminium|silver roughness=f
+    /// material <name> dielectric ior=f|dispersion=bk7|sf11|water roughness=f
+    /// material <name> principled color=r,g,b metallic=f roughness=f specular=f transmission=f ior=f clearcoat=f
+    ///     clearcoat_roughness=f sheen=f sheen_tint=f dispersion=bk7|sf11|water
+    /// material <name> emissive color=r,g,b strength=f
+    ///
+    /// light <name> point position=x,y,z color=r,g,b intensity=f falloff=f
+    /// light <name> spot position=x,y,z direction=x,y,z color=r,g,b intensity=f inner_angle=degrees
+    ///     outer_angle=degrees
+    /// light <name> directional direction=x,y,z color=r,g,b irradiance=f
+    ///
+    /// sphere <name> center=x,y,z radius=f
+    /// plane <name> point=x,y,z normal=x,y,z
+    /// quad <name> corner=x,y,z u=x,y,z v=x,y,z
+    /// disk <name> center=x,y,z normal=x,y,z radius=f
+    /// box <name> min=x,y,z max=x,y,z
+    /// triangle <name> a=x,y,z b=x,y,z c=x,y,z
+    /// cylinder|cone <name> center=x,y,z radius=f height=f capped=true|false
+    /// capsule <name> center=x,y,z radius=f height=f
+    /// torus <name> center=x,y,z major_radius=f minor_radius=f
+    /// group <name>
+    ///
+    /// camera position=x,y,z rotation=x,y,z,degrees focal_length=f shutter=open,close
+    ///
+    /// key camera <frame> position=x,y,z rotation=x,y,z,degrees focal_length=f
+    /// key object <name> <frame> translation=x,y,z rotation=x,y,z,degrees scale=x,y,z
+    /// key material <name> <frame> [principled options]
+    /// ```
+    ///
+    /// Objects also take the `material=<name>`, `parent=<name>`, `translation`, `rotation` and `scale` options.
+    /// Keyframes take an `interpolation=linear|ease|bezier:x1,y1,x2,y2` option, linear by default. The components
+    /// left out of an object or material keyframe keep the value of the previous keyframe of the file, or the value
+    /// given when the object or material was defined. Only principled materials can be animated.
+    pub fn load_scene(&mut self, path: &Path) -> io::Result<SceneDescription> {
+        self.parse_scene(&fs::read_to_string(path)?)
+    }
+
+
+    /// Same as `load_scene`, from the content of a scene file
+    pub fn parse_scene(&mut self, source: &str) -> io::Result<SceneDescription> {
+        let mut loader = SceneLoader {
+            device: self,
+            scene: SceneDescription {
+                camera: Camera::new(Vec3::ZERO, 1.0),
+                animation: Animation::new(),
+                objects: HashMap::new(),
+                materials: HashMap::new(),
+                lights: HashMap::new()
+            },
+            transforms: HashMap::new(),
+            principled: HashMap::new()
+        };
+
+        for (index, text) in source.lines().enumerate() {
+            loader.load_directive(&Directive::parse(index + 1, text))?;
+        }
+        Ok(loader.scene)
+    }
+}
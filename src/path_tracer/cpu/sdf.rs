@@ -0,0 +1,277 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use simple_term_renderer::math::{Vec2, Vec3};
+use simple_term_renderer::vec2;
+
+use crate::path_tracer::{Ray, HitInfo};
+use crate::path_tracer::math::*;
+
+use super::obj::{oriented_hit, Object};
+
+
+/// Composable signed distance expression, negative inside of the shape
+pub enum SdfExpr {
+    Sphere { radius: f64 },
+    /// Box centered on the origin, possibly with rounded edges
+    Cuboid { half_extent: Vec3, rounding: f64 },
+    /// Torus lying in the `xz` plane
+    Torus { major_radius: f64, minor_radius: f64 },
+    /// Mandelbulb fractal of the given power, fitting in a sphere of radius around 1.2
+    Mandelbulb { power: f64, iterations: u32 },
+
+    Union(Box<SdfExpr>, Box<SdfExpr>),
+    Intersection(Box<SdfExpr>, Box<SdfExpr>),
+    Difference(Box<SdfExpr>, Box<SdfExpr>),
+    /// Union blending the two shapes over a distance of about `k`
+    SmoothUnion(Box<SdfExpr>, Box<SdfExpr>, f64),
+
+    Translate(Box<SdfExpr>, Vec3),
+    Scale(Box<SdfExpr>, f64),
+    /// Infinite repetition of the shape on a grid of cell size `period`, a zero component disabling an axis
+    Repeat(Box<SdfExpr>, Vec3),
+    /// Rotation around the `y` axis proportional to the height, in radians per unit
+    Twist(Box<SdfExpr>, f64),
+    /// Sine displacement of the surface, of amplitude `amplitude` and spatial frequency `frequency`
+    Displace(Box<SdfExpr>, f64, f64)
+}
+
+
+impl SdfExpr {
+
+    pub fn sphere(radius: f64) -> Self {
+        SdfExpr::Sphere { radius: radius }
+    }
+
+
+    pub fn cuboid(half_extent: Vec3, rounding: f64) -> Self {
+        SdfExpr::Cuboid { half_extent: half_extent, rounding: rounding }
+    }
+
+
+    pub fn torus(major_radius: f64, minor_radius: f64) -> Self {
+        SdfExpr::Torus { major_radius: major_radius, minor_radius: minor_radius }
+    }
+
+
+    pub fn mandelbulb(power: f64, iterations: u32) -> Self {
+        SdfExpr::Mandelbulb { power: power, iterations: iterations }
+    }
+
+
+    pub fn union(self, other: SdfExpr) -> Self {
+        SdfExpr::Union(Box::new(self), Box::new(other))
+    }
+
+
+    pub fn intersection(self, other: SdfExpr) -> Self {
+        SdfExpr::Intersection(Box::new(self), Box::new(other))
+    }
+
+
+    pub fn difference(self, other: SdfExpr) -> Self {
+        SdfExpr::Difference(Box::new(self), Box::new(other))
+    }
+
+
+    pub fn smooth_union(self, other: SdfExpr, k: f64) -> Self {
+        SdfExpr::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        SdfExpr::Translate(Box::new(self), offset)
+    }
+
+
+    pub fn scale(self, factor: f64) -> Self {
+        SdfExpr::Scale(Box::new(self), factor)
+    }
+
+
+    pub fn repeat(self, period: Vec3) -> Self {
+        SdfExpr::Repeat(Box::new(self), period)
+    }
+
+
+    pub fn twist(self, rate: f64) -> Self {
+        SdfExpr::Twist(Box::new(self), rate)
+    }
+
+
+    pub fn displace(self, amplitude: f64, frequency: f64) -> Self {
+        SdfExpr::Displace(Box::new(self), amplitude, frequency)
+    }
+
+
+    /// Lower bound of the distance to the surface, exact for the primitives.
+    /// Twist and displacement are divided by their Lipschitz bound so that sphere tracing stays conservative.
+    pub fn distance(&self, p: Vec3) -> f64 {
+        match self {
+            SdfExpr::Sphere { radius } => p.length() - radius,
+            SdfExpr::Cuboid { half_extent, rounding } => {
+                let inner = *half_extent - *rounding * Vec3::new(1.0, 1.0, 1.0);
+                let q = Vec3::new(p.x.abs(), p.y.abs(), p.z.abs()) - inner;
+                let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+                outside + q.x.max(q.y).max(q.z).min(0.0) - rounding
+            },
+            SdfExpr::Torus { major_radius, minor_radius } => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major_radius;
+                (ring * ring + p.y * p.y).sqrt() - minor_radius
+            },
+            SdfExpr::Mandelbulb { power, iterations } => mandelbulb(p, *power, *iterations),
+
+            SdfExpr::Union(a, b) => a.distance(p).min(b.distance(p)),
+            SdfExpr::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            SdfExpr::Difference(a, b) => a.distance(p).max(-b.distance(p)),
+            SdfExpr::SmoothUnion(a, b, k) => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+                lerp(h, db, da) - k * h * (1.0 - h)
+            },
+
+            SdfExpr::Translate(expr, offset) => expr.distance(p - *offset),
+            SdfExpr::Scale(expr, factor) => factor * expr.distance(p / *factor),
+            SdfExpr::Repeat(expr, period) => {
+                let wrap = |x: f64, period: f64| if period > 0.0 { x - period * (x / period).round() } else { x };
+                expr.distance(Vec3::new(wrap(p.x, period.x), wrap(p.y, period.y), wrap(p.z, period.z)))
+            },
+            SdfExpr::Twist(expr, rate) => {
+                let (sin, cos) = (rate * p.y).sin_cos();
+                let twisted = Vec3::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z);
+                let radial = (p.x * p.x + p.z * p.z).sqrt();
+                expr.distance(twisted) / (1.0 + (rate * radial).powi(2)).sqrt()
+            },
+            SdfExpr::Displace(expr, amplitude, frequency) => {
+                let wave = (frequency * p.x).sin() * (frequency * p.y).sin() * (frequency * p.z).sin();
+                let displacement = amplitude * wave;
+                (expr.distance(p) + displacement) / (1.0 + amplitude.abs() * frequency.abs() * 3.0_f64.sqrt())
+            }
+        }
+    }
+}
+
+
+/// Distance estimator of the Mandelbulb fractal
+fn mandelbulb(p: Vec3, power: f64, iterations: u32) -> f64 {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = z.length();
+
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+
+        let theta = (z.z / r).clamp(-1.0, 1.0).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = power * r.powf(power - 1.0) * dr + 1.0;
+
+        let zr = r.powf(power);
+        z = zr * Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) + p;
+        r = z.length();
+    }
+
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+
+/// Object defined by a signed distance expression, intersected by sphere tracing
+pub struct SdfObject {
+    expr: SdfExpr,
+    max_steps: u32,
+    epsilon: f64,
+    max_distance: f64
+}
+
+
+impl SdfObject {
+
+    /// Marching stops after `max_steps`, or once closer than `epsilon` to the surface. It should stay below the offset
+    /// of spawned rays, otherwise secondary rays would hit the surface they leave.
+    pub fn new(expr: SdfExpr, max_steps: u32, epsilon: f64) -> Self {
+        Self {
+            expr: expr,
+            max_steps: max_steps,
+            epsilon: epsilon,
+            max_distance: 1e4
+        }
+    }
+
+
+    /// Gradient of the distance, estimated with the tetrahedron technique
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let k0 = Vec3::new(1.0, -1.0, -1.0);
+        let k1 = Vec3::new(-1.0, -1.0, 1.0);
+        let k2 = Vec3::new(-1.0, 1.0, -1.0);
+        let k3 = Vec3::new(1.0, 1.0, 1.0);
+
+        let gradient = self.expr.distance(p + h * k0) * k0
+            + self.expr.distance(p + h * k1) * k1
+            + self.expr.distance(p + h * k2) * k2
+            + self.expr.distance(p + h * k3) * k3;
+
+        if gradient.length_sq() == 0.0 {
+            return Vec3::UNIT_Y;
+        }
+        gradient.normalized()
+    }
+}
+
+
+impl Object for SdfObject {
+    fn hit(&self, ray: &Ray, interval: &Interval) -> Option<HitInfo> {
+        let direction_length = ray.direction.length();
+        let end = interval.end().min(self.max_distance / direction_length);
+
+        let mut t = interval.start().max(0.0);
+        // Marching on the side the ray starts from, so that rays leaving the inside of the shape work too
+        let side = self.expr.distance(ray.at(t)).signum();
+
+        for _ in 0..self.max_steps {
+            if t >= end {
+                return None;
+            }
+
+            let distance = side * self.expr.distance(ray.at(t));
+            if distance < self.epsilon {
+                if !interval.surrounds(t) {
+                    return None;
+                }
+
+                let position = ray.at(t);
+                let normal = self.normal(position);
+                let frame = Frame::from_normal(normal);
+                return Some(oriented_hit(ray, t, normal, vec2!(0.0, 0.0), frame.x, frame.y));
+            }
+
+            t += distance / direction_length;
+        }
+
+        None
+    }
+
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None // Expressions may be unbounded, e.g. repeated shapes
+    }
+}
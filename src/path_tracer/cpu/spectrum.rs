@@ -0,0 +1,262 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::sync::OnceLock;
+
+use simple_term_renderer::math::Vec3;
+
+use crate::path_tracer::sampler::random;
+
+use super::mul_elem;
+
+
+/// Range of visible wavelengths, in nanometers
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+
+/// CIE 1931 colour matching functions, using the multi-lobe fit of Wyman, Sloan and Shirley (2013)
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let lobe = |mu: f64, sigma_low: f64, sigma_high: f64| {
+        let sigma = if lambda < mu { sigma_low } else { sigma_high };
+        let x = (lambda - mu) / sigma;
+        (-0.5 * x * x).exp()
+    };
+
+    Vec3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8)
+    )
+}
+
+
+/// Converts CIE XYZ to the linear sRGB primaries used by the renderer
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z
+    )
+}
+
+
+/// Spectral radiance of a black body at `temperature` kelvins (Planck's law), in W.sr⁻¹.m⁻².nm⁻¹
+pub fn planck(lambda: f64, temperature: f64) -> f64 {
+    if temperature <= 0.0 {
+        return 0.0;
+    }
+
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62606957e-34;
+    const KB: f64 = 1.3806488e-23;
+
+    let l = lambda * 1e-9;
+    let radiance = 2.0 * H * C * C / (l.powi(5) * ((H * C / (l * KB * temperature)).exp() - 1.0));
+    radiance * 1e-9
+}
+
+
+/// CIE XYZ colour of a black body, in physical units
+fn blackbody_xyz(temperature: f64) -> Vec3 {
+    let mut xyz = Vec3::ZERO;
+    let step = 5.0;
+    let mut lambda = LAMBDA_MIN;
+
+    while lambda <= LAMBDA_MAX {
+        xyz += (step * planck(lambda, temperature)) * cie_xyz(lambda);
+        lambda += step;
+    }
+
+    xyz
+}
+
+
+/// Colour of a black body at `temperature` kelvins, normalized to a unit luminance
+pub fn blackbody_rgb(temperature: f64) -> Vec3 {
+    blackbody(temperature, BlackbodyUnits::Normalized)
+}
+
+
+/// Scale of the radiance emitted by a black body
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlackbodyUnits {
+    /// Unit luminance whatever the temperature, only the colour changes
+    Normalized,
+    /// Radiance integrated against the colour matching functions, in W.sr⁻¹.m⁻². It grows quickly with the
+    /// temperature, a body at 5800 K having a luminance around 2.5e6.
+    Physical
+}
+
+
+/// Linear RGB radiance of a black body at `temperature` kelvins. Colours outside of the gamut are clamped.
+pub fn blackbody(temperature: f64, units: BlackbodyUnits) -> Vec3 {
+    let xyz = blackbody_xyz(temperature);
+    if xyz.y <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let rgb = match units {
+        BlackbodyUnits::Normalized => xyz_to_rgb(xyz / xyz.y),
+        BlackbodyUnits::Physical => xyz_to_rgb(xyz)
+    };
+    Vec3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+}
+
+
+/// Wavelength dependent index of refraction, wavelengths being converted to micrometers
+#[derive(Debug, Copy, Clone)]
+pub enum Dispersion {
+    /// `n = a + b / λ²`
+    Cauchy { a: f64, b: f64 },
+    /// `n² = 1 + Σ bᵢ λ² / (λ² - cᵢ)`
+    Sellmeier { b: [f64; 3], c: [f64; 3] }
+}
+
+
+impl Dispersion {
+    /// Borosilicate crown glass
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653]
+    };
+
+    /// Dense flint glass, strongly dispersive
+    pub const SF11: Dispersion = Dispersion::Sellmeier {
+        b: [1.73759695, 0.313747346, 1.89878101],
+        c: [0.013188707, 0.0623068142, 155.23629]
+    };
+
+    pub const WATER: Dispersion = Dispersion::Cauchy { a: 1.3199, b: 0.00306 };
+
+
+    /// Index of refraction at `lambda` nanometers
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda * 1e-3).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f64 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+
+/// Spectral reflectance or radiance of an RGB colour at `lambda`, a weighted sum of smooth basis functions adding up
+/// to one, so that white stays flat
+pub fn rgb_to_spectrum(rgb: Vec3, lambda: f64) -> f64 {
+    let smoothstep = |edge0: f64, edge1: f64| {
+        let x = ((lambda - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+        x * x * (3.0 - 2.0 * x)
+    };
+
+    let red = smoothstep(565.0, 615.0);
+    let blue = 1.0 - smoothstep(465.0, 515.0);
+    let green = 1.0 - red - blue;
+    (rgb.x * red + rgb.y * green + rgb.z * blue).max(0.0)
+}
+
+
+/// Colour matching integrals of a flat unit spectrum, used to normalize spectral samples
+struct SpectralWhite {
+    /// Integral of the `y` matching function
+    y_integral: f64,
+    /// Linear RGB colour of the flat spectrum, normalized to a unit luminance
+    rgb: Vec3
+}
+
+
+fn spectral_white() -> &'static SpectralWhite {
+    static WHITE: OnceLock<SpectralWhite> = OnceLock::new();
+    WHITE.get_or_init(|| {
+        let mut xyz = Vec3::ZERO;
+        let step = 1.0;
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            xyz += step * cie_xyz(lambda);
+            lambda += step;
+        }
+
+        SpectralWhite {
+            y_integral: xyz.y,
+            rgb: xyz_to_rgb(xyz / xyz.y)
+        }
+    })
+}
+
+
+/// Wavelengths carried by a path, one per lane of a `Vec3`. The first one is the hero wavelength, the others are
+/// evenly rotated from it over the visible range.
+#[derive(Debug, Copy, Clone)]
+pub struct Wavelengths {
+    lambda: [f64; 3]
+}
+
+
+impl Wavelengths {
+
+    /// Uniformly samples a hero wavelength
+    pub fn sample() -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = random() * range;
+        let lane = |i: usize| LAMBDA_MIN + (hero + i as f64 * range / 3.0) % range;
+        Self {
+            lambda: [lane(0), lane(1), lane(2)]
+        }
+    }
+
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+
+    /// Values of an RGB colour at each wavelength
+    pub fn upsample(&self, rgb: Vec3) -> Vec3 {
+        Vec3::new(
+            rgb_to_spectrum(rgb, self.lambda[0]),
+            rgb_to_spectrum(rgb, self.lambda[1]),
+            rgb_to_spectrum(rgb, self.lambda[2])
+        )
+    }
+
+
+    /// Weights of a path whose secondary wavelengths can not follow the hero one, e.g. through a dispersive interface
+    pub fn terminate_secondary(weight: Vec3) -> Vec3 {
+        Vec3::new(3.0 * weight.x, 0.0, 0.0)
+    }
+
+
+    /// Linear RGB colour of the radiance carried at each wavelength. A flat spectrum gives a grey colour.
+    pub fn to_rgb(&self, radiance: Vec3) -> Vec3 {
+        let white = spectral_white();
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+
+        // Monte Carlo estimate of the colour matching integrals, the wavelengths being uniformly sampled
+        let lanes = [radiance.x, radiance.y, radiance.z];
+        let mut xyz = Vec3::ZERO;
+        for (lambda, value) in self.lambda.iter().zip(lanes) {
+            xyz += (value * range / 3.0) * cie_xyz(*lambda);
+        }
+
+        let rgb = xyz_to_rgb(xyz / white.y_integral);
+        mul_elem(rgb, Vec3::new(1.0 / white.rgb.x, 1.0 / white.rgb.y, 1.0 / white.rgb.z))
+    }
+}
@@ -0,0 +1,436 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use simple_term_renderer::math::Vec3;
+
+use crate::HitInfo;
+
+use super::lerp;
+
+
+/// Spatially varying value plugged into material slots (albedo, roughness, emission...)
+pub trait Texture {
+    fn value(&self, hit_info: &HitInfo) -> Vec3;
+}
+
+
+pub struct ConstantTexture {
+    value: Vec3
+}
+
+
+impl ConstantTexture {
+
+    pub fn new(value: Vec3) -> Self {
+        Self {
+            value: value
+        }
+    }
+}
+
+
+impl Texture for ConstantTexture {
+    fn value(&self, _hit_info: &HitInfo) -> Vec3 {
+        self.value
+    }
+}
+
+
+/// Alternates between two textures on a grid of `scale` cells per unit of UV
+pub struct CheckerTexture {
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    scale: f64
+}
+
+
+impl CheckerTexture {
+
+    pub fn new(even: Arc<dyn Texture>, odd: Arc<dyn Texture>, scale: f64) -> Self {
+        Self {
+            even: even,
+            odd: odd,
+            scale: scale
+        }
+    }
+}
+
+
+impl Texture for CheckerTexture {
+    fn value(&self, hit_info: &HitInfo) -> Vec3 {
+        let cell_u = (self.scale * hit_info.uv.x).floor() as i64;
+        let cell_v = (self.scale * hit_info.uv.y).floor() as i64;
+
+        if (cell_u + cell_v).rem_euclid(2) == 0 {
+            self.even.value(hit_info)
+        } else {
+            self.odd.value(hit_info)
+        }
+    }
+}
+
+
+/// Improved Perlin gradient noise
+pub struct Perlin {
+    permutation: [u8; 512]
+}
+
+
+impl Perlin {
+
+    pub fn new(seed: u64) -> Self {
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let mut permutation = [0; 512];
+        for (i, value) in permutation.iter_mut().enumerate() {
+            *value = table[i % 256];
+        }
+
+        Self {
+            permutation: permutation
+        }
+    }
+
+
+    fn fade(t: f64) -> f64 {
+        t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+    }
+
+
+    fn gradient(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+
+
+    /// Noise value in [-1; 1]
+    pub fn noise(&self, point: Vec3) -> f64 {
+        let (xf, yf, zf) = (point.x.floor(), point.y.floor(), point.z.floor());
+        let xi = (xf as i64).rem_euclid(256) as usize;
+        let yi = (yf as i64).rem_euclid(256) as usize;
+        let zi = (zf as i64).rem_euclid(256) as usize;
+        let (x, y, z) = (point.x - xf, point.y - yf, point.z - zf);
+
+        let (u, v, w) = (Self::fade(x), Self::fade(y), Self::fade(z));
+        let p = &self.permutation;
+
+        let a = p[xi] as usize + yi;
+        let aa = p[a] as usize + zi;
+        let ab = p[a + 1] as usize + zi;
+        let b = p[xi + 1] as usize + yi;
+        let ba = p[b] as usize + zi;
+        let bb = p[b + 1] as usize + zi;
+
+        lerp(w,
+            lerp(v,
+                lerp(u, Self::gradient(p[aa], x, y, z), Self::gradient(p[ba], x - 1.0, y, z)),
+                lerp(u, Self::gradient(p[ab], x, y - 1.0, z), Self::gradient(p[bb], x - 1.0, y - 1.0, z))
+            ),
+            lerp(v,
+                lerp(u, Self::gradient(p[aa + 1], x, y, z - 1.0), Self::gradient(p[ba + 1], x - 1.0, y, z - 1.0)),
+                lerp(u,
+                    Self::gradient(p[ab + 1], x, y - 1.0, z - 1.0),
+                    Self::gradient(p[bb + 1], x - 1.0, y - 1.0, z - 1.0)
+                )
+            )
+        )
+    }
+
+
+    /// Fractal Brownian motion: sum of `octaves` layers of noise, each twice as detailed and half as strong
+    pub fn fbm(&self, point: Vec3, octaves: u32) -> f64 {
+        let mut value = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut normalization = 0.0;
+
+        for _ in 0..octaves.max(1) {
+            value += amplitude * self.noise(frequency * point);
+            normalization += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+
+        value / normalization
+    }
+}
+
+
+/// Solid fBm noise blending between two colors
+pub struct NoiseTexture {
+    perlin: Perlin,
+    scale: f64,
+    octaves: u32,
+    low: Vec3,
+    high: Vec3
+}
+
+
+impl NoiseTexture {
+
+    pub fn new(seed: u64, scale: f64, octaves: u32, low: Vec3, high: Vec3) -> Self {
+        Self {
+            perlin: Perlin::new(seed),
+            scale: scale,
+            octaves: octaves,
+            low: low,
+            high: high
+        }
+    }
+}
+
+
+impl Texture for NoiseTexture {
+    fn value(&self, hit_info: &HitInfo) -> Vec3 {
+        let t = (0.5 * (1.0 + self.perlin.fbm(self.scale * hit_info.position, self.octaves))).clamp(0.0, 1.0);
+        (1.0 - t) * self.low + t * self.high
+    }
+}
+
+
+/// How UV coordinates outside of [0; 1] are handled
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WrapMode {
+    Repeat,
+    Mirror,
+    Clamp
+}
+
+
+impl WrapMode {
+
+    fn apply(&self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(size),
+            WrapMode::Mirror => {
+                let period = index.rem_euclid(2 * size);
+                if period < size { period } else { 2 * size - 1 - period }
+            },
+            WrapMode::Clamp => index.clamp(0, size - 1)
+        };
+        index as usize
+    }
+}
+
+
+/// Bilinearly filtered image, loaded from PNG or PPM files
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    wrap: WrapMode
+}
+
+
+impl ImageTexture {
+
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>, wrap: WrapMode) -> Self {
+        assert_eq!(pixels.len(), width * height, "the pixel count does not match the image size");
+        Self {
+            width: width,
+            height: height,
+            pixels: pixels,
+            wrap: wrap
+        }
+    }
+
+
+    /// Loads a PNG or a PPM (P3 or P6) image, chosen from the extension of `path`.
+    ///
+    /// When `color` is set, the image is considered gamma encoded like the render output and is linearized.
+    pub fn load(path: &Path, wrap: WrapMode, color: bool) -> io::Result<Self> {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        let (width, height, mut pixels) = match extension.as_deref() {
+            Some("png") => Self::read_png(path)?,
+            Some("ppm") => Self::read_ppm(path)?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported image format"))
+        };
+
+        if color {
+            for pixel in pixels.iter_mut() {
+                *pixel = Vec3::new(pixel.x * pixel.x, pixel.y * pixel.y, pixel.z * pixel.z);
+            }
+        }
+
+        Ok(Self::new(width, height, pixels, wrap))
+    }
+
+
+    fn read_png(path: &Path) -> io::Result<(usize, usize, Vec<Vec3>)> {
+        let invalid_data = |err: png::DecodingError| io::Error::new(io::ErrorKind::InvalidData, err);
+
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(invalid_data)?;
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(invalid_data)?;
+        let bytes = &buffer[..info.buffer_size()];
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            png::ColorType::Indexed => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "indexed PNG was not expanded"));
+            }
+        };
+
+        let pixels = bytes.chunks_exact(channels)
+            .map(|texel| {
+                let channel = |i: usize| texel[i] as f64 / 255.0;
+                if channels < 3 {
+                    Vec3::new(channel(0), channel(0), channel(0))
+                } else {
+                    Vec3::new(channel(0), channel(1), channel(2))
+                }
+            })
+            .collect();
+
+        Ok((info.width as usize, info.height as usize, pixels))
+    }
+
+
+    fn read_ppm(path: &Path) -> io::Result<(usize, usize, Vec<Vec3>)> {
+        let invalid_data = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+
+        // Reads the header tokens, skipping whitespaces and comments
+        let mut cursor = 0;
+        let mut next_token = |data: &[u8]| -> Option<String> {
+            loop {
+                while cursor < data.len() && data[cursor].is_ascii_whitespace() {
+                    cursor += 1;
+                }
+                if cursor < data.len() && data[cursor] == b'#' {
+                    while cursor < data.len() && data[cursor] != b'\n' {
+                        cursor += 1;
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            let start = cursor;
+            while cursor < data.len() && !data[cursor].is_ascii_whitespace() {
+                cursor += 1;
+            }
+            (start < cursor).then(|| String::from_utf8_lossy(&data[start..cursor]).into_owned())
+        };
+
+        let magic = next_token(&data).ok_or_else(|| invalid_data("missing PPM magic number"))?;
+        let mut header = [0usize; 3];
+        for value in header.iter_mut() {
+            *value = next_token(&data)
+                .and_then(|token| token.parse().ok())
+                .ok_or_else(|| invalid_data("invalid PPM header"))?;
+        }
+        let [width, height, max_value] = header;
+        if max_value == 0 || max_value > 65535 {
+            return Err(invalid_data("invalid PPM maximum value"));
+        }
+
+        // Every sample takes at least a byte of the file, which bounds the size given by a corrupted header
+        let count = width.checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .filter(|count| *count <= data.len())
+            .ok_or_else(|| invalid_data("invalid PPM size"))?;
+        let samples: Vec<f64> = match magic.as_str() {
+            "P3" => {
+                let mut samples = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let sample: usize = next_token(&data)
+                        .and_then(|token| token.parse().ok())
+                        .ok_or_else(|| invalid_data("truncated PPM data"))?;
+                    samples.push(sample as f64 / max_value as f64);
+                }
+                samples
+            },
+            "P6" => {
+                let start = cursor + 1; // Single whitespace after the header
+                let sample_size = if max_value < 256 { 1 } else { 2 };
+                let bytes = data.get(start..start + count * sample_size)
+                    .ok_or_else(|| invalid_data("truncated PPM data"))?;
+
+                bytes.chunks_exact(sample_size)
+                    .map(|sample| {
+                        let value = if sample_size == 1 {
+                            sample[0] as usize
+                        } else {
+                            (sample[0] as usize) << 8 | sample[1] as usize
+                        };
+                        value as f64 / max_value as f64
+                    })
+                    .collect()
+            },
+            _ => return Err(invalid_data("unsupported PPM variant"))
+        };
+
+        let pixels = samples.chunks_exact(3)
+            .map(|rgb| Vec3::new(rgb[0], rgb[1], rgb[2]))
+            .collect();
+
+        Ok((width, height, pixels))
+    }
+
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.pixels[y * self.width + x]
+    }
+}
+
+
+impl Texture for ImageTexture {
+    fn value(&self, hit_info: &HitInfo) -> Vec3 {
+        if self.pixels.is_empty() {
+            return Vec3::ZERO;
+        }
+
+        // The v axis goes up while the image rows go down
+        let x = hit_info.uv.x * self.width as f64 - 0.5;
+        let y = (1.0 - hit_info.uv.y) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        (1.0 - dx) * (1.0 - dy) * self.texel(x0, y0)
+            + dx * (1.0 - dy) * self.texel(x0 + 1, y0)
+            + (1.0 - dx) * dy * self.texel(x0, y0 + 1)
+            + dx * dy * self.texel(x0 + 1, y0 + 1)
+    }
+}
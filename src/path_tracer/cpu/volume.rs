@@ -0,0 +1,355 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use simple_term_renderer::math::Vec3;
+
+use crate::Ray;
+use crate::path_tracer::sampler::random;
+
+use super::{mul_elem, Aabb};
+use super::medium::{HenyeyGreenstein, Medium, MediumEvent};
+use super::spectrum::blackbody_rgb;
+use super::tex::Perlin;
+
+
+/// Parameters of a voxel grid medium. Coefficients are given per unit of distance, for a density of one.
+pub struct VolumeParameters {
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    /// Asymmetry of the Henyey-Greenstein phase function
+    pub g: f64,
+    pub density_scale: f64,
+    /// Strength of the black body emission driven by the temperature channel. Only absorbing media emit light.
+    pub emission_scale: f64
+}
+
+
+impl Default for VolumeParameters {
+    fn default() -> Self {
+        Self {
+            sigma_a: Vec3::new(0.05, 0.05, 0.05),
+            sigma_s: Vec3::new(1.0, 1.0, 1.0),
+            g: 0.0,
+            density_scale: 1.0,
+            emission_scale: 0.0
+        }
+    }
+}
+
+
+/// Dense grid of values sampled at the center of the voxels, `x` varying first
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    values: Vec<f64>
+}
+
+
+impl VoxelGrid {
+
+    pub fn new(resolution: [usize; 3], values: Vec<f64>) -> Self {
+        assert_eq!(values.len(), resolution.iter().product::<usize>(), "the value count does not match the grid size");
+        Self {
+            resolution: resolution,
+            values: values
+        }
+    }
+
+
+    pub fn max_value(&self) -> f64 {
+        self.values.iter().copied().fold(0.0, f64::max)
+    }
+
+
+    fn value(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.values[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+
+
+    /// Trilinear lookup, `point` being in the unit cube covered by the grid
+    pub fn lookup(&self, point: Vec3) -> f64 {
+        let coordinate = |value: f64, axis: usize| {
+            let size = self.resolution[axis];
+            let continuous = (value * size as f64 - 0.5).clamp(0.0, (size - 1) as f64);
+            let index = (continuous as usize).min(size.saturating_sub(2));
+            (index, (index + 1).min(size - 1), continuous - index as f64)
+        };
+
+        let (x0, x1, fx) = coordinate(point.x, 0);
+        let (y0, y1, fy) = coordinate(point.y, 1);
+        let (z0, z1, fz) = coordinate(point.z, 2);
+
+        let along_x = |y: usize, z: usize| (1.0 - fx) * self.value(x0, y, z) + fx * self.value(x1, y, z);
+        let along_y = |z: usize| (1.0 - fy) * along_x(y0, z) + fy * along_x(y1, z);
+        (1.0 - fz) * along_y(z0) + fz * along_y(z1)
+    }
+
+
+    /// Reads a grid file: a text header `VOL <nx> <ny> <nz> <channels>` ended by a newline, followed by the voxels as
+    /// little endian `f32`, `x` varying first. The channels of a voxel are interleaved, the first one being the
+    /// density and the optional second one the temperature in kelvins.
+    pub fn load(path: &Path) -> io::Result<(VoxelGrid, Option<VoxelGrid>)> {
+        let invalid_data = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+
+        let header_end = data.iter().position(|byte| *byte == b'\n').ok_or_else(|| invalid_data("missing header"))?;
+        let header = String::from_utf8_lossy(&data[..header_end]).into_owned();
+        let mut tokens = header.split_whitespace();
+        if tokens.next() != Some("VOL") {
+            return Err(invalid_data("not a voxel grid file"));
+        }
+
+        let fields = tokens.map(|token| token.parse::<usize>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid_data("invalid header"))?;
+        let &[nx, ny, nz, channels] = fields.as_slice() else {
+            return Err(invalid_data("invalid header"));
+        };
+        if nx == 0 || ny == 0 || nz == 0 || !(1..=2).contains(&channels) {
+            return Err(invalid_data("invalid grid size or channel count"));
+        }
+
+        let count = nx.checked_mul(ny)
+            .and_then(|count| count.checked_mul(nz))
+            .ok_or_else(|| invalid_data("invalid grid size"))?;
+        let size = count.checked_mul(4 * channels).ok_or_else(|| invalid_data("invalid grid size"))?;
+        let body = &data[header_end + 1..];
+        if body.len() < size {
+            return Err(invalid_data("truncated voxel data"));
+        }
+
+        let channel = |index: usize| -> Vec<f64> {
+            (0..count).map(|voxel| {
+                let offset = 4 * (voxel * channels + index);
+                f32::from_le_bytes([body[offset], body[offset + 1], body[offset + 2], body[offset + 3]]) as f64
+            }).collect()
+        };
+
+        let density = VoxelGrid::new([nx, ny, nz], channel(0));
+        let temperature = (channels == 2).then(|| VoxelGrid::new([nx, ny, nz], channel(1)));
+        Ok((density, temperature))
+    }
+
+
+    /// Cloud-like density made of fBm noise fading towards the border of the grid
+    pub fn from_noise(resolution: usize, seed: u64, scale: f64, octaves: u32) -> VoxelGrid {
+        let perlin = Perlin::new(seed);
+        let mut values = Vec::with_capacity(resolution.pow(3));
+
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    // Coordinates in [-1; 1]
+                    let coordinate = |i: usize| 2.0 * (i as f64 + 0.5) / resolution as f64 - 1.0;
+                    let point = Vec3::new(coordinate(x), coordinate(y), coordinate(z));
+
+                    let falloff = 1.0 - point.length_sq();
+                    values.push((falloff + 0.6 * perlin.fbm(scale * point, octaves)).max(0.0));
+                }
+            }
+        }
+
+        VoxelGrid::new([resolution; 3], values)
+    }
+}
+
+
+/// Black body colours tabulated over a range of temperatures
+struct BlackbodyTable {
+    step: f64,
+    colors: Vec<Vec3>
+}
+
+
+impl BlackbodyTable {
+
+    const SIZE: usize = 256;
+
+
+    fn new(max_temperature: f64) -> Self {
+        let step = max_temperature.max(1.0) / (Self::SIZE - 1) as f64;
+        Self {
+            step: step,
+            colors: (0..Self::SIZE).map(|i| blackbody_rgb(i as f64 * step)).collect()
+        }
+    }
+
+
+    fn color(&self, temperature: f64) -> Vec3 {
+        let index = ((temperature / self.step).round().max(0.0) as usize).min(Self::SIZE - 1);
+        self.colors[index]
+    }
+}
+
+
+/// Heterogeneous medium whose density comes from a voxel grid stretched over a box, free of any medium outside of
+/// it. Free flights are sampled with delta tracking and transmittance estimated with ratio tracking.
+pub struct GridMedium {
+    bounds: Aabb,
+    density: VoxelGrid,
+    temperature: Option<(VoxelGrid, BlackbodyTable)>,
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    emission_scale: f64,
+    /// Upper bound of the extinction coefficient over the grid and the colour channels
+    majorant: f64,
+    phase: HenyeyGreenstein
+}
+
+
+impl GridMedium {
+
+    pub fn new(bounds: Aabb, density: VoxelGrid, temperature: Option<VoxelGrid>, params: &VolumeParameters) -> Self {
+        let sigma_a = params.density_scale * params.sigma_a;
+        let sigma_s = params.density_scale * params.sigma_s;
+        let sigma_t = sigma_a + sigma_s;
+        let majorant = density.max_value() * sigma_t.x.max(sigma_t.y).max(sigma_t.z);
+
+        Self {
+            bounds: bounds,
+            temperature: temperature.map(|grid| {
+                let table = BlackbodyTable::new(grid.max_value());
+                (grid, table)
+            }),
+            density: density,
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            emission_scale: params.emission_scale,
+            majorant: majorant,
+            phase: HenyeyGreenstein::new(params.g)
+        }
+    }
+
+
+    /// Position in the unit cube covered by the grid
+    fn grid_point(&self, point: Vec3) -> Vec3 {
+        let local = point - self.bounds.min;
+        let extent = self.bounds.extent();
+        Vec3::new(local.x / extent.x, local.y / extent.y, local.z / extent.z)
+    }
+
+
+    fn emission(&self, grid_point: Vec3) -> Vec3 {
+        match &self.temperature {
+            Some((grid, table)) if self.emission_scale > 0.0 => {
+                self.emission_scale * table.color(grid.lookup(grid_point))
+            },
+            _ => Vec3::ZERO
+        }
+    }
+
+
+    /// Ray parameters of the part of the ray inside of the grid, before `t_max`
+    fn segment(&self, ray: &Ray, t_max: f64) -> Option<(f64, f64)> {
+        let (enter, exit) = self.bounds.hit_range(ray.origin, ray.direction)?;
+        let (start, end) = (enter.max(0.0), exit.min(t_max));
+        (start < end).then_some((start, end))
+    }
+
+
+    /// Samples the ray parameter of the next tentative collision
+    fn next_collision(&self, t: f64, speed: f64) -> f64 {
+        t - (1.0 - random()).ln() / (self.majorant * speed)
+    }
+}
+
+
+impl Medium for GridMedium {
+    fn sample(&self, ray: &Ray, t_max: f64) -> MediumEvent {
+        let mut weight = Vec3::new(1.0, 1.0, 1.0);
+        let mut emitted = Vec3::ZERO;
+
+        let Some((start, end)) = self.segment(ray, t_max).filter(|_| self.majorant > 0.0) else {
+            return MediumEvent::Pass { weight: weight, emitted: emitted };
+        };
+        let speed = ray.direction.length();
+        let mut t = start;
+
+        loop {
+            t = self.next_collision(t, speed);
+            if t >= end {
+                return MediumEvent::Pass { weight: weight, emitted: emitted };
+            }
+
+            let point = self.grid_point(ray.at(t));
+            let density = self.density.lookup(point);
+            let sigma_a = density * self.sigma_a;
+            let sigma_s = density * self.sigma_s;
+
+            // Emission is accounted for at every tentative collision
+            emitted += (1.0 / self.majorant) * mul_elem(weight, mul_elem(sigma_a, self.emission(point)));
+
+            // Events are chosen with the average of the channels, the weight correcting for each channel
+            let p_absorb = (sigma_a.x + sigma_a.y + sigma_a.z) / (3.0 * self.majorant);
+            let p_scatter = (sigma_s.x + sigma_s.y + sigma_s.z) / (3.0 * self.majorant);
+            let p_null = (1.0 - p_absorb - p_scatter).max(0.0);
+            let u = random();
+
+            if u < p_absorb {
+                return MediumEvent::Absorb { emitted: emitted };
+            }
+
+            if u < p_absorb + p_scatter {
+                return MediumEvent::Scatter {
+                    distance: t,
+                    weight: (1.0 / (self.majorant * p_scatter)) * mul_elem(weight, sigma_s),
+                    emitted: emitted
+                };
+            }
+
+            if p_null <= 0.0 {
+                return MediumEvent::Absorb { emitted: emitted };
+            }
+            let sigma_n = self.majorant * Vec3::new(1.0, 1.0, 1.0) - sigma_a - sigma_s;
+            weight = (1.0 / (self.majorant * p_null)) * mul_elem(weight, sigma_n);
+        }
+    }
+
+
+    fn transmittance(&self, ray: &Ray, t_max: f64) -> Vec3 {
+        let mut transmittance = Vec3::new(1.0, 1.0, 1.0);
+
+        let Some((start, end)) = self.segment(ray, t_max).filter(|_| self.majorant > 0.0) else {
+            return transmittance;
+        };
+        let speed = ray.direction.length();
+        let mut t = start;
+
+        // Ratio tracking: every tentative collision attenuates by the probability of a null collision
+        loop {
+            t = self.next_collision(t, speed);
+            if t >= end {
+                return transmittance;
+            }
+
+            let density = self.density.lookup(self.grid_point(ray.at(t)));
+            let sigma_t = density * (self.sigma_a + self.sigma_s);
+            let null = Vec3::new(1.0, 1.0, 1.0) - (1.0 / self.majorant) * sigma_t;
+            transmittance = mul_elem(transmittance, null);
+        }
+    }
+
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
@@ -16,9 +16,12 @@ OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
 */
 
 
-use rand::random;
+use std::f64::consts::TAU;
+
 use simple_term_renderer::math::Vec3;
 
+use super::sampler::random;
+
 
 
 #[derive(Debug, Copy, Clone)]
@@ -37,6 +40,16 @@ impl Interval {
     }
 
 
+    pub fn start(&self) -> f64 {
+        self.start
+    }
+
+
+    pub fn end(&self) -> f64 {
+        self.end
+    }
+
+
     pub fn contains(&self, t: f64) -> bool {
         self.start <= t && t <= self.end
     }
@@ -49,7 +62,7 @@ impl Interval {
 
 
 pub fn random_unit_vec() -> Vec3 {
-    let vec = 2.0 * Vec3::new(random::<f64>() - 0.5, random::<f64>() - 0.5, random::<f64>() - 0.5);
+    let vec = 2.0 * Vec3::new(random() - 0.5, random() - 0.5, random() - 0.5);
     vec.normalized()
 }
 
@@ -74,6 +87,338 @@ pub fn is_approx_zero(vec: Vec3) -> bool {
 }
 
 
+/// Refracts `vec` (pointing away from the surface) through a surface of normal `normal` with relative index of
+/// refraction `eta`. Returns the refracted direction and the relative index that was actually used, or `None` on
+/// total internal reflection.
+pub fn refract(vec: Vec3, normal: Vec3, eta: f64) -> Option<(Vec3, f64)> {
+    let mut cos_i = normal.dot(vec);
+    let mut eta = eta;
+    let mut normal = normal;
+
+    if cos_i < 0.0 { // The vector is on the inside of the surface
+        eta = 1.0 / eta;
+        cos_i = -cos_i;
+        normal = -normal;
+    }
+
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = sin2_i / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None; // Total internal reflection
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some((-vec / eta + (cos_i / eta - cos_t) * normal, eta))
+}
+
+
+/// Component-wise product of two vectors
+pub fn mul_elem(a: Vec3, b: Vec3) -> Vec3 {
+    Vec3::new(a.x * b.x, a.y * b.y, a.z * b.z)
+}
+
+
+pub fn lerp_vec(t: f64, a: Vec3, b: Vec3) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+
+pub fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    (1.0 - t) * a + t * b
+}
+
+
+/// Uniformly samples a point on the unit disk using polar coordinates
+pub fn sample_uniform_disk_polar(u: (f64, f64)) -> (f64, f64) {
+    let r = u.0.sqrt();
+    let theta = TAU * u.1;
+    (r * theta.cos(), r * theta.sin())
+}
+
+
+/// Samples a direction on the `z` up hemisphere with a density of `cos(theta) / pi`
+pub fn sample_cosine_hemisphere() -> Vec3 {
+    let (x, y) = sample_uniform_disk_polar((random(), random()));
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    Vec3::new(x, y, z)
+}
+
+
+/// Uniformly samples a direction on the unit sphere
+pub fn sample_uniform_sphere() -> Vec3 {
+    let z = 1.0 - 2.0 * random();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = TAU * random();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+
+/// Weight of a sample taken with the density `pdf` when another technique could have sampled it with the density
+/// `other_pdf`, using the power heuristic with an exponent of 2
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+
+/// Relative luminance of a linear RGB color
+pub fn luminance(color: Vec3) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+
+/// Real roots of `a x² + b x + c`, in increasing order
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        let root = -c / b;
+        return Some((root, root));
+    }
+
+    let delta = b * b - 4.0 * a * c;
+    if delta < 0.0 {
+        return None;
+    }
+
+    // Avoids the cancellation of `-b + sqrt(delta)`
+    let q = -0.5 * (b + b.signum() * delta.sqrt());
+    if q == 0.0 {
+        return Some((0.0, 0.0)); // b = c = 0
+    }
+    let (r0, r1) = (q / a, c / q);
+    Some((r0.min(r1), r0.max(r1)))
+}
+
+
+/// Largest real root of the monic cubic `x³ + a x² + b x + c`
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    // Depressed cubic t³ + p t + q, with x = t - a / 3
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let shift = -a / 3.0;
+
+    let delta = (q / 2.0).powi(2) + (p / 3.0).powi(3);
+    if delta > 0.0 { // Single real root (Cardano)
+        let sqrt_delta = delta.sqrt();
+        return (-q / 2.0 + sqrt_delta).cbrt() + (-q / 2.0 - sqrt_delta).cbrt() + shift;
+    }
+
+    if p == 0.0 {
+        return shift;
+    }
+
+    // Three real roots (trigonometric method), the first one being the largest
+    let radius = 2.0 * (-p / 3.0).sqrt();
+    let angle = (3.0 * q / (p * radius)).clamp(-1.0, 1.0).acos() / 3.0;
+    radius * angle.cos() + shift
+}
+
+
+/// Real roots of the monic quartic `x⁴ + a x³ + b x² + c x + d` (Ferrari's method), polished with Newton iterations.
+/// The roots are given in increasing order.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Depressed quartic y⁴ + p y² + q y + r, with x = y - a / 4
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+    let shift = -a / 4.0;
+
+    let mut roots = Vec::with_capacity(4);
+    let mut push_quadratic_roots = |b: f64, c: f64| {
+        if let Some((y0, y1)) = solve_quadratic(1.0, b, c) {
+            roots.push(y0 + shift);
+            roots.push(y1 + shift);
+        }
+    };
+
+    if q.abs() < 1e-12 { // Biquadratic equation
+        if let Some((z0, z1)) = solve_quadratic(1.0, p, r) {
+            for z in [z0, z1] {
+                if z >= 0.0 {
+                    push_quadratic_roots(0.0, -z);
+                }
+            }
+        }
+    } else {
+        // Resolvent cubic, whose root makes both sides of the equation perfect squares
+        let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+        let sqrt_2m = (2.0 * m).sqrt();
+        push_quadratic_roots(-sqrt_2m, p / 2.0 + m + q / (2.0 * sqrt_2m));
+        push_quadratic_roots(sqrt_2m, p / 2.0 + m - q / (2.0 * sqrt_2m));
+    }
+
+    // Polish the roots on the original polynomial
+    for root in roots.iter_mut() {
+        for _ in 0..2 {
+            let x = *root;
+            let value = (((x + a) * x + b) * x + c) * x + d;
+            let derivative = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+            if derivative != 0.0 {
+                *root = x - value / derivative;
+            }
+        }
+    }
+
+    roots.sort_by(|x, y| x.total_cmp(y));
+    roots
+}
+
+
+/// Component of `vec` along the axis of index `axis` (0 for `x`, 1 for `y`, 2 for `z`)
+pub fn axis_component(vec: Vec3, axis: usize) -> f64 {
+    match axis {
+        0 => vec.x,
+        1 => vec.y,
+        _ => vec.z
+    }
+}
+
+
+/// Unit vector along the axis of index `axis`
+pub fn axis_vec(axis: usize) -> Vec3 {
+    match axis {
+        0 => Vec3::UNIT_X,
+        1 => Vec3::UNIT_Y,
+        _ => Vec3::UNIT_Z
+    }
+}
+
+
+/// Axis aligned bounding box
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+
+impl Aabb {
+
+    pub fn new(a: Vec3, b: Vec3) -> Self {
+        Self {
+            min: Vec3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+            max: Vec3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z))
+        }
+    }
+
+
+    /// Smallest box containing all of `points`
+    pub fn from_points(points: &[Vec3]) -> Self {
+        let mut bounds = Self::new(points[0], points[0]);
+        for point in &points[1..] {
+            bounds = bounds.union(&Self::new(*point, *point));
+        }
+        bounds
+    }
+
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: Vec3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vec3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z))
+        }
+    }
+
+
+    /// Grows the box by `delta` in every direction, giving flat shapes some thickness
+    pub fn padded(&self, delta: f64) -> Self {
+        let delta = Vec3::new(delta, delta, delta);
+        Self {
+            min: self.min - delta,
+            max: self.max + delta
+        }
+    }
+
+
+    /// Corners of the box
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vec3::new(a.x, a.y, a.z), Vec3::new(b.x, a.y, a.z), Vec3::new(a.x, b.y, a.z), Vec3::new(b.x, b.y, a.z),
+            Vec3::new(a.x, a.y, b.z), Vec3::new(b.x, a.y, b.z), Vec3::new(a.x, b.y, b.z), Vec3::new(b.x, b.y, b.z)
+        ]
+    }
+
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+
+    pub fn center(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+
+    /// Distances at which `ray` enters and leaves the box (slab test), if it crosses it
+    pub fn hit_range(&self, origin: Vec3, direction: Vec3) -> Option<(f64, f64)> {
+        let mut t_min = f64::NEG_INFINITY;
+        let mut t_max = f64::INFINITY;
+
+        for axis in 0..3 {
+            let inv_direction = 1.0 / axis_component(direction, axis);
+            let origin = axis_component(origin, axis);
+            let mut t0 = (axis_component(self.min, axis) - origin) * inv_direction;
+            let mut t1 = (axis_component(self.max, axis) - origin) * inv_direction;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            // NaN (ray in the plane of a slab) are ignored by min / max
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+}
+
+
+/// Orthonormal basis used to express directions in a local shading space where `z` is the normal.
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    pub x: Vec3,
+    pub y: Vec3,
+    pub z: Vec3
+}
+
+
+impl Frame {
+
+    /// Builds a frame around `normal` (Duff et al., "Building an Orthonormal Basis, Revisited").
+    pub fn from_normal(normal: Vec3) -> Self {
+        let sign = 1.0f64.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+
+        Self {
+            x: Vec3::new(1.0 + sign * normal.x * normal.x * a, sign * b, -sign * normal.x),
+            y: Vec3::new(b, sign + normal.y * normal.y * a, -normal.y),
+            z: normal
+        }
+    }
+
+
+    pub fn to_local(&self, vec: Vec3) -> Vec3 {
+        Vec3::new(vec.dot(self.x), vec.dot(self.y), vec.dot(self.z))
+    }
+
+
+    pub fn from_local(&self, vec: Vec3) -> Vec3 {
+        vec.x * self.x + vec.y * self.y + vec.z * self.z
+    }
+}
+
+
 // /// Returns a normalized vector orthogonal to `vec`
 // pub fn get_orthogonal(vec: Vec3) -> Vec3 {
 //     if vec.y == 0.0 && vec.z == 0.0 {
@@ -130,4 +475,63 @@ pub fn is_approx_zero(vec: Vec3) -> bool {
 //             self.transformation * vec
 //         )
 //     }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// Coefficients of the monic quartic whose roots are `roots`
+    fn quartic_from_roots(roots: [f64; 4]) -> (f64, f64, f64, f64) {
+        let [r0, r1, r2, r3] = roots;
+        (
+            -(r0 + r1 + r2 + r3),
+            r0 * r1 + r0 * r2 + r0 * r3 + r1 * r2 + r1 * r3 + r2 * r3,
+            -(r0 * r1 * r2 + r0 * r1 * r3 + r0 * r2 * r3 + r1 * r2 * r3),
+            r0 * r1 * r2 * r3
+        )
+    }
+
+
+    fn assert_roots(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "roots {:?}, expected {:?}", actual, expected);
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-9, "root {}, expected {}", actual, expected);
+        }
+    }
+
+
+    #[test]
+    fn quartic_with_four_real_roots() {
+        let (a, b, c, d) = quartic_from_roots([1.0, 2.0, 3.0, 4.0]);
+        assert_roots(&solve_quartic(a, b, c, d), &[1.0, 2.0, 3.0, 4.0]);
+
+        let (a, b, c, d) = quartic_from_roots([4.1, -0.5, 0.7, -2.5]);
+        assert_roots(&solve_quartic(a, b, c, d), &[-2.5, -0.5, 0.7, 4.1]);
+    }
+
+
+    #[test]
+    fn biquadratic_quartic() {
+        // (x² - 1)(x² - 4)
+        assert_roots(&solve_quartic(0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0]);
+        // (x² - 0.25)(x² + 1)
+        assert_roots(&solve_quartic(0.0, 0.75, 0.0, -0.25), &[-0.5, 0.5]);
+    }
+
+
+    #[test]
+    fn quartic_with_two_real_roots() {
+        // (x - 1)(x - 3)(x² + x + 1)
+        assert_roots(&solve_quartic(-3.0, 0.0, -1.0, 3.0), &[1.0, 3.0]);
+    }
+
+
+    #[test]
+    fn quartic_without_real_roots() {
+        assert!(solve_quartic(0.0, 0.0, 0.0, 1.0).is_empty());
+        // (x² + 1)(x² + 2x + 5)
+        assert!(solve_quartic(2.0, 6.0, 2.0, 5.0).is_empty());
+    }
+}
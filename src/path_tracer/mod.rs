@@ -18,22 +18,79 @@ OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR
 
 pub mod rid;
 pub mod cpu;
+pub mod transform;
 
 
 mod math;
+mod sampler;
 
 use simple_term_renderer::img::Image;
 use simple_term_renderer::math::*;
 
+use math::lerp_vec;
+use transform::Quat;
+
+
+/// Algorithm estimating the light reaching the camera
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    /// Paths traced from the camera, with next event estimation of the lights
+    PathTracing,
+    /// Camera and light subpaths connected together, weighted with multiple importance sampling
+    Bidirectional,
+    /// Paths traced from the camera until a non specular surface, where indirect light is estimated from photons
+    /// shot from the lights beforehand
+    PhotonMapping,
+    /// Paths traced from the lights, splatted onto the image by connecting them to the camera
+    LightTracing,
+    /// Paths traced from the camera, explored with Metropolis mutations of the random numbers they consume
+    Metropolis,
+    /// Cheap view of the first surface seen through each pixel, without global illumination
+    Debug(DebugView)
+}
+
+
+/// Quantity shown by the debug integrator
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DebugView {
+    /// Fraction of the light of a uniform sky reaching the surface, only counting occluders within the given radius.
+    /// Directions of the hemisphere above the surface are weighted by their cosine.
+    AmbientOcclusion(f64),
+    ShadingNormal,
+    GeometricNormal,
+    /// Distance to the camera, closer surfaces being brighter
+    Depth,
+    Uv,
+    /// Colour unique to the hit object
+    ObjectId,
+    /// Colour unique to the material of the hit object
+    MaterialId,
+    /// Barycentric coordinates of triangle hits, black on other objects
+    Barycentrics,
+    /// Surfaces reached by paths before they end, from blue to red
+    BounceCount
+}
+
 
 pub trait PTRenderer {
-    fn render(&self, camera: &Camera, target: &mut Image);
+    fn render(&mut self, camera: &Camera, target: &mut Image);
+
+    fn set_integrator(&mut self, integrator: Integrator);
 }
 
 
 pub struct Camera {
     pub position: Vec3,
-    pub focal_length: f64
+    /// Position at the end of the frame, the camera moving linearly from `position`
+    pub end_position: Vec3,
+    /// Orientation of the camera, which looks towards `-z` when not rotated
+    pub rotation: Quat,
+    /// Orientation at the end of the frame, the camera turning from `rotation`. `None` if it does not turn.
+    pub end_rotation: Option<Quat>,
+    pub focal_length: f64,
+    /// Part of the frame during which the shutter is open, rays being spread over it for motion blur
+    pub shutter_open: f64,
+    pub shutter_close: f64
 }
 
 
@@ -43,38 +100,156 @@ impl Camera {
     pub fn new(position: Vec3, focal_length: f64) -> Self {
         Self {
             position: position,
-            focal_length: focal_length
+            end_position: position,
+            rotation: Quat::IDENTITY,
+            end_rotation: None,
+            focal_length: focal_length,
+            shutter_open: 0.0,
+            shutter_close: 1.0
+        }
+    }
+
+
+    pub fn position_at(&self, time: f64) -> Vec3 {
+        lerp_vec(time, self.position, self.end_position)
+    }
+
+
+    pub fn rotation_at(&self, time: f64) -> Quat {
+        match &self.end_rotation {
+            Some(end) => Quat::slerp(time, &self.rotation, end),
+            None => self.rotation
+        }
+    }
+
+
+    /// World direction of the point `(u, v)` of the image at `time`, both in [0; 1] from the top left corner.
+    /// The viewport is two units high at the focal length.
+    pub fn direction(&self, u: f64, v: f64, aspect_ratio: f64, time: f64) -> Vec3 {
+        let local = Vec3::new(2.0 * aspect_ratio * (u - 0.5), 2.0 * (0.5 - v), -self.focal_length);
+        self.rotation_at(time).rotate(local)
+    }
+
+
+    /// Point `(u, v)` of the image where `point` is seen at `time`, the inverse of `direction`.
+    /// Returns `None` for points behind the camera or outside of the image.
+    pub fn project(&self, point: Vec3, aspect_ratio: f64, time: f64) -> Option<(f64, f64)> {
+        let local = self.rotation_at(time).conjugate().rotate(point - self.position_at(time));
+        if local.z >= 0.0 {
+            return None;
+        }
+
+        let scale = self.focal_length / -local.z;
+        let u = 0.5 + scale * local.x / (2.0 * aspect_ratio);
+        let v = 0.5 - scale * local.y / 2.0;
+        if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) {
+            Some((u, v))
+        } else {
+            None
+        }
+    }
+
+
+    /// Direction the camera looks towards at `time`
+    pub fn forward(&self, time: f64) -> Vec3 {
+        self.rotation_at(time).rotate(-Vec3::UNIT_Z)
+    }
+
+
+    /// Importance emitted by the camera along `direction`, normalized to integrate to one over the image so that it
+    /// measures the average radiance over the image. The image covers `4 * aspect_ratio / focal_length²` units of
+    /// area at unit distance.
+    pub fn importance(&self, direction: Vec3, aspect_ratio: f64, time: f64) -> f64 {
+        let cos_theta = direction.normalized().dot(self.forward(time));
+        if cos_theta <= 0.0 {
+            return 0.0;
         }
+
+        let area = 4.0 * aspect_ratio / (self.focal_length * self.focal_length);
+        1.0 / (area * cos_theta.powi(4))
     }
 }
 
 
+#[derive(Clone)]
 pub struct HitInfo {
     pub distance: f64,
     pub position: Vec3,
+    /// Geometric normal, facing the incoming ray
     pub normal: Vec3,
-    pub front_face: bool
+    /// Normal used for shading, on the same side as `normal`. It may be perturbed by normal or bump maps.
+    pub shading_normal: Vec3,
+    pub front_face: bool,
+    pub uv: Vec2,
+    /// Partial derivatives of the position with respect to the UV coordinates
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    /// Object whose material shades the hit when it is not the hit object itself, e.g. the operand of a CSG object
+    pub material_object: Option<rid::Rid>,
+    /// Hero wavelength of the path in nanometers, when rendering spectrally
+    pub wavelength: Option<f64>,
+    /// Weights of the vertices of the hit triangle
+    pub barycentrics: Option<Vec3>
 }
 
 
 impl HitInfo {
-    pub fn new(distance: f64, position: Vec3, normal: Vec3, front_face: bool) -> HitInfo {
+
+    /// Offset applied along the geometric normal when spawning rays from a surface
+    const RAY_OFFSET: f64 = 1e-4;
+
+
+    pub fn new(
+        distance: f64, position: Vec3, normal: Vec3, front_face: bool, uv: Vec2, dpdu: Vec3, dpdv: Vec3
+    ) -> HitInfo {
         Self {
             distance: distance,
             position: position,
             normal: normal,
-            front_face: front_face
+            shading_normal: normal,
+            front_face: front_face,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            material_object: None,
+            wavelength: None,
+            barycentrics: None
         }
     }
 
 
-    pub fn front_face(distance: f64, position: Vec3, out_normal: Vec3) -> HitInfo {
-        Self::new(distance, position, out_normal, true)
+    pub fn front_face(distance: f64, position: Vec3, out_normal: Vec3, uv: Vec2, dpdu: Vec3, dpdv: Vec3) -> HitInfo {
+        Self::new(distance, position, out_normal, true, uv, dpdu, dpdv)
+    }
+
+
+    pub fn back_face(distance: f64, position: Vec3, out_normal: Vec3, uv: Vec2, dpdu: Vec3, dpdv: Vec3) -> HitInfo {
+        Self::new(distance, position, -out_normal, false, uv, dpdu, dpdv)
     }
 
 
-    pub fn back_face(distance: f64, position: Vec3, out_normal: Vec3) -> HitInfo {
-        Self::new(distance, position, -out_normal, false)
+    /// Normal pointing out of the surface, regardless of the side that was hit
+    pub fn outward_normal(&self) -> Vec3 {
+        if self.front_face { self.normal } else { -self.normal }
+    }
+
+
+    /// Shading normal pointing out of the surface, regardless of the side that was hit
+    pub fn outward_shading_normal(&self) -> Vec3 {
+        if self.front_face { self.shading_normal } else { -self.shading_normal }
+    }
+
+
+    /// Sets the shading normal from a normal pointing out of the surface
+    pub fn set_outward_shading_normal(&mut self, normal: Vec3) {
+        self.shading_normal = if self.front_face { normal } else { -normal };
+    }
+
+
+    /// Creates a ray leaving the surface, offset along the geometric normal to avoid self intersections
+    pub fn spawn_ray(&self, direction: Vec3) -> Ray {
+        let offset = if direction.dot(self.normal) > 0.0 { Self::RAY_OFFSET } else { -Self::RAY_OFFSET };
+        Ray::new(self.position + offset * self.normal, direction)
     }
 }
 
@@ -83,7 +258,9 @@ impl HitInfo {
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
-    pub direction: Vec3
+    pub direction: Vec3,
+    /// Instant of the ray within the frame, `0` and `1` being the times of the start and end keyframes
+    pub time: f64
 }
 
 
@@ -92,10 +269,17 @@ impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Self {
         Self {
             origin: origin,
-            direction: direction
+            direction: direction,
+            time: 0.0
         }
     }
 
+
+    pub fn with_time(mut self, time: f64) -> Self {
+        self.time = time;
+        self
+    }
+
     
     pub fn at(&self, t: f64) -> Vec3 {
         self.origin + t * self.direction
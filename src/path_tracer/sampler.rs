@@ -0,0 +1,167 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::cell::RefCell;
+use std::f64::consts::TAU;
+use std::rc::Rc;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+
+thread_local! {
+    /// Primary sampler the random numbers of the thread are drawn from, if any
+    static PRIMARY_SAMPLER: RefCell<Option<Rc<RefCell<PrimarySampler>>>> = RefCell::new(None);
+}
+
+
+/// Uniform random number in [0; 1) used to sample paths. It comes from the primary sampler installed on the thread,
+/// or from the thread generator otherwise.
+pub fn random() -> f64 {
+    PRIMARY_SAMPLER.with(|sampler| match sampler.borrow().as_ref() {
+        Some(sampler) => sampler.borrow_mut().next(),
+        None => rand::random()
+    })
+}
+
+
+#[derive(Debug, Copy, Clone)]
+struct PrimarySample {
+    value: f64,
+    /// Iteration during which the value was last changed
+    last_modification: u64,
+    /// State before the current iteration, restored if its mutation is rejected
+    value_backup: f64,
+    modification_backup: u64
+}
+
+
+/// Sampler of the primary sample space (Kelemen et al., "A Simple and Robust Mutation Strategy for the Metropolis
+/// Light Transport Algorithm"). It replays the random numbers consumed by a path, which are mutated at every
+/// iteration: either slightly, or all replaced by a large step.
+///
+/// Numbers are drawn from a generator seeded by `seed`, so that the first path sampled is known from the seed alone.
+pub struct PrimarySampler {
+    rng: StdRng,
+    samples: Vec<PrimarySample>,
+    /// Standard deviation of small steps
+    sigma: f64,
+    large_step_probability: f64,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    /// Next sample consumed by the path
+    index: usize
+}
+
+
+impl PrimarySampler {
+
+    pub fn new(seed: u64, sigma: f64, large_step_probability: f64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            samples: Vec::new(),
+            sigma: sigma,
+            large_step_probability: large_step_probability,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0
+        }
+    }
+
+
+    /// Runs `f` with the numbers returned by `random` coming from `sampler`, starting over from its first sample
+    pub fn run<R>(sampler: &Rc<RefCell<PrimarySampler>>, f: impl FnOnce() -> R) -> R {
+        sampler.borrow_mut().index = 0;
+        PRIMARY_SAMPLER.with(|installed| *installed.borrow_mut() = Some(sampler.clone()));
+        let result = f();
+        PRIMARY_SAMPLER.with(|installed| *installed.borrow_mut() = None);
+        result
+    }
+
+
+    /// Starts a new mutation of the samples, applied as they are consumed
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+    }
+
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+
+    /// Restores the samples to their state before the current iteration
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modification == self.iteration {
+                sample.value = sample.value_backup;
+                sample.last_modification = sample.modification_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+
+    /// Uniform random number in [0; 1) that is not part of the primary samples, e.g. to accept mutations
+    pub fn uniform(&mut self) -> f64 {
+        self.rng.gen()
+    }
+
+
+    fn next(&mut self) -> f64 {
+        if self.index >= self.samples.len() {
+            self.samples.resize(self.index + 1, PrimarySample {
+                value: 0.0,
+                last_modification: 0,
+                value_backup: 0.0,
+                modification_backup: 0
+            });
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // The sample was not used since the last large step, which would have replaced it
+        if sample.last_modification < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modification = self.last_large_step;
+        }
+
+        sample.value_backup = sample.value;
+        sample.modification_backup = sample.last_modification;
+
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Small steps missed since the last modification are applied at once, their sum being normal too
+            let small_steps = (self.iteration - sample.last_modification) as f64;
+            let (u1, u2): (f64, f64) = (self.rng.gen(), self.rng.gen());
+            let normal = (-2.0 * (1.0 - u1).ln()).sqrt() * (TAU * u2).cos();
+
+            sample.value += normal * self.sigma * small_steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+
+        sample.last_modification = self.iteration;
+        sample.value
+    }
+}
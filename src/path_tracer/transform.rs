@@ -0,0 +1,366 @@
+/*
+Copyright 2024 Souchet Ferdinand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated
+documentation files (the “Software”), to deal in the Software without restriction, including without limitation the
+rights to use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of the Software, and to permit
+persons to whom the Software is furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all copies or substantial portions of the
+Software.
+
+THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE
+WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR
+OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+
+use std::ops::Mul;
+
+use simple_term_renderer::math::Vec3;
+
+use super::{HitInfo, Ray};
+use super::math::{lerp_vec, mul_elem, Aabb};
+
+
+/// Rotation quaternion
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Quat {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64
+}
+
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat::new(1.0, 0.0, 0.0, 0.0);
+
+
+    pub const fn new(w: f64, x: f64, y: f64, z: f64) -> Self {
+        Self {
+            w: w,
+            x: x,
+            y: y,
+            z: z
+        }
+    }
+
+
+    /// Rotation of `angle` radians around `axis`
+    pub fn from_axis_angle(axis: Vec3, angle: f64) -> Self {
+        let axis = axis.normalized();
+        let (sin, cos) = (0.5 * angle).sin_cos();
+        Self::new(cos, sin * axis.x, sin * axis.y, sin * axis.z)
+    }
+
+
+    pub fn length_sq(&self) -> f64 {
+        self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z
+    }
+
+
+    pub fn normalized(&self) -> Self {
+        let length = self.length_sq().sqrt();
+        Self::new(self.w / length, self.x / length, self.y / length, self.z / length)
+    }
+
+
+    pub fn dot(&self, other: &Quat) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+
+    /// Spherical interpolation, following the shortest arc
+    pub fn slerp(t: f64, a: &Quat, b: &Quat) -> Self {
+        let mut cos = a.dot(b);
+        let b = if cos < 0.0 {
+            cos = -cos;
+            Quat::new(-b.w, -b.x, -b.y, -b.z)
+        } else {
+            *b
+        };
+
+        // Close rotations are linearly interpolated, the sine of the angle being too small
+        let (ka, kb) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+
+        Quat::new(
+            ka * a.w + kb * b.w,
+            ka * a.x + kb * b.x,
+            ka * a.y + kb * b.y,
+            ka * a.z + kb * b.z
+        ).normalized()
+    }
+
+
+    pub fn conjugate(&self) -> Self {
+        Self::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+
+    pub fn rotate(&self, vec: Vec3) -> Vec3 {
+        let axis = Vec3::new(self.x, self.y, self.z);
+        let t = 2.0 * axis.cross(vec);
+        vec + self.w * t + axis.cross(t)
+    }
+
+
+    /// Rotation matrix, given as rows
+    pub fn to_matrix(&self) -> [[f64; 3]; 3] {
+        let q = self.normalized();
+        let (w, x, y, z) = (q.w, q.x, q.y, q.z);
+        [
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y)],
+            [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x)],
+            [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y)]
+        ]
+    }
+}
+
+
+impl Mul for Quat {
+    type Output = Quat;
+
+    fn mul(self, rhs: Quat) -> Quat {
+        Quat::new(
+            self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w
+        )
+    }
+}
+
+
+/// Affine transformation made of a scale, followed by a rotation and a translation
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3
+}
+
+
+impl Transform {
+
+    pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        Self {
+            translation: translation,
+            rotation: rotation,
+            scale: scale
+        }
+    }
+
+
+    pub fn identity() -> Self {
+        Self::new(Vec3::ZERO, Quat::IDENTITY, Vec3::new(1.0, 1.0, 1.0))
+    }
+
+
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self::new(translation, Quat::IDENTITY, Vec3::new(1.0, 1.0, 1.0))
+    }
+
+
+    /// Interpolates translation and scale linearly, and rotation spherically
+    pub fn lerp(t: f64, a: &Transform, b: &Transform) -> Self {
+        Self::new(
+            lerp_vec(t, a.translation, b.translation),
+            Quat::slerp(t, &a.rotation, &b.rotation),
+            lerp_vec(t, a.scale, b.scale)
+        )
+    }
+
+
+    /// Transform applying `child` then `parent`. It is exact unless `parent` has a non uniform scale and `child` is
+    /// rotated, the resulting shear not being representable: the scales are then multiplied axis by axis.
+    pub fn compose(parent: &Transform, child: &Transform) -> Self {
+        Self::new(
+            parent.translation + parent.rotation.rotate(mul_elem(parent.scale, child.translation)),
+            parent.rotation * child.rotation,
+            mul_elem(parent.scale, child.scale)
+        )
+    }
+
+
+    /// Whether `compose` is exact for `parent` and `child`, that is if the scale of `parent` is uniform or `child` is
+    /// not rotated
+    pub fn composes_exactly(parent: &Transform, child: &Transform) -> bool {
+        const EPSILON: f64 = 1e-9;
+        let close = |a: f64, b: f64| (a - b).abs() <= EPSILON * a.abs().max(b.abs());
+
+        let scale = parent.scale;
+        (close(scale.x, scale.y) && close(scale.x, scale.z)) || child.rotation.w.abs() >= 1.0 - EPSILON
+    }
+
+
+    pub fn to_affine(&self) -> Affine {
+        let rotation = self.rotation.to_matrix();
+        let scale = [self.scale.x, self.scale.y, self.scale.z];
+
+        let linear: [[f64; 3]; 3] = std::array::from_fn(|i| std::array::from_fn(|j| rotation[i][j] * scale[j]));
+        Affine::new(linear, self.translation)
+    }
+}
+
+
+/// Affine matrix, stored as a linear part (rows) and a translation
+#[derive(Debug, Copy, Clone)]
+pub struct Affine {
+    linear: [[f64; 3]; 3],
+    translation: Vec3
+}
+
+
+impl Affine {
+
+    pub fn new(linear: [[f64; 3]; 3], translation: Vec3) -> Self {
+        Self {
+            linear: linear,
+            translation: translation
+        }
+    }
+
+
+    pub fn identity() -> Self {
+        Self::new([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]], Vec3::ZERO)
+    }
+
+
+    pub fn determinant(&self) -> f64 {
+        let m = &self.linear;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+
+    /// Inverse transformation, the matrix needs to be invertible (no null scale)
+    pub fn inverse(&self) -> Self {
+        let m = &self.linear;
+        let inv_det = 1.0 / self.determinant();
+
+        let linear = [
+            [
+                (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+                (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+                (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det
+            ],
+            [
+                (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+                (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+                (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det
+            ],
+            [
+                (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+                (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+                (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det
+            ]
+        ];
+
+        let inverse = Self::new(linear, Vec3::ZERO);
+        Self::new(linear, -inverse.vector(self.translation))
+    }
+
+
+    pub fn point(&self, point: Vec3) -> Vec3 {
+        self.vector(point) + self.translation
+    }
+
+
+    pub fn vector(&self, vec: Vec3) -> Vec3 {
+        let m = &self.linear;
+        Vec3::new(
+            m[0][0] * vec.x + m[0][1] * vec.y + m[0][2] * vec.z,
+            m[1][0] * vec.x + m[1][1] * vec.y + m[1][2] * vec.z,
+            m[2][0] * vec.x + m[2][1] * vec.y + m[2][2] * vec.z
+        )
+    }
+
+
+    /// Applies the transpose of the linear part, used to transform normals with the inverse matrix
+    pub fn transpose_vector(&self, vec: Vec3) -> Vec3 {
+        let m = &self.linear;
+        Vec3::new(
+            m[0][0] * vec.x + m[1][0] * vec.y + m[2][0] * vec.z,
+            m[0][1] * vec.x + m[1][1] * vec.y + m[2][1] * vec.z,
+            m[0][2] * vec.x + m[1][2] * vec.y + m[2][2] * vec.z
+        )
+    }
+}
+
+
+impl Mul for Affine {
+    type Output = Affine;
+
+    /// Composition, `rhs` being applied first
+    fn mul(self, rhs: Affine) -> Affine {
+        let linear: [[f64; 3]; 3] = std::array::from_fn(|i| {
+            std::array::from_fn(|j| (0..3).map(|k| self.linear[i][k] * rhs.linear[k][j]).sum())
+        });
+        Affine::new(linear, self.point(rhs.translation))
+    }
+}
+
+
+/// Object to world transformation of an object, along with its inverse
+#[derive(Debug, Copy, Clone)]
+pub struct ObjectToWorld {
+    to_world: Affine,
+    to_object: Affine
+}
+
+
+impl ObjectToWorld {
+
+    pub fn new(to_world: Affine) -> Self {
+        Self {
+            to_world: to_world,
+            to_object: to_world.inverse()
+        }
+    }
+
+
+    /// Brings a world space ray to object space. The direction is not normalized so that distances are preserved.
+    pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(self.to_object.point(ray.origin), self.to_object.vector(ray.direction)).with_time(ray.time)
+    }
+
+
+    /// World space box containing an object space box
+    pub fn box_to_world(&self, bounds: &Aabb) -> Aabb {
+        Aabb::from_points(&bounds.corners().map(|corner| self.to_world.point(corner)))
+    }
+
+
+    /// Factor by which the transform scales the areas of a surface whose world space normal is `normal`
+    pub fn area_scale(&self, normal: Vec3) -> f64 {
+        self.to_world.determinant().abs() / self.to_world.transpose_vector(normal).length()
+    }
+
+
+    /// Factor by which the transform scales areas on average, exact for uniform scales
+    pub fn mean_area_scale(&self) -> f64 {
+        self.to_world.determinant().abs().powf(2.0 / 3.0)
+    }
+
+
+    /// Brings an object space hit back to world space
+    pub fn hit_to_world(&self, hit_info: HitInfo) -> HitInfo {
+        HitInfo {
+            position: self.to_world.point(hit_info.position),
+            normal: self.to_object.transpose_vector(hit_info.normal).normalized(),
+            shading_normal: self.to_object.transpose_vector(hit_info.shading_normal).normalized(),
+            dpdu: self.to_world.vector(hit_info.dpdu),
+            dpdv: self.to_world.vector(hit_info.dpdv),
+            ..hit_info
+        }
+    }
+}